@@ -0,0 +1,77 @@
+//! Cost per frame of `trace_ips` against the full `StackFrames` iterator,
+//! with and without the stack cache or compact unwind tables.
+//!
+//! Run with `cargo bench --bench trace`.
+
+extern crate unwind;
+extern crate fallible_iterator;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use fallible_iterator::FallibleIterator;
+use unwind::{Unwinder, DwarfUnwinder};
+
+const DEPTH: usize = 64;
+const DEEP: usize = 512;
+const ITERATIONS: u32 = 2000;
+
+#[inline(never)]
+fn recurse(depth: usize, f: &mut dyn FnMut() -> usize) -> usize {
+    if depth == 0 {
+        f()
+    } else {
+        black_box(recurse(black_box(depth - 1), f))
+    }
+}
+
+fn measure(name: &str, depth: usize, mut f: impl FnMut() -> usize) {
+    let mut frames = 0;
+    let mut elapsed = Duration::new(0, 0);
+    recurse(depth, &mut || {
+        f(); // warm up
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            frames += f();
+        }
+        elapsed = start.elapsed();
+        0
+    });
+    let per_frame = elapsed.as_secs_f64() * 1e9 / frames as f64;
+    println!("{:<20} {:>4} frames/trace {:>8.1} ns/frame", name, frames / ITERATIONS as usize, per_frame);
+}
+
+fn main() {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut ips = [0u64; 256];
+    measure("trace_ips", DEPTH, || unwinder.trace_ips(&mut ips));
+
+    let mut unwinder = DwarfUnwinder::default();
+    let mut count = move || {
+        let mut n = 0;
+        unwinder.trace(|frames| n = frames.count().unwrap());
+        n
+    };
+    measure("StackFrames", DEPTH, &mut count);
+    measure("StackFrames", DEEP, &mut count);
+
+    // Every trace after the first joins the last one in the innermost
+    // `recurse` frame.
+    let mut unwinder = DwarfUnwinder::default();
+    unwinder.set_stack_cache(true);
+    let mut count = move || {
+        let mut n = 0;
+        unwinder.trace(|frames| n = frames.count().unwrap());
+        n
+    };
+    measure("StackFrames cached", DEPTH, &mut count);
+    measure("StackFrames cached", DEEP, &mut count);
+
+    let mut unwinder = DwarfUnwinder::default();
+    unwinder.set_compact_tables(true);
+    let mut count = move || {
+        let mut n = 0;
+        unwinder.trace(|frames| n = frames.count().unwrap());
+        n
+    };
+    measure("StackFrames compact", DEPTH, &mut count);
+}
@@ -9,7 +9,7 @@ use unwind::{Unwinder, DwarfUnwinder};
 fn bar() {
     DwarfUnwinder::default().trace(|x| {
         while let Some(frame) = x.next().unwrap() {
-            backtrace::resolve(x.registers()[16].unwrap() as *mut std::os::raw::c_void, |sym| println!("{:?} ({:?}:{:?})", sym.name(), sym.filename(), sym.lineno()));
+            backtrace::resolve(frame.return_address() as *mut std::os::raw::c_void, |sym| println!("{:?} ({:?}:{:?})", sym.name(), sym.filename(), sym.lineno()));
             println!("{:?}", frame);
         }
     });
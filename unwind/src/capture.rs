@@ -0,0 +1,267 @@
+//! Stacks of all threads of the process, e.g. to diagnose hangs.
+//!
+//! Every thread listed in `/proc/self/task` is sent a real-time signal. Its
+//! handler unwinds the interrupted code with a `SignalSafeUnwinder` into a
+//! buffer prepared for that thread, and the capturing thread waits for all
+//! of them or until the timeout.
+//!
+//! Threads that block the signal, or are stopped, do not answer before the
+//! timeout. The signal stays pending for them, so in that case the handler
+//! stays installed until a later capture with the same signal gets every
+//! answer. The buffers are never freed, but reused by later captures.
+
+use std::cell::UnsafeCell;
+use std::fs;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::string::String;
+use std::vec::Vec;
+use std::{format, vec};
+use std::boxed::Box;
+
+use ModuleIndex;
+use signal_safe::SignalSafeUnwinder;
+
+/// The stack of one thread.
+#[derive(Debug, Clone)]
+pub struct ThreadStack {
+    tid: i32,
+    name: String,
+    ips: Vec<u64>,
+    timed_out: bool,
+}
+
+impl ThreadStack {
+    /// The kernel's thread id.
+    pub fn tid(&self) -> i32 {
+        self.tid
+    }
+
+    /// The thread's name from `/proc/self/task/<tid>/comm`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The interrupted instruction, followed by return addresses.
+    pub fn ips(&self) -> &[u64] {
+        &self.ips
+    }
+
+    /// The thread did not handle the signal in time, so `ips` is empty.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+}
+
+/// How to capture the stacks of all threads.
+#[derive(Debug, Clone)]
+pub struct ThreadCapture {
+    /// The signal used to interrupt the threads. Nothing else in the
+    /// process may use it.
+    pub signal: i32,
+    /// How long to wait for the threads to answer.
+    pub timeout: Duration,
+    /// The most addresses recorded per thread.
+    pub max_frames: usize,
+}
+
+impl Default for ThreadCapture {
+    fn default() -> ThreadCapture {
+        ThreadCapture {
+            signal: ::libc::SIGRTMAX() - 2,
+            timeout: Duration::from_secs(1),
+            max_frames: 256,
+        }
+    }
+}
+
+/// Captures the stacks of all threads with the default settings.
+pub fn capture_all_threads() -> io::Result<Vec<ThreadStack>> {
+    ThreadCapture::default().capture()
+}
+
+/// A thread's buffer. Slots are kept in a list that only grows, so a
+/// handler that runs late never sees freed memory, and are reused by later
+/// captures once disarmed.
+struct Slot {
+    next: *mut Slot,
+    tid: AtomicI32,
+    state: AtomicU8,
+    // Only used by the handler while `WRITING`, and by the capturing thread
+    // while `IDLE` or `DONE`.
+    unwinder: UnsafeCell<Option<SignalSafeUnwinder>>,
+    ips: UnsafeCell<Box<[u64]>>,
+    len: AtomicUsize,
+}
+
+const IDLE: u8 = 0;
+const ARMED: u8 = 1;
+const WRITING: u8 = 2;
+const DONE: u8 = 3;
+
+static SLOTS: AtomicPtr<Slot> = AtomicPtr::new(ptr::null_mut());
+
+/// The signals our handler is still installed for after a timeout, with
+/// the actions it replaced.
+static CAPTURE: Mutex<Vec<(::libc::c_int, ::libc::sigaction)>> = Mutex::new(Vec::new());
+
+fn slots() -> impl Iterator<Item = &'static Slot> {
+    let mut slot = SLOTS.load(Ordering::Acquire);
+    std::iter::from_fn(move || {
+        let current = unsafe { slot.as_ref()? };
+        slot = current.next;
+        Some(current)
+    })
+}
+
+extern "C" fn on_signal(_sig: ::libc::c_int, _info: *mut ::libc::siginfo_t, uc: *mut ::libc::c_void) {
+    let tid = unsafe { ::libc::syscall(::libc::SYS_gettid) } as i32;
+    for slot in slots() {
+        if slot.tid.load(Ordering::Relaxed) != tid
+            || slot.state.compare_exchange(ARMED, WRITING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            continue;
+        }
+        // The slot may have been given to another thread since we read `tid`.
+        if slot.tid.load(Ordering::Relaxed) != tid {
+            slot.state.store(ARMED, Ordering::Release);
+            continue;
+        }
+        unsafe {
+            if let Some(ref unwinder) = *slot.unwinder.get() {
+                let len = unwinder.trace_from_ucontext(uc as *const ::libc::ucontext_t, &mut *slot.ips.get());
+                slot.len.store(len, Ordering::Relaxed);
+            }
+        }
+        slot.state.store(DONE, Ordering::Release);
+        return;
+    }
+}
+
+/// Stops late handlers from writing to the slot, waiting for one that
+/// already started.
+fn disarm(slot: &Slot) {
+    while slot.state.compare_exchange(ARMED, IDLE, Ordering::Acquire, Ordering::Acquire) == Err(WRITING) {
+        thread::yield_now();
+    }
+    slot.state.store(IDLE, Ordering::Relaxed);
+}
+
+impl ThreadCapture {
+    /// Captures the stacks of all threads, including the calling one.
+    pub fn capture(&self) -> io::Result<Vec<ThreadStack>> {
+        let mut installed = CAPTURE.lock().unwrap_or_else(|e| e.into_inner());
+        for slot in slots() {
+            disarm(slot);
+        }
+
+        let mut tids = Vec::new();
+        for entry in fs::read_dir("/proc/self/task")? {
+            if let Ok(tid) = entry?.file_name().to_string_lossy().parse::<i32>() {
+                tids.push(tid);
+            }
+        }
+
+        while slots().count() < tids.len() {
+            let slot = Box::new(Slot {
+                next: SLOTS.load(Ordering::Relaxed),
+                tid: AtomicI32::new(0),
+                state: AtomicU8::new(IDLE),
+                unwinder: UnsafeCell::new(None),
+                ips: UnsafeCell::new(Box::new([])),
+                len: AtomicUsize::new(0),
+            });
+            SLOTS.store(Box::into_raw(slot), Ordering::Release);
+        }
+        // Threads started after the index was built have stacks outside its
+        // memory map, so those are read with process_vm_readv.
+        let index = ModuleIndex::global();
+        let used: Vec<&Slot> = slots().take(tids.len()).collect();
+        for (slot, &tid) in used.iter().zip(&tids) {
+            let mut unwinder = SignalSafeUnwinder::new(index.clone(), 1);
+            unwinder.set_process_vm_readv(true);
+            unsafe {
+                *slot.unwinder.get() = Some(unwinder);
+                let ips = &mut *slot.ips.get();
+                if ips.len() != self.max_frames {
+                    *ips = vec![0; self.max_frames].into_boxed_slice();
+                }
+            }
+            slot.tid.store(tid, Ordering::Relaxed);
+            slot.len.store(0, Ordering::Relaxed);
+            slot.state.store(ARMED, Ordering::Release);
+        }
+
+        if !installed.iter().any(|&(signal, _)| signal == self.signal) {
+            let mut old: ::libc::sigaction = unsafe { mem::zeroed() };
+            unsafe {
+                let mut action: ::libc::sigaction = mem::zeroed();
+                action.sa_sigaction = on_signal as *const () as usize;
+                // Not SA_ONSTACK: the alternate stacks Rust sets up for its
+                // stack overflow handler are too small to unwind on.
+                action.sa_flags = ::libc::SA_SIGINFO | ::libc::SA_RESTART;
+                ::libc::sigemptyset(&mut action.sa_mask);
+                if ::libc::sigaction(self.signal, &action, &mut old) != 0 {
+                    let error = io::Error::last_os_error();
+                    for slot in &used {
+                        disarm(slot);
+                    }
+                    return Err(error);
+                }
+            }
+            installed.push((self.signal, old));
+        }
+
+        let pid = unsafe { ::libc::getpid() };
+        let mut exited = vec![false; tids.len()];
+        for (i, &tid) in tids.iter().enumerate() {
+            // Fails if the thread exited since we listed it.
+            exited[i] = unsafe { ::libc::syscall(::libc::SYS_tgkill, pid, tid, self.signal) } != 0;
+        }
+
+        let start = Instant::now();
+        let pending = || used.iter().zip(&exited)
+            .any(|(slot, &exited)| !exited && slot.state.load(Ordering::Acquire) != DONE);
+        while pending() && start.elapsed() < self.timeout {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut stacks = Vec::with_capacity(tids.len());
+        for (slot, &exited) in used.iter().zip(&exited) {
+            if exited {
+                continue;
+            }
+            let done = slot.state.load(Ordering::Acquire) == DONE;
+            let ips = if done {
+                unsafe { (&**slot.ips.get())[..slot.len.load(Ordering::Relaxed)].to_vec() }
+            } else {
+                Vec::new()
+            };
+            stacks.push(ThreadStack {
+                tid: slot.tid.load(Ordering::Relaxed),
+                name: thread_name(slot.tid.load(Ordering::Relaxed)),
+                ips,
+                timed_out: !done,
+            });
+        }
+
+        // Otherwise the signal is still pending somewhere, and the handler
+        // has to stay until a later capture gets every answer.
+        if stacks.iter().all(|stack| !stack.timed_out) {
+            let i = installed.iter().position(|&(signal, _)| signal == self.signal).unwrap();
+            let (signal, old) = installed.remove(i);
+            unsafe { ::libc::sigaction(signal, &old, ptr::null_mut()) };
+        }
+        Ok(stacks)
+    }
+}
+
+fn thread_name(tid: i32) -> String {
+    fs::read_to_string(format!("/proc/self/task/{}/comm", tid))
+        .map(|name| name.trim_end_matches('\n').into())
+        .unwrap_or_default()
+}
@@ -0,0 +1,294 @@
+//! Unwind tables compiled ahead of time from `.eh_frame`.
+//!
+//! Finding the row for an address in `.eh_frame` means replaying the CFA
+//! program of its FDE up to that address. Like the Linux kernel's ORC
+//! tables, a `CompactTable` does that once for every row of an object and
+//! keeps only what a stack walk needs: the CFA as RSP or RBP plus an offset,
+//! and where the caller's RBP and return address were saved. A lookup is
+//! then a binary search.
+//!
+//! Rows that do not fit this form, e.g. with DWARF expressions, a
+//! `DW_CFA_GNU_args_size` or in a signal trampoline, have no rule and are
+//! left to the full CFI.
+//!
+//! `CompactTable::write` stores a table for other unwinders, e.g. eBPF-based
+//! ones. All integers are little-endian. The file starts with the magic
+//! `UNWCOMPT`, a `u32` version (1) and a `u32` row count, followed by the
+//! rows, 24 bytes each so that addresses stay aligned:
+//!
+//! - `u64` address of the row in the object, i.e. less `Module::base`; the
+//!   row covers the addresses up to the next row
+//! - `i32` CFA offset
+//! - `i16` offset of the saved RBP from the CFA
+//! - `i16` offset of the return address from the CFA
+//! - `u8` DWARF number of the CFA register, 7 (RSP) or 6 (RBP), or 255 if
+//!   the row has no rule
+//! - `u8` 1 if RBP was saved, 0 if the frame did not change it
+//! - `u8` 1 if the return address was saved, 0 in the outermost frame
+//! - 5 bytes of padding
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use gimli::{CfaRule, CieOrFde, EhFrame, RegisterRule, UninitializedUnwindContext, UnwindSection, UnwindTable, UnwindTableRow, X86_64};
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+use find_cfi::Module;
+use range::AddrRange;
+use {ObjectRecord, StaticReader};
+
+#[cfg(feature = "std")]
+const MAGIC: &[u8; 8] = b"UNWCOMPT";
+#[cfg(feature = "std")]
+const VERSION: u32 = 1;
+
+/// How to find the caller of a frame, in the form a `CompactTable` keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactRule {
+    /// The register the CFA is computed from, RSP or RBP.
+    pub cfa_register: gimli::Register,
+    pub cfa_offset: i32,
+    /// Where the caller's RBP was saved, relative to the CFA, or `None` if
+    /// the frame did not change RBP.
+    pub rbp: Option<i16>,
+    /// Where the return address was saved, relative to the CFA, or `None`
+    /// in the outermost frame.
+    pub return_address: Option<i16>,
+}
+
+/// The unwind rule from `address` up to the next row of a `CompactTable`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactRow {
+    address: u64,
+    function: Option<AddrRange>,
+    rule: Option<CompactRule>,
+    /// The general purpose registers other than RBP that the frame saved,
+    /// one bit per DWARF number.
+    clobbered: u16,
+}
+
+impl CompactRow {
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// The range of the FDE this row comes from, or `None` between FDEs.
+    pub fn function(&self) -> Option<AddrRange> {
+        self.function
+    }
+
+    /// The rule, or `None` if the CFI of these addresses does not fit the
+    /// compact form or there is none.
+    pub fn rule(&self) -> Option<CompactRule> {
+        self.rule
+    }
+
+    /// The registers that the frame saved but the rule does not restore.
+    pub(crate) fn clobbered(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..16).filter(move |reg| self.clobbered & 1 << reg != 0)
+    }
+}
+
+/// The rows of every FDE in an object, flattened and sorted by address.
+pub struct CompactTable {
+    module: Arc<Module>,
+    rows: Vec<CompactRow>,
+}
+
+impl CompactTable {
+    pub(crate) fn compile(rec: &ObjectRecord) -> CompactTable {
+        let mut fdes = Vec::new();
+        let mut entries = rec.eh_frame.entries(&rec.bases);
+        loop {
+            match entries.next() {
+                Ok(Some(CieOrFde::Fde(partial))) => match partial.parse(EhFrame::cie_from_offset) {
+                    Ok(fde) => fdes.push(fde),
+                    Err(e) => debug!("bad FDE in {}: {}", rec.module.path(), e),
+                },
+                Ok(Some(CieOrFde::Cie(_))) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("bad eh_frame entry in {}: {}", rec.module.path(), e);
+                    break;
+                }
+            }
+        }
+        fdes.sort_by_key(|fde| fde.initial_address());
+
+        let mut ctx = UninitializedUnwindContext::new();
+        let mut rows = Vec::new();
+        let mut end = None;
+        for fde in &fdes {
+            let function = AddrRange { start: fde.initial_address(), end: fde.initial_address() + fde.len() };
+            match end {
+                Some(end) if end < function.start => push(&mut rows, CompactRow { address: end, function: None, rule: None, clobbered: 0 }),
+                _ => {}
+            }
+            end = end.max(Some(function.end));
+
+            // Addresses past a row that failed to parse get no rule.
+            let mut next = function.start;
+            let mut failed = false;
+            match UnwindTable::new(&rec.eh_frame, &rec.bases, &mut ctx, fde) {
+                Ok(mut table) => loop {
+                    match table.next_row() {
+                        Ok(Some(row)) => {
+                            let (rule, clobbered) = if fde.is_signal_trampoline() { (None, 0) } else { compact_rule(row) };
+                            push(&mut rows, CompactRow { address: row.start_address(), function: Some(function), rule, clobbered });
+                            next = row.end_address();
+                        }
+                        Ok(None) => break,
+                        Err(_) => {
+                            failed = true;
+                            break;
+                        }
+                    }
+                },
+                Err(_) => failed = true,
+            }
+            if failed {
+                debug!("bad CFI for 0x{:x} - 0x{:x} in {}", function.start, function.end, rec.module.path());
+                push(&mut rows, CompactRow { address: next, function: Some(function), rule: None, clobbered: 0 });
+            }
+        }
+        if let Some(end) = end {
+            push(&mut rows, CompactRow { address: end, function: None, rule: None, clobbered: 0 });
+        }
+
+        CompactTable { module: rec.module.clone(), rows }
+    }
+
+    /// The object the table was compiled from.
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// The rows, sorted by address.
+    pub fn rows(&self) -> &[CompactRow] {
+        &self.rows
+    }
+
+    /// The row covering `address`, if it is not below the first one.
+    pub fn lookup(&self, address: u64) -> Option<&CompactRow> {
+        let after = self.rows.partition_point(|row| row.address <= address);
+        after.checked_sub(1).map(|i| &self.rows[i])
+    }
+
+    /// Writes the table in the format described in the module docs.
+    #[cfg(feature = "std")]
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let count: u32 = self.rows.len().try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many rows"))?;
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&count.to_le_bytes())?;
+        for row in &self.rows {
+            let mut entry = [0u8; 24];
+            entry[..8].copy_from_slice(&row.address.wrapping_sub(self.module.base()).to_le_bytes());
+            match row.rule {
+                Some(rule) => {
+                    entry[8..12].copy_from_slice(&rule.cfa_offset.to_le_bytes());
+                    entry[12..14].copy_from_slice(&rule.rbp.unwrap_or(0).to_le_bytes());
+                    entry[14..16].copy_from_slice(&rule.return_address.unwrap_or(0).to_le_bytes());
+                    entry[16] = rule.cfa_register.0 as u8;
+                    entry[17] = rule.rbp.is_some() as u8;
+                    entry[18] = rule.return_address.is_some() as u8;
+                }
+                None => entry[16] = 0xff,
+            }
+            out.write_all(&entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends `row` unless it just continues the last one.
+fn push(rows: &mut Vec<CompactRow>, row: CompactRow) {
+    match rows.last_mut() {
+        Some(last) if last.address == row.address => *last = row,
+        Some(last) if (last.function, last.rule, last.clobbered) == (row.function, row.rule, row.clobbered) => {}
+        _ => rows.push(row),
+    }
+}
+
+/// Converts a row of CFI, if it fits the compact form.
+fn compact_rule(row: &UnwindTableRow<StaticReader>) -> (Option<CompactRule>, u16) {
+    if row.saved_args_size() != 0 {
+        return (None, 0);
+    }
+    let (cfa_register, cfa_offset) = match *row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } if register == X86_64::RSP || register == X86_64::RBP => {
+            match offset.try_into() {
+                Ok(offset) => (register, offset),
+                Err(_) => return (None, 0),
+            }
+        }
+        _ => return (None, 0),
+    };
+    // Without a rule RBP keeps its value, and the return address is
+    // undefined.
+    let mut rule = CompactRule { cfa_register, cfa_offset, rbp: None, return_address: None };
+    let mut clobbered = 0;
+    for &(reg, ref register_rule) in row.registers() {
+        let offset = match *register_rule {
+            RegisterRule::Offset(n) => n.try_into().ok(),
+            _ => None,
+        };
+        match (reg, register_rule) {
+            (X86_64::RSP, _) => {}
+            (X86_64::RBP, &RegisterRule::SameValue) => {}
+            (X86_64::RA, &RegisterRule::Undefined) => {}
+            (X86_64::RBP, _) if offset.is_some() => rule.rbp = offset,
+            (X86_64::RA, _) if offset.is_some() => rule.return_address = offset,
+            (X86_64::RBP, _) | (X86_64::RA, _) => return (None, 0),
+            (_, &RegisterRule::Undefined) | (_, &RegisterRule::SameValue) => {}
+            _ if reg.0 < 16 => clobbered |= 1 << reg.0,
+            // Other registers are not worth a wider mask.
+            _ => return (None, 0),
+        }
+    }
+    (Some(rule), clobbered)
+}
+
+/// A `CompactTable` compiled on first use.
+pub(crate) struct LazyTable(AtomicPtr<CompactTable>);
+
+impl LazyTable {
+    pub(crate) fn new() -> LazyTable {
+        LazyTable(AtomicPtr::new(ptr::null_mut()))
+    }
+
+    pub(crate) fn get<F: FnOnce() -> CompactTable>(&self, compile: F) -> &CompactTable {
+        let mut table = self.0.load(Ordering::Acquire);
+        if table.is_null() {
+            let new = Box::into_raw(Box::new(compile()));
+            table = match self.0.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => new,
+                Err(winner) => {
+                    // Another thread was faster.
+                    drop(unsafe { Box::from_raw(new) });
+                    winner
+                }
+            };
+        }
+        unsafe { &*table }
+    }
+
+    /// The table if it was compiled already. Does not allocate.
+    pub(crate) fn peek(&self) -> Option<&CompactTable> {
+        unsafe { self.0.load(Ordering::Acquire).as_ref() }
+    }
+}
+
+impl Drop for LazyTable {
+    fn drop(&mut self) {
+        let table = *self.0.get_mut();
+        if !table.is_null() {
+            drop(unsafe { Box::from_raw(table) });
+        }
+    }
+}
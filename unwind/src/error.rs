@@ -0,0 +1,49 @@
+use core::fmt::{self, Display, Formatter};
+use gimli;
+use registers::RegisterError;
+use guard::Guard;
+
+/// Why a frame could not be unwound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnwindError {
+    /// The CFI could not be parsed, or there is none for the address.
+    Cfi(gimli::Error),
+    /// A rule names a register that does not exist, or uses it with the wrong width.
+    Register(RegisterError),
+    /// A rule depends on a register whose value is unknown in this frame.
+    UndefinedRegister(u16),
+    /// The CFI uses a DWARF expression, which we cannot evaluate.
+    UnsupportedExpression,
+    /// Unwinding would read from this address, which is not readable.
+    InvalidRead(u64),
+    /// A sanity check failed, so the CFI is probably wrong.
+    Guard(Guard),
+}
+
+impl From<gimli::Error> for UnwindError {
+    fn from(e: gimli::Error) -> UnwindError {
+        UnwindError::Cfi(e)
+    }
+}
+
+impl From<RegisterError> for UnwindError {
+    fn from(e: RegisterError) -> UnwindError {
+        UnwindError::Register(e)
+    }
+}
+
+impl Display for UnwindError {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            UnwindError::Cfi(ref e) => write!(fmt, "bad CFI: {}", e),
+            UnwindError::Register(ref e) => write!(fmt, "{}", e),
+            UnwindError::UndefinedRegister(reg) => write!(fmt, "register {} is undefined", reg),
+            UnwindError::UnsupportedExpression => write!(fmt, "DWARF expressions are not supported"),
+            UnwindError::InvalidRead(addr) => write!(fmt, "cannot read from 0x{:x}", addr),
+            UnwindError::Guard(guard) => write!(fmt, "{}", guard),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for UnwindError {}
@@ -1,5 +1,7 @@
+use alloc::vec::Vec;
 use range::AddrRange;
-use super::EhRef;
+use alloc::string::String;
+use super::{EhRef, Module};
 
 extern "C" {
     static __text_start: usize;
@@ -28,8 +30,14 @@ pub fn find_cfi_sections() -> Vec<EhRef> {
             text,
             eh_frame_hdr,
             eh_frame_end,
+            module: Some(Module { path: String::new(), base: 0, build_id: Vec::new(), text, text_offset: 0 }),
         });
     }
     trace!("CFI sections: {:?}", cfi);
     cfi
 }
+
+/// The objects never change.
+pub fn generation() -> Option<u64> {
+    None
+}
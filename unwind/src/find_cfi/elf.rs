@@ -0,0 +1,43 @@
+//! ELF structures shared by the loaded-object and file-backed lookups.
+
+use core::cmp;
+use alloc::vec::Vec;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Phdr64 {
+    pub type_: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+pub const PT_GNU_EH_FRAME: u32 = 0x6474e550;
+pub const PT_LOAD: u32 = 1;
+pub const PT_NOTE: u32 = 4;
+pub const PF_X: u32 = 1;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Finds the `NT_GNU_BUILD_ID` note among the contents of a `PT_NOTE` segment.
+pub fn build_id(notes: &[u8]) -> Option<Vec<u8>> {
+    let align = |n: usize| (n + 3) & !3;
+    let mut rest = notes;
+    while rest.len() >= 12 {
+        let word = |i: usize| u32::from_ne_bytes([rest[i], rest[i + 1], rest[i + 2], rest[i + 3]]);
+        let (namesz, descsz, type_) = (word(0) as usize, word(4) as usize, word(8));
+        let desc_start = 12 + align(namesz);
+        let desc_end = desc_start + descsz;
+        if desc_end > rest.len() {
+            break;
+        }
+        if type_ == NT_GNU_BUILD_ID && &rest[12..12 + namesz] == b"GNU\0" {
+            return Some(rest[desc_start..desc_end].to_vec());
+        }
+        rest = &rest[cmp::min(rest.len(), align(desc_end))..];
+    }
+    None
+}
@@ -0,0 +1,99 @@
+//! The CFI of object files mapped into another process, read from disk.
+
+use std::fs;
+use core::{cmp, mem, ptr};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use range::AddrRange;
+use super::{EhRef, Module};
+use super::elf::{self, Phdr64, PT_GNU_EH_FRAME, PT_LOAD, PT_NOTE, PF_X};
+
+/// An executable mapping of an object file in another process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMapping {
+    /// Path of the mapped file.
+    pub path: String,
+    /// Where the file was mapped.
+    pub range: AddrRange,
+    /// The file offset mapped at `range.start`.
+    pub offset: u64,
+}
+
+/// The contents of an object file, and where its segments were loaded.
+pub struct FileImage {
+    data: Box<[u8]>,
+    bias: u64,
+    loads: Vec<Phdr64>,
+}
+
+impl FileImage {
+    /// Up to `len` bytes of the loaded object at `addr`, as far as the
+    /// segment containing it is backed by the file.
+    pub fn bytes(&self, addr: u64, len: u64) -> Option<&[u8]> {
+        let vaddr = addr.wrapping_sub(self.bias);
+        let load = self.loads.iter().find(|x| vaddr >= x.vaddr && vaddr - x.vaddr < x.filesz)?;
+        let start = load.offset + (vaddr - load.vaddr);
+        let end = cmp::min(load.offset + load.filesz, start.saturating_add(len));
+        self.data.get(start as usize..end as usize)
+    }
+}
+
+fn program_headers(data: &[u8]) -> Option<Vec<Phdr64>> {
+    let native = if cfg!(target_endian = "little") { 1 } else { 2 };
+    if data.len() < 64 || &data[..4] != b"\x7fELF" || data[4] != 2 || data[5] != native {
+        return None;
+    }
+    let word = |i: usize| u64::from_ne_bytes([data[i], data[i + 1], data[i + 2], data[i + 3],
+                                               data[i + 4], data[i + 5], data[i + 6], data[i + 7]]);
+    let half = |i: usize| u16::from_ne_bytes([data[i], data[i + 1]]) as usize;
+    let (phoff, phentsize, phnum) = (word(32) as usize, half(54), half(56));
+    if phentsize != mem::size_of::<Phdr64>() || phoff.checked_add(phnum * phentsize)? > data.len() {
+        return None;
+    }
+    let phdr: Vec<Phdr64> = (0..phnum).map(|i| unsafe {
+        ptr::read_unaligned(data[phoff + i * phentsize..].as_ptr() as *const Phdr64)
+    }).collect();
+    // Segments that wrap around cannot be valid, and would make the sums
+    // below overflow.
+    let valid = phdr.iter().all(|x| x.offset.checked_add(x.filesz).is_some() && x.vaddr.checked_add(x.memsz).is_some());
+    if valid { Some(phdr) } else { None }
+}
+
+/// Reads the file of `mapping` and finds its CFI sections at the addresses
+/// they were loaded at.
+///
+/// Returns `None` if the file cannot be read, is not a native ELF object,
+/// or has no `PT_GNU_EH_FRAME` segment.
+pub fn load(mapping: &FileMapping) -> Option<(EhRef, FileImage)> {
+    let data = fs::read(&mapping.path).ok()?.into_boxed_slice();
+    let phdr = program_headers(&data)?;
+
+    // The executable segment the mapping was made for says how far the
+    // object was moved from its link-time addresses.
+    let map_end = mapping.range.end.checked_sub(mapping.range.start)?.checked_add(mapping.offset)?;
+    let text = phdr.iter().find(|x| {
+        x.type_ == PT_LOAD && x.flags & PF_X != 0 && x.offset < map_end && mapping.offset < x.offset + x.filesz
+    })?;
+    let bias = mapping.range.start.wrapping_sub(mapping.offset).wrapping_add(text.offset).wrapping_sub(text.vaddr);
+    let eh_frame_hdr = phdr.iter().find(|x| x.type_ == PT_GNU_EH_FRAME)?;
+
+    let start_addr = bias.wrapping_add(text.vaddr);
+    let eh_frame_hdr_start = bias.wrapping_add(eh_frame_hdr.vaddr);
+    let max_vaddr = phdr.iter().filter(|x| x.type_ == PT_LOAD)
+        .fold(0, |vaddr, x| cmp::max(vaddr, x.vaddr + x.memsz));
+    let build_id = phdr.iter().filter(|x| x.type_ == PT_NOTE)
+        .find_map(|note| data.get(note.offset as usize..(note.offset + note.filesz) as usize).and_then(elf::build_id))
+        .unwrap_or_default();
+    let image = FileImage { data, bias, loads: phdr.iter().filter(|x| x.type_ == PT_LOAD).cloned().collect() };
+
+    trace!("{} at 0x{:x} with bias 0x{:x}", mapping.path, mapping.range.start, bias);
+    let text_range = AddrRange { start: start_addr, end: start_addr.checked_add(text.memsz)? };
+    let er = EhRef {
+        text: text_range,
+        eh_frame_hdr: AddrRange { start: eh_frame_hdr_start, end: eh_frame_hdr_start.checked_add(eh_frame_hdr.memsz)? },
+        eh_frame_end: bias.wrapping_add(max_vaddr),
+        module: Some(Module { path: mapping.path.clone(), base: bias, build_id, text: text_range, text_offset: text.offset }),
+    };
+    Some((er, image))
+}
@@ -1,8 +1,11 @@
 use libc::{c_void, c_int, c_char};
 use std::ffi::CStr;
-use std::{slice, mem, cmp};
+use core::{slice, mem, cmp};
+use alloc::vec::Vec;
+use alloc::string::String;
 use range::AddrRange;
-use super::EhRef;
+use super::{EhRef, Module};
+use super::elf::{self, Phdr64, PT_GNU_EH_FRAME, PT_LOAD, PT_NOTE, PF_X};
 
 #[repr(C)]
 struct DlPhdrInfo {
@@ -10,6 +13,9 @@ struct DlPhdrInfo {
     name: *const c_char,
     phdr: *const Phdr64,
     phnum: u16,
+    // Only present if `size` says so.
+    adds: u64,
+    subs: u64,
 }
 
 /*
@@ -26,21 +32,14 @@ struct Phdr32 {
 }
 */
 
-#[repr(C)]
-struct Phdr64 {
-    type_: u32,
-    flags: u32,
-    offset: u64,
-    vaddr: u64,
-    paddr: u64,
-    filesz: u64,
-    memsz: u64,
-    align: u64,
+unsafe fn build_id(addr: u64, phdr: &[Phdr64]) -> Vec<u8> {
+    phdr.iter().filter(|x| x.type_ == PT_NOTE).find_map(|note| {
+        elf::build_id(slice::from_raw_parts((addr + note.vaddr) as *const u8, note.memsz as usize))
+    }).unwrap_or_default()
 }
 
-const PT_GNU_EH_FRAME: u32 = 0x6474e550;
-const PT_LOAD: u32 = 1;
-const PF_X: u32 = 1;
+/// The size of the fields before `adds`, which every loader provides.
+const ADDS_OFFSET: usize = mem::size_of::<u64>() * 4;
 
 type PhdrCb = extern "C" fn(info: *const DlPhdrInfo, size: usize, data: *mut c_void) -> c_int;
 extern "C" {
@@ -49,7 +48,7 @@ extern "C" {
 
 extern "C" fn callback(info: *const DlPhdrInfo, size: usize, data: *mut c_void) -> c_int {
     let data = data as *mut Vec<EhRef>;
-    assert!(size >= mem::size_of::<DlPhdrInfo>());
+    assert!(size >= ADDS_OFFSET);
 
     unsafe {
         let name = CStr::from_ptr((*info).name);
@@ -57,18 +56,33 @@ extern "C" fn callback(info: *const DlPhdrInfo, size: usize, data: *mut c_void)
 
         let phdr = slice::from_raw_parts((*info).phdr, (*info).phnum as usize);
 
-        if let Some(text) = phdr.iter().filter(|x| x.type_ == PT_LOAD && x.flags & PF_X != 0).next() {
-            if let Some(eh_frame_hdr) = phdr.iter().filter(|x| x.type_ == PT_GNU_EH_FRAME).next() {
+        if let Some(text) = phdr.iter().find(|x| x.type_ == PT_LOAD && x.flags & PF_X != 0) {
+            if let Some(eh_frame_hdr) = phdr.iter().find(|x| x.type_ == PT_GNU_EH_FRAME) {
                 let start_addr = (*info).addr + text.vaddr;
                 let eh_frame_hdr_start = (*info).addr + eh_frame_hdr.vaddr;
                 let max_vaddr = phdr.iter().filter(|x| x.type_ == PT_LOAD)
                     .fold(0, |vaddr, x| cmp::max(vaddr, x.vaddr + x.memsz));
                 // This is an upper bound, not the exact address.
                 let eh_frame_end = (*info).addr + max_vaddr;
+                // The main executable has an empty name.
+                let path = match name.to_str() {
+                    Ok("") | Err(_) => ::std::env::current_exe().ok()
+                        .and_then(|p| p.to_str().map(String::from))
+                        .unwrap_or_default(),
+                    Ok(name) => String::from(name),
+                };
+                let text_range = AddrRange { start: start_addr, end: start_addr + text.memsz };
                 (*data).push(EhRef {
-                    text: AddrRange { start: start_addr, end: start_addr + text.memsz },
+                    text: text_range,
                     eh_frame_hdr: AddrRange { start: eh_frame_hdr_start, end: eh_frame_hdr_start + eh_frame_hdr.memsz },
                     eh_frame_end,
+                    module: Some(Module {
+                        path,
+                        base: (*info).addr,
+                        build_id: build_id((*info).addr, phdr),
+                        text: text_range,
+                        text_offset: text.offset,
+                    }),
                 });
             }
         }
@@ -83,3 +97,19 @@ pub fn find_cfi_sections() -> Vec<EhRef> {
     trace!("CFI sections: {:?}", cfi);
     cfi
 }
+
+extern "C" fn generation_callback(info: *const DlPhdrInfo, size: usize, data: *mut c_void) -> c_int {
+    if size >= mem::size_of::<DlPhdrInfo>() {
+        unsafe { *(data as *mut Option<u64>) = Some((*info).adds.wrapping_add((*info).subs)) };
+    }
+    // The counters are the same for every object.
+    1
+}
+
+/// A number that changes whenever an object is loaded or unloaded, if the
+/// dynamic loader keeps count.
+pub fn generation() -> Option<u64> {
+    let mut generation = None;
+    unsafe { dl_iterate_phdr(generation_callback, &mut generation as *mut _ as *mut c_void) };
+    generation
+}
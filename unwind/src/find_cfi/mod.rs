@@ -1,19 +1,64 @@
+use alloc::string::String;
+use alloc::vec::Vec;
 use range::AddrRange;
 
+/// A loaded object (the executable or a shared library).
+#[derive(Debug, Clone)]
+pub struct Module {
+    path: String,
+    base: u64,
+    build_id: Vec<u8>,
+    text: AddrRange,
+    text_offset: u64,
+}
+
+impl Module {
+    /// Path of the object as reported by the dynamic loader. Empty if unknown.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Difference between the addresses in the object and where it was loaded.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Contents of the `NT_GNU_BUILD_ID` note. Empty if the object has none.
+    pub fn build_id(&self) -> &[u8] {
+        &self.build_id
+    }
+
+    /// Where the executable segment was loaded.
+    pub fn text(&self) -> AddrRange {
+        self.text
+    }
+
+    /// The offset of the executable segment in the file.
+    pub fn text_offset(&self) -> u64 {
+        self.text_offset
+    }
+}
+
 #[derive(Debug)]
 pub struct EhRef {
     pub text: AddrRange,
     pub eh_frame_hdr: AddrRange,
     pub eh_frame_end: u64,
+    pub module: Option<Module>,
 }
 
-#[cfg(unix)]
+#[cfg(feature = "std")]
+mod elf;
+#[cfg(feature = "std")]
+pub mod file;
+
+#[cfg(all(unix, feature = "std"))]
 #[path = "ld.rs"]
 mod imp;
 
-#[cfg(not(unix))]
+#[cfg(not(all(unix, feature = "std")))]
 #[path = "baremetal.rs"]
 mod imp;
 
 
-pub use self::imp::find_cfi_sections;
+pub use self::imp::{find_cfi_sections, generation};
@@ -0,0 +1,196 @@
+//! Output of sampled stacks for flamegraph tools.
+//!
+//! The folded format, one line per stack with the frames separated by `;`,
+//! is what `flamegraph.pl` and inferno read. speedscope reads its own JSON
+//! format, where a sampled profile refers to a shared table of frames.
+//!
+//! Frames are named by the `Symbolizer`, one frame per inlined function.
+//! Without one, or for addresses it does not know, they are named
+//! `file+0xoffset` like `perf::Frame`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::string::String;
+use std::vec::Vec;
+use std::{format, vec};
+
+use find_cfi::Module;
+use symbolize::Symbolizer;
+
+/// How to name and label the output.
+pub struct Flamegraph<'a> {
+    /// The name of the profile in speedscope.
+    pub name: String,
+    /// The unit of the sample values in speedscope: `"none"` for counts,
+    /// or e.g. `"nanoseconds"` or `"bytes"`.
+    pub unit: String,
+    pub symbolizer: Option<&'a dyn Symbolizer>,
+}
+
+impl<'a> Default for Flamegraph<'a> {
+    fn default() -> Flamegraph<'a> {
+        Flamegraph {
+            name: "unwind".into(),
+            unit: "none".into(),
+            symbolizer: None,
+        }
+    }
+}
+
+/// `file+0xoffset`, with the offset relative to the object's link-time
+/// addresses, or just the address if the module is unknown.
+pub(crate) fn address_name(module: Option<&Module>, address: u64) -> String {
+    match module {
+        Some(module) => {
+            let file = module.path().rsplit('/').next().unwrap_or("");
+            format!("{}+0x{:x}", file, address.wrapping_sub(module.base()))
+        }
+        None => format!("0x{:x}", address),
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct Frame {
+    name: String,
+    file: String,
+}
+
+/// The frames of all stacks, and every stack as indices into them.
+#[derive(Default)]
+struct Frames {
+    table: Vec<Frame>,
+    index: HashMap<Frame, usize>,
+    addresses: HashMap<(u64, bool), Vec<usize>>,
+}
+
+impl Frames {
+    fn add(&mut self, frame: Frame) -> usize {
+        if let Some(&i) = self.index.get(&frame) {
+            return i;
+        }
+        let i = self.table.len();
+        self.index.insert(Frame { name: frame.name.clone(), file: frame.file.clone() }, i);
+        self.table.push(frame);
+        i
+    }
+
+    /// The frames of `address`, the outermost one first.
+    fn at(&mut self, flamegraph: &Flamegraph, modules: &[Module], address: u64, leaf: bool) -> &[usize] {
+        if !self.addresses.contains_key(&(address, leaf)) {
+            // Callers are looked up inside the call.
+            let lookup = if leaf { address } else { address.wrapping_sub(1) };
+            let module = modules.iter().find(|module| module.text().contains(lookup));
+            let symbols = match flamegraph.symbolizer {
+                Some(symbolizer) => symbolizer.symbolize(module, lookup),
+                None => Vec::new(),
+            };
+            let frames = if symbols.is_empty() {
+                vec![self.add(Frame { name: address_name(module, lookup), file: String::new() })]
+            } else {
+                symbols.into_iter().rev().map(|symbol| self.add(Frame { name: symbol.name, file: symbol.file })).collect()
+            };
+            self.addresses.insert((address, leaf), frames);
+        }
+        &self.addresses[&(address, leaf)]
+    }
+
+    /// Every stack, the outermost frame first.
+    fn stacks(&mut self, flamegraph: &Flamegraph, stacks: &[(Vec<u64>, i64)], modules: &[Module]) -> Vec<(Vec<usize>, i64)> {
+        stacks.iter().map(|&(ref ips, value)| {
+            let mut frames = Vec::new();
+            for (i, &ip) in ips.iter().enumerate().rev() {
+                frames.extend_from_slice(self.at(flamegraph, modules, ip, i == 0));
+            }
+            (frames, value)
+        }).collect()
+    }
+}
+
+pub(crate) fn write_folded<W: Write>(folded: &BTreeMap<String, i64>, mut out: W) -> io::Result<()> {
+    for (stack, value) in folded {
+        writeln!(out, "{} {}", stack, value)?;
+    }
+    Ok(())
+}
+
+fn write_json_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+impl<'a> Flamegraph<'a> {
+    /// Writes `stacks`, each the sampled instruction followed by return
+    /// addresses, with its value, in the folded format.
+    ///
+    /// Stacks that end up with the same frames are merged. As `;` separates
+    /// frames, it is replaced by `:` in names.
+    pub fn write_folded<W: Write>(&self, stacks: &[(Vec<u64>, i64)], modules: &[Module], out: W) -> io::Result<()> {
+        let mut folded = BTreeMap::new();
+        self.fold(stacks, modules, None, &mut folded);
+        write_folded(&folded, out)
+    }
+
+    /// Adds the folded `stacks` to `folded`, below a frame named `root` if
+    /// there is one.
+    pub(crate) fn fold(&self, stacks: &[(Vec<u64>, i64)], modules: &[Module], root: Option<&str>, folded: &mut BTreeMap<String, i64>) {
+        let mut frames = Frames::default();
+        for (stack, value) in frames.stacks(self, stacks, modules) {
+            let names: Vec<String> = root.into_iter().map(String::from)
+                .chain(stack.iter().map(|&i| frames.table[i].name.clone()))
+                .map(|name| name.replace(';', ":"))
+                .collect();
+            *folded.entry(names.join(";")).or_insert(0) += value;
+        }
+    }
+
+    /// Writes `stacks` as a speedscope file with one sampled profile.
+    pub fn write_speedscope<W: Write>(&self, stacks: &[(Vec<u64>, i64)], modules: &[Module], mut out: W) -> io::Result<()> {
+        let mut frames = Frames::default();
+        let stacks = frames.stacks(self, stacks, modules);
+
+        out.write_all(b"{\"$schema\":\"https://www.speedscope.app/file-format-schema.json\",\"shared\":{\"frames\":[")?;
+        for (i, frame) in frames.table.iter().enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            out.write_all(b"{\"name\":")?;
+            write_json_string(&mut out, &frame.name)?;
+            if !frame.file.is_empty() {
+                out.write_all(b",\"file\":")?;
+                write_json_string(&mut out, &frame.file)?;
+            }
+            out.write_all(b"}")?;
+        }
+        out.write_all(b"]},\"profiles\":[{\"type\":\"sampled\",\"name\":")?;
+        write_json_string(&mut out, &self.name)?;
+        out.write_all(b",\"unit\":")?;
+        write_json_string(&mut out, &self.unit)?;
+        let total: i64 = stacks.iter().map(|stack| stack.1).sum();
+        write!(out, ",\"startValue\":0,\"endValue\":{},\"samples\":[", total)?;
+        for (i, (stack, _)) in stacks.iter().enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            out.write_all(b"[")?;
+            for (j, frame) in stack.iter().enumerate() {
+                write!(out, "{}{}", if j == 0 { "" } else { "," }, frame)?;
+            }
+            out.write_all(b"]")?;
+        }
+        out.write_all(b"],\"weights\":[")?;
+        for (i, &(_, value)) in stacks.iter().enumerate() {
+            write!(out, "{}{}", if i == 0 { "" } else { "," }, value)?;
+        }
+        out.write_all(b"]}],\"name\":")?;
+        write_json_string(&mut out, &self.name)?;
+        out.write_all(b",\"exporter\":\"unwind\"}\n")
+    }
+}
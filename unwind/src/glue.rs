@@ -4,67 +4,93 @@ use registers::Registers;
 type UnwindPayload<'a> = &'a mut dyn FnMut(Registers);
 
 pub fn registers<F>(mut f: F) where F: FnMut(Registers) {
-    let mut f = &mut f as UnwindPayload;
+    registers_dyn(&mut f)
+}
+
+// Calls the trampoline from a single non-generic function, so that its frame
+// can be recognised by address (see `is_internal_frame`).
+#[inline(never)]
+pub(crate) fn registers_dyn(mut f: UnwindPayload) {
     unsafe { unwind_trampoline(&mut f) };
 }
 
 #[allow(improper_ctypes)] // trampoline just forwards the ptr
-extern "C" {
-    #[cfg(not(feature = "asm"))]
+extern "C-unwind" {
     pub fn unwind_trampoline(payload: *mut UnwindPayload);
-    #[cfg(not(feature = "asm"))]
-    fn unwind_lander(regs: *const LandingRegisters);
 }
 
-#[cfg(feature = "asm")]
-#[naked]
-pub unsafe extern fn unwind_trampoline(_payload: *mut UnwindPayload) {
-    asm!("
-     movq %rsp, %rsi
-     .cfi_def_cfa rsi, 8
-     pushq %rbp
-     .cfi_offset rbp, -16
-     pushq %rbx
-     pushq %r12
-     pushq %r13
-     pushq %r14
-     pushq %r15
-     movq %rsp, %rdx
-     subq 0x08, %rsp
-     .cfi_def_cfa rsp, 0x40
-     call unwind_recorder
-     addq 0x38, %rsp
-     .cfi_def_cfa rsp, 8
-     ret
-     ");
-    ::std::hint::unreachable_unchecked();
+extern "C" {
+    fn unwind_lander(regs: *const LandingRegisters);
 }
 
-#[cfg(feature = "asm")]
-#[naked]
-unsafe extern fn unwind_lander(_regs: *const LandingRegisters) {
-    asm!("
-     movq %rdi, %rsp
-     popq %rax
-     popq %rbx
-     popq %rcx
-     popq %rdx
-     popq %rdi
-     popq %rsi
-     popq %rbp
-     popq %r8
-     popq %r9
-     popq %r10
-     popq %r11
-     popq %r12
-     popq %r13
-     popq %r14
-     popq %r15
-     movq 0(%rsp), %rsp
-     ret // HYPERSPACE JUMP :D
-     ");
-    ::std::hint::unreachable_unchecked();
-}
+// unwind_trampoline(payload) spills the callee-saved registers and calls
+// unwind_recorder(payload, stack, saved_regs), where `stack` points at our
+// return address. The CFI describes the spills so the trampoline frame
+// itself can be unwound, e.g. by a panic escaping the payload.
+#[cfg(target_arch = "x86_64")]
+::core::arch::global_asm!("
+    .globl unwind_trampoline
+    .type unwind_trampoline, @function
+unwind_trampoline:
+    .cfi_startproc
+    movq %rsp, %rsi
+    pushq %rbp
+    .cfi_adjust_cfa_offset 8
+    .cfi_offset %rbp, -16
+    pushq %rbx
+    .cfi_adjust_cfa_offset 8
+    .cfi_offset %rbx, -24
+    pushq %r12
+    .cfi_adjust_cfa_offset 8
+    .cfi_offset %r12, -32
+    pushq %r13
+    .cfi_adjust_cfa_offset 8
+    .cfi_offset %r13, -40
+    pushq %r14
+    .cfi_adjust_cfa_offset 8
+    .cfi_offset %r14, -48
+    pushq %r15
+    .cfi_adjust_cfa_offset 8
+    .cfi_offset %r15, -56
+    movq %rsp, %rdx
+    subq $0x08, %rsp
+    .cfi_adjust_cfa_offset 8
+    call {recorder}
+    addq $0x38, %rsp
+    .cfi_adjust_cfa_offset -0x38
+    .cfi_restore %rbp
+    .cfi_restore %rbx
+    .cfi_restore %r12
+    .cfi_restore %r13
+    .cfi_restore %r14
+    .cfi_restore %r15
+    ret
+    .cfi_endproc
+    .size unwind_trampoline, . - unwind_trampoline
+
+    .globl unwind_lander
+    .type unwind_lander, @function
+unwind_lander:
+    movq %rdi, %rsp
+    popq %rax
+    popq %rbx
+    popq %rcx
+    popq %rdx
+    popq %rdi
+    popq %rsi
+    popq %rbp
+    popq %r8
+    popq %r9
+    popq %r10
+    popq %r11
+    popq %r12
+    popq %r13
+    popq %r14
+    popq %r15
+    movq 0(%rsp), %rsp
+    ret // HYPERSPACE JUMP :D
+    .size unwind_lander, . - unwind_lander
+", recorder = sym unwind_recorder, options(att_syntax));
 
 #[repr(C)]
 struct LandingRegisters {
@@ -97,8 +123,8 @@ pub struct SavedRegs {
     pub rbp: u64,
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn unwind_recorder(payload: *mut UnwindPayload, stack: u64, saved_regs: *mut SavedRegs) {
+// Only called by the trampoline, with the payload pointer it was given.
+unsafe extern "C-unwind" fn unwind_recorder(payload: *mut UnwindPayload, stack: u64, saved_regs: *mut SavedRegs) {
     let payload = &mut *payload;
     let saved_regs = &*saved_regs;
 
@@ -115,6 +141,14 @@ pub unsafe extern "C" fn unwind_recorder(payload: *mut UnwindPayload, stack: u64
     payload(registers);
 }
 
+/// Resumes execution with the given registers, never returning.
+///
+/// # Safety
+///
+/// `regs` must describe a frame that is live on the current stack, above
+/// the caller, with RSP and RA set, such as the registers of a landing pad
+/// found by unwinding. Every frame below it is abandoned without running
+/// destructors.
 pub unsafe fn land(regs: &Registers) {
     let mut lr = LandingRegisters {
         rax: regs[X86_64::RAX].unwrap_or(0),
@@ -0,0 +1,49 @@
+use core::fmt::{self, Display, Formatter};
+
+/// Sanity checks that end a walk before wrong CFI makes it loop forever or
+/// wander off into unrelated memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guards {
+    /// The most frames a walk may produce.
+    pub max_depth: usize,
+    /// Every frame's CFA must be above the previous one, unless the walk
+    /// just moved to another stack.
+    pub cfa_order: bool,
+    /// Every return address must be in the code of a known module. Without
+    /// this, frames in unknown code (e.g. JIT code) are unwound through the
+    /// frame pointer.
+    pub return_address: bool,
+    /// Every CFA must be 8-byte aligned.
+    pub alignment: bool,
+}
+
+impl Default for Guards {
+    fn default() -> Guards {
+        Guards {
+            max_depth: 16384,
+            cfa_order: true,
+            return_address: true,
+            alignment: true,
+        }
+    }
+}
+
+/// The check in `Guards` that ended a walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Guard {
+    MaxDepth,
+    CfaOrder,
+    ReturnAddress,
+    Alignment,
+}
+
+impl Display for Guard {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            Guard::MaxDepth => write!(fmt, "too many frames"),
+            Guard::CfaOrder => write!(fmt, "CFA is not above the previous frame's"),
+            Guard::ReturnAddress => write!(fmt, "return address is outside all known modules"),
+            Guard::Alignment => write!(fmt, "CFA is misaligned"),
+        }
+    }
+}
@@ -0,0 +1,469 @@
+//! A sampling heap profiler.
+//!
+//! `SampledAlloc` wraps the global allocator. While a `HeapProfiler` runs,
+//! it picks allocations the way tcmalloc does: the number of bytes between
+//! two samples is drawn from an exponential distribution, so every byte has
+//! the same chance of being sampled, and a sampled allocation of `size`
+//! bytes stands for `size / (1 - exp(-size / interval))` bytes. A sampled
+//! allocation is recorded with its stack until it is freed.
+//!
+//! The recording happens inside the allocator, so it must neither allocate
+//! nor take locks an allocation might wait for. The stack is walked with a
+//! `SignalSafeUnwinder` into a fixed-size table allocated in `start`, and a
+//! per-thread flag lets allocations the profiler makes itself pass straight
+//! through to the wrapped allocator.
+//!
+//! Stacks start inside the allocator, with the frames of `SampledAlloc`.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::{Cell, UnsafeCell};
+use std::io;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::boxed::Box;
+use std::vec::Vec;
+use std::vec;
+
+use {Module, ModuleIndex, Symbolizer};
+use pprof::Pprof;
+use signal_safe::SignalSafeUnwinder;
+
+const MAX_FRAMES: usize = 64;
+const EMPTY: usize = 0;
+const TOMBSTONE: usize = 1;
+
+static STATE: AtomicPtr<State> = AtomicPtr::new(ptr::null_mut());
+// Allocator calls that may be using the state right now.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+// Live sampled allocations, so that most frees need not look anything up.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static INTERVAL: AtomicUsize = AtomicUsize::new(0);
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+struct ThreadState {
+    /// Set while the profiler itself runs on this thread.
+    busy: Cell<bool>,
+    until_sample: Cell<i64>,
+    /// Zero until the first allocation on this thread.
+    rng: Cell<u64>,
+}
+
+// Const-initialized and without a destructor, so that using it neither
+// allocates nor fails while the thread exits.
+std::thread_local!(static THREAD: ThreadState = const {
+    ThreadState { busy: Cell::new(false), until_sample: Cell::new(0), rng: Cell::new(0) }
+});
+
+/// The bytes until the next sample, exponentially distributed with the
+/// given mean.
+fn next_interval(rng: &Cell<u64>, mean: usize) -> i64 {
+    // xorshift64*
+    let mut x = rng.get();
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    rng.set(x);
+    let u = ((x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+    (-u.ln() * mean as f64) as i64 + 1
+}
+
+/// Runs `f` with sampling off on this thread.
+fn without_sampling<T, F: FnOnce() -> T>(f: F) -> T {
+    let busy = THREAD.with(|thread| thread.busy.replace(true));
+    let result = f();
+    THREAD.with(|thread| thread.busy.set(busy));
+    result
+}
+
+/// A sampled allocation as kept in the table.
+#[derive(Clone, Copy)]
+struct Entry {
+    size: usize,
+    /// Where its stack starts in `Table::stacks`.
+    stack: usize,
+    len: usize,
+}
+
+/// Sampled allocations by address, in open addressing with linear probing.
+///
+/// Keys are read without the lock, so that a free can tell cheaply that its
+/// pointer was not sampled: no key is further than `max_probe` slots from
+/// where it hashes to. Everything else is only touched with `lock` held.
+struct Table {
+    keys: Vec<AtomicUsize>,
+    max_probe: AtomicUsize,
+    lock: AtomicBool,
+    entries: UnsafeCell<Vec<Entry>>,
+    stacks: UnsafeCell<Vec<u64>>,
+    /// Unused stacks, with room for all of them so that pushing never
+    /// allocates.
+    free: UnsafeCell<Vec<usize>>,
+}
+
+unsafe impl Sync for Table {}
+
+impl Table {
+    fn new(max_live: usize) -> Table {
+        let capacity = (max_live * 2).next_power_of_two();
+        Table {
+            keys: (0..capacity).map(|_| AtomicUsize::new(EMPTY)).collect(),
+            max_probe: AtomicUsize::new(0),
+            lock: AtomicBool::new(false),
+            entries: UnsafeCell::new(vec![Entry { size: 0, stack: 0, len: 0 }; capacity]),
+            stacks: UnsafeCell::new(vec![0; max_live * MAX_FRAMES]),
+            free: UnsafeCell::new((0..max_live).rev().map(|i| i * MAX_FRAMES).collect()),
+        }
+    }
+
+    fn home(&self, ptr: usize) -> usize {
+        ((ptr as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize & (self.keys.len() - 1)
+    }
+
+    fn with_lock<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            thread::yield_now();
+        }
+        let result = f();
+        self.lock.store(false, Ordering::Release);
+        result
+    }
+
+    fn insert(&self, ptr: usize, size: usize, ips: &[u64]) -> bool {
+        self.with_lock(|| unsafe {
+            let (entries, stacks, free) = (&mut *self.entries.get(), &mut *self.stacks.get(), &mut *self.free.get());
+            let stack = match free.pop() {
+                Some(stack) => stack,
+                None => return false,
+            };
+            let home = self.home(ptr);
+            for probe in 0..self.keys.len() {
+                let slot = (home + probe) & (self.keys.len() - 1);
+                let key = self.keys[slot].load(Ordering::Relaxed);
+                if key == EMPTY || key == TOMBSTONE {
+                    stacks[stack..stack + ips.len()].copy_from_slice(ips);
+                    entries[slot] = Entry { size, stack, len: ips.len() };
+                    self.max_probe.fetch_max(probe, Ordering::Relaxed);
+                    self.keys[slot].store(ptr, Ordering::Release);
+                    LIVE.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+            }
+            // Unreachable while the table is at most half full.
+            free.push(stack);
+            false
+        })
+    }
+
+    fn remove(&self, ptr: usize) {
+        let home = self.home(ptr);
+        for probe in 0..=self.max_probe.load(Ordering::Relaxed) {
+            let slot = (home + probe) & (self.keys.len() - 1);
+            if self.keys[slot].load(Ordering::Relaxed) != ptr {
+                continue;
+            }
+            self.with_lock(|| unsafe {
+                if self.keys[slot].load(Ordering::Relaxed) == ptr {
+                    self.keys[slot].store(TOMBSTONE, Ordering::Relaxed);
+                    let (entries, free) = (&*self.entries.get(), &mut *self.free.get());
+                    free.push(entries[slot].stack);
+                    LIVE.fetch_sub(1, Ordering::Relaxed);
+                }
+            });
+            return;
+        }
+    }
+
+    fn allocations(&self) -> Vec<Allocation> {
+        self.with_lock(|| unsafe {
+            let entries = &*self.entries.get();
+            let stacks = &*self.stacks.get();
+            self.keys.iter().zip(entries).filter(|&(key, _)| key.load(Ordering::Relaxed) > TOMBSTONE).map(|(_, entry)| {
+                Allocation { size: entry.size, stack: stacks[entry.stack..entry.stack + entry.len].to_vec() }
+            }).collect()
+        })
+    }
+}
+
+struct State {
+    unwinder: SignalSafeUnwinder,
+    table: Table,
+    dropped: AtomicU64,
+}
+
+/// Calls `f` with the state if a profiler is running.
+fn with_state<F: FnOnce(&State)>(f: F) {
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    let state = STATE.load(Ordering::SeqCst);
+    if !state.is_null() {
+        f(unsafe { &*state });
+    }
+    ACTIVE.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Wraps an allocator to sample its allocations while a `HeapProfiler`
+/// runs. Has no effect unless it is the `#[global_allocator]`.
+#[derive(Debug, Default)]
+pub struct SampledAlloc<A> {
+    inner: A,
+}
+
+impl<A> SampledAlloc<A> {
+    pub const fn new(inner: A) -> SampledAlloc<A> {
+        SampledAlloc { inner }
+    }
+
+    fn allocated(&self, ptr: *mut u8, size: usize) {
+        if ptr.is_null() || STATE.load(Ordering::Relaxed).is_null() {
+            return;
+        }
+        let _ = THREAD.try_with(|thread| {
+            if thread.busy.get() {
+                return;
+            }
+            let interval = INTERVAL.load(Ordering::Relaxed);
+            let mut until = thread.until_sample.get();
+            if thread.rng.get() == 0 {
+                let seed = SEED.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed) ^ (thread as *const ThreadState as u64);
+                thread.rng.set(seed | 1);
+                until = next_interval(&thread.rng, interval);
+            }
+            until -= size as i64;
+            if until > 0 {
+                thread.until_sample.set(until);
+                return;
+            }
+            thread.until_sample.set(next_interval(&thread.rng, interval));
+            thread.busy.set(true);
+            with_state(|state| record(state, ptr as usize, size));
+            thread.busy.set(false);
+        });
+    }
+
+    fn freed(&self, ptr: *mut u8) {
+        if LIVE.load(Ordering::Relaxed) != 0 {
+            with_state(|state| state.table.remove(ptr as usize));
+        }
+    }
+}
+
+#[inline(never)]
+fn record(state: &State, ptr: usize, size: usize) {
+    let mut ips = [0; MAX_FRAMES];
+    let len = state.unwinder.trace(&mut ips);
+    if len == 0 || !state.table.insert(ptr, size, &ips[..len]) {
+        state.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for SampledAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        self.allocated(ptr, layout.size());
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        self.allocated(ptr, layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Before the address can be handed out again.
+        self.freed(ptr);
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // If this fails the old allocation stays, untracked.
+        self.freed(ptr);
+        let new = self.inner.realloc(ptr, layout, new_size);
+        self.allocated(new, new_size);
+        new
+    }
+}
+
+/// How to profile.
+#[derive(Debug, Clone)]
+pub struct HeapProfiler {
+    /// The mean number of bytes allocated between two samples.
+    pub sample_interval: usize,
+    /// The most sampled allocations tracked at once. Samples beyond that
+    /// are counted as dropped.
+    pub max_live: usize,
+}
+
+impl Default for HeapProfiler {
+    fn default() -> HeapProfiler {
+        HeapProfiler {
+            sample_interval: 512 * 1024,
+            max_live: 4096,
+        }
+    }
+}
+
+impl HeapProfiler {
+    /// Starts sampling allocations made through `SampledAlloc`. Fails if
+    /// another heap profiler is running.
+    ///
+    /// The module index is built here if it does not exist yet; objects
+    /// loaded later are not seen.
+    pub fn start(&self) -> io::Result<RunningHeapProfiler> {
+        if self.sample_interval == 0 || self.max_live == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sample interval and table size must not be zero"));
+        }
+        let index = ModuleIndex::global();
+        let concurrency = thread::available_parallelism().map_or(8, |n| n.get()) * 2;
+        let mut unwinder = SignalSafeUnwinder::new(index.clone(), concurrency);
+        // Threads started later have stacks outside the index's memory map.
+        unwinder.set_process_vm_readv(true);
+        let state = Box::into_raw(Box::new(State {
+            unwinder,
+            table: Table::new(self.max_live),
+            dropped: AtomicU64::new(0),
+        }));
+        INTERVAL.store(self.sample_interval, Ordering::Relaxed);
+        if STATE.compare_exchange(ptr::null_mut(), state, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            drop(unsafe { Box::from_raw(state) });
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a heap profiler is already running"));
+        }
+        Ok(RunningHeapProfiler {
+            state,
+            index,
+            sample_interval: self.sample_interval,
+            started: SystemTime::now(),
+            stopped: false,
+        })
+    }
+}
+
+/// A heap profiler that samples allocations until it is stopped or dropped.
+pub struct RunningHeapProfiler {
+    state: *mut State,
+    index: Arc<ModuleIndex>,
+    sample_interval: usize,
+    started: SystemTime,
+    stopped: bool,
+}
+
+unsafe impl Send for RunningHeapProfiler {}
+unsafe impl Sync for RunningHeapProfiler {}
+
+impl RunningHeapProfiler {
+    /// The sampled allocations not freed yet.
+    pub fn report(&self) -> HeapReport {
+        without_sampling(|| {
+            let state = unsafe { &*self.state };
+            HeapReport {
+                allocations: state.table.allocations(),
+                dropped: state.dropped.load(Ordering::Relaxed),
+                sample_interval: self.sample_interval,
+                modules: self.index.modules().cloned().collect(),
+                started: self.started,
+            }
+        })
+    }
+
+    /// Stops sampling and returns the sampled allocations not freed yet.
+    pub fn stop(mut self) -> HeapReport {
+        self.finish();
+        self.report()
+    }
+
+    fn finish(&mut self) {
+        if self.stopped {
+            return;
+        }
+        self.stopped = true;
+        STATE.store(ptr::null_mut(), Ordering::SeqCst);
+        while ACTIVE.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        LIVE.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RunningHeapProfiler {
+    fn drop(&mut self) {
+        self.finish();
+        drop(unsafe { Box::from_raw(self.state) });
+    }
+}
+
+/// A sampled allocation.
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    size: usize,
+    stack: Vec<u64>,
+}
+
+impl Allocation {
+    /// The requested size in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Return addresses, the innermost first.
+    pub fn stack(&self) -> &[u64] {
+        &self.stack
+    }
+}
+
+/// The sampled allocations live at some point.
+#[derive(Debug, Clone)]
+pub struct HeapReport {
+    allocations: Vec<Allocation>,
+    dropped: u64,
+    sample_interval: usize,
+    modules: Vec<Module>,
+    started: SystemTime,
+}
+
+impl HeapReport {
+    pub fn allocations(&self) -> &[Allocation] {
+        &self.allocations
+    }
+
+    /// Sampled allocations that were not recorded because the table was
+    /// full or their stack could not be walked.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The bytes a sampled allocation of `size` bytes stands for.
+    pub fn estimate(&self, size: usize) -> f64 {
+        let size = size as f64;
+        size / (1.0 - (-size / self.sample_interval as f64).exp())
+    }
+
+    /// The estimated live bytes per distinct stack, as taken by the
+    /// exporters.
+    pub fn stacks(&self) -> Vec<(Vec<u64>, i64)> {
+        let mut stacks: Vec<(Vec<u64>, i64)> = Vec::new();
+        let mut allocations: Vec<&Allocation> = self.allocations.iter().collect();
+        allocations.sort_by(|a, b| a.stack.cmp(&b.stack));
+        for allocation in allocations {
+            let bytes = self.estimate(allocation.size) as i64;
+            match stacks.last_mut() {
+                Some(last) if last.0 == allocation.stack => last.1 += bytes,
+                _ => stacks.push((allocation.stack.clone(), bytes)),
+            }
+        }
+        stacks
+    }
+
+    /// A gzip-compressed pprof profile of the estimated live bytes.
+    pub fn pprof(&self, symbolizer: Option<&dyn Symbolizer>) -> Vec<u8> {
+        let pprof = Pprof {
+            sample_type: ("inuse_space".into(), "bytes".into()),
+            period: Some(("space".into(), "bytes".into(), self.sample_interval as i64)),
+            time_nanos: self.started.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as i64),
+            symbolizer,
+            ..Pprof::default()
+        };
+        pprof.encode(&self.stacks(), &self.modules)
+    }
+}
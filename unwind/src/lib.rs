@@ -1,36 +1,208 @@
-#![cfg_attr(feature = "nightly", feature(unwind_attributes))]
-#![cfg_attr(feature = "asm", feature(asm, naked_functions))]
+#![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+extern crate alloc;
 extern crate gimli;
 extern crate libc;
 extern crate fallible_iterator;
 #[macro_use] extern crate log;
+#[cfg(feature = "std")]
+extern crate miniz_oxide;
 
 use gimli::{UnwindSection, UnwindTable, UnwindTableRow, EhFrame, BaseAddresses, UninitializedUnwindContext, Pointer, Reader, EndianSlice, NativeEndian, CfaRule, RegisterRule, EhFrameHdr, ParsedEhFrameHdr, X86_64};
 use fallible_iterator::FallibleIterator;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::boxed::Box;
+use core::cell::RefCell;
+use core::ptr;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
-mod registers;
+pub mod registers;
+mod compact;
+mod error;
 mod find_cfi;
+mod guard;
+mod memory;
 mod range;
+mod snapshot;
+mod stacks;
+#[cfg(feature = "std")]
+mod stack_cache;
+mod symbolize;
 pub mod glue;
-pub use registers::Registers;
+pub mod signal_safe;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "std"))]
+pub mod capture;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "std"))]
+pub mod profiler;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "std"))]
+pub mod heap;
+#[cfg(feature = "std")]
+pub mod perf;
+#[cfg(feature = "std")]
+pub mod pprof;
+#[cfg(feature = "std")]
+pub mod flamegraph;
+pub use registers::{Registers, RegisterError};
+pub use compact::{CompactTable, CompactRow, CompactRule};
+pub use error::UnwindError;
+pub use guard::{Guard, Guards};
+use registers::REGISTER_COUNT;
+pub use range::AddrRange;
+pub use memory::MemoryMap;
+use memory::Memory;
+pub use stacks::Stacks;
+pub use snapshot::StackSnapshot;
+pub use find_cfi::Module;
+pub use symbolize::{Symbol, Symbolizer};
+#[cfg(feature = "std")]
+pub use find_cfi::file::FileMapping;
 use find_cfi::EhRef;
 
 #[cfg(feature = "libunwind_shim")]
 pub mod libunwind_shim;
+#[cfg(feature = "panic_runtime")]
+mod lsda;
+#[cfg(feature = "panic_runtime")]
+pub mod panic_runtime;
 
 
 pub struct StackFrames<'a> {
     unwinder: &'a mut DwarfUnwinder,
+    cursor: UnwindCursor,
+    /// Until the first frame of the caller, skip the unwinder's own frames
+    /// and those of the generic `trace` at this address.
+    hide_internal: Option<u64>,
+    skip: usize,
+    #[cfg(feature = "std")]
+    cache: Option<stack_cache::StackCache>,
+}
+
+/// An owned position in a stack walk.
+///
+/// It holds the registers of the current frame and how to get to its
+/// caller, but not the unwinder, so it can be kept around, cloned to fork
+/// the walk, and stepped one frame at a time. The stack it describes must
+/// of course still be live when stepping.
+#[derive(Clone)]
+pub struct UnwindCursor {
     registers: Registers,
-    state: Option<(UnwindTableRow<StaticReader>, u64)>,
+    locations: [SaveLocation; REGISTER_COUNT],
+    state: Option<(UnwindRule, u64, bool)>,
+    interrupted: bool,
+    stacks: Stacks,
+    snapshot: Option<Arc<StackSnapshot>>,
+    depth: usize,
+    last_cfa: Option<u64>,
+    /// `last_cfa` before the current frame was described.
+    caller_cfa: Option<u64>,
+}
+
+/// Everything `UnwindCursor::from_state` needs to start a walk at the
+/// current frame of a cursor, as if it had not been described yet.
+///
+/// Unlike the registers alone, this keeps the save locations, whether the
+/// frame was interrupted and the guard state of the frames below it.
+#[derive(Clone)]
+pub struct CursorState {
+    registers: Registers,
+    locations: [SaveLocation; REGISTER_COUNT],
+    interrupted: bool,
+    stacks: Stacks,
+    snapshot: Option<Arc<StackSnapshot>>,
+    depth: usize,
+    last_cfa: Option<u64>,
+}
+
+impl CursorState {
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn save_location(&self, reg: gimli::Register) -> Result<SaveLocation, RegisterError> {
+        registers::check(reg)?;
+        Ok(self.locations[reg.0 as usize])
+    }
+
+    /// Whether the return address column holds the address of an
+    /// interrupted instruction rather than a return address.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted
+    }
+
+    /// The number of frames described before this one.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
 }
 
-#[derive(Debug)]
+/// How the CFA of a frame, and with it the caller's registers, was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// From the CFI covering the frame's address.
+    Cfi,
+    /// There is no CFI for the address, so the RBP chain was followed.
+    FramePointer,
+    /// There is no CFI and no usable frame pointer, so the innermost (or
+    /// interrupted) frame was assumed to be a leaf function that has not
+    /// touched the stack yet.
+    Heuristic,
+}
+
+/// Where the value of a register in the current frame is kept, like
+/// libunwind's `unw_get_save_loc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveLocation {
+    /// The value is unknown, e.g. a caller-saved register in a caller frame.
+    Undefined,
+    /// The value is still live in this register: no frame below saved it.
+    Register(gimli::Register),
+    /// The value was saved on the stack at this address.
+    Memory(u64),
+    /// The value is not stored anywhere but recomputed from the CFA, like
+    /// the stack pointer of every frame but the innermost.
+    Computed,
+}
+
+fn live_locations() -> [SaveLocation; REGISTER_COUNT] {
+    let mut locations = [SaveLocation::Undefined; REGISTER_COUNT];
+    for (i, location) in locations.iter_mut().enumerate() {
+        let reg = gimli::Register(i as u16);
+        if registers::check(reg).is_ok() {
+            *location = SaveLocation::Register(reg);
+        }
+    }
+    locations
+}
+
+#[derive(Clone)]
+enum UnwindRule {
+    // A row is about a kilobyte, the other variants are small.
+    Cfi(Box<UnwindTableRow<StaticReader>>),
+    /// The caller's registers are in the `ucontext_t` at this address.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    SignalContext(u64),
+    FramePointer,
+    Heuristic,
+    Compact(CompactRow),
+}
+
+#[derive(Debug, Clone)]
 pub struct StackFrame {
     personality: Option<u64>,
     lsda: Option<u64>,
     initial_address: u64,
+    args_size: u64,
+    return_address: u64,
+    lookup_address: u64,
+    cfa: u64,
+    fde_range: Option<AddrRange>,
+    module: Option<Arc<Module>>,
+    signal_frame: bool,
+    kind: FrameKind,
 }
 
 impl StackFrame {
@@ -45,6 +217,48 @@ impl StackFrame {
     pub fn initial_address(&self) -> u64 {
         self.initial_address
     }
+
+    /// Size of the outgoing arguments pushed at the call site (`DW_CFA_GNU_args_size`).
+    pub fn args_size(&self) -> u64 {
+        self.args_size
+    }
+
+    /// The address execution continues at in this frame.
+    ///
+    /// This is a return address, except for frames interrupted by a signal,
+    /// where it is the address of the interrupted instruction.
+    pub fn return_address(&self) -> u64 {
+        self.return_address
+    }
+
+    /// The address used to look up unwind info for this frame: the return
+    /// address minus one, so that it points into the call instruction.
+    pub fn lookup_address(&self) -> u64 {
+        self.lookup_address
+    }
+
+    pub fn cfa(&self) -> u64 {
+        self.cfa
+    }
+
+    /// The address range covered by this frame's FDE, if it has one.
+    pub fn fde_range(&self) -> Option<AddrRange> {
+        self.fde_range
+    }
+
+    /// The loaded object containing `lookup_address`, if known.
+    pub fn module(&self) -> Option<&Module> {
+        self.module.as_deref()
+    }
+
+    /// Whether this is a signal trampoline frame (augmentation `S`).
+    pub fn is_signal_frame(&self) -> bool {
+        self.signal_frame
+    }
+
+    pub fn kind(&self) -> FrameKind {
+        self.kind
+    }
 }
 
 pub trait Unwinder: Default {
@@ -55,56 +269,343 @@ type StaticReader = EndianSlice<'static, NativeEndian>;
 
 struct ObjectRecord {
     er: EhRef,
+    module: Arc<Module>,
     eh_frame_hdr: ParsedEhFrameHdr<StaticReader>,
     eh_frame: EhFrame<StaticReader>,
     bases: BaseAddresses,
+    compact: compact::LazyTable,
+    #[cfg(feature = "std")]
+    _image: Option<find_cfi::file::FileImage>,
 }
 
-pub struct DwarfUnwinder {
+impl ObjectRecord {
+    /// Parses the CFI sections of `er`. `bytes(addr, len)` returns up to
+    /// `len` bytes of the object at `addr`.
+    fn new<F>(mut er: EhRef, bytes: F) -> Option<ObjectRecord>
+        where F: Fn(u64, u64) -> Option<&'static [u8]>
+    {
+        // TODO: set_got()
+        let bases = BaseAddresses::default()
+            .set_eh_frame_hdr(er.eh_frame_hdr.start)
+            .set_text(er.text.start);
+
+        let eh_frame_hdr = bytes(er.eh_frame_hdr.start, er.eh_frame_hdr.len())?;
+        let eh_frame_hdr = match EhFrameHdr::new(eh_frame_hdr, NativeEndian).parse(&bases, 8) {
+            Ok(hdr) => hdr,
+            Err(e) => {
+                debug!("bad eh_frame_hdr at 0x{:x}: {}", er.eh_frame_hdr.start, e);
+                return None;
+            }
+        };
+
+        let eh_frame_addr = match eh_frame_hdr.eh_frame_ptr() {
+            Pointer::Direct(x) => x,
+            Pointer::Indirect(x) => {
+                let ptr = bytes(x, 8)?;
+                u64::from_ne_bytes(ptr.get(..8)?.try_into().ok()?)
+            }
+        };
+        let eh_frame_sz = er.eh_frame_end.saturating_sub(eh_frame_addr);
+
+        let eh_frame = bytes(eh_frame_addr, eh_frame_sz)?;
+        trace!("eh_frame at {:p} sz {:x}", eh_frame_addr as *const u8, eh_frame.len());
+        let eh_frame = EhFrame::new(eh_frame, NativeEndian);
+
+        let bases = bases.set_eh_frame(eh_frame_addr);
+        let module = Arc::new(er.module.take().unwrap());
+
+        Some(ObjectRecord {
+            er,
+            module,
+            eh_frame_hdr,
+            eh_frame,
+            bases,
+            compact: compact::LazyTable::new(),
+            #[cfg(feature = "std")]
+            _image: None,
+        })
+    }
+
+    fn compact_table(&self) -> &CompactTable {
+        self.compact.get(|| CompactTable::compile(self))
+    }
+}
+
+/// The unwind info of every loaded object.
+///
+/// This is immutable once built, so one index can be shared by all threads.
+pub struct ModuleIndex {
     cfi: Vec<ObjectRecord>,
-    ctx: UninitializedUnwindContext<StaticReader>,
+    memory: Option<MemoryMap>,
+    /// Whether the objects are loaded in this process.
+    live: bool,
+    /// `find_cfi::generation()` when the index was built.
+    generation: Option<u64>,
 }
 
-impl Default for DwarfUnwinder {
-    fn default() -> DwarfUnwinder {
-        let cfi = find_cfi::find_cfi_sections().into_iter().map(|er| {
-            unsafe {
-                // TODO: set_got()
-                let bases = BaseAddresses::default()
-                    .set_eh_frame_hdr(er.eh_frame_hdr.start)
-                    .set_text(er.text.start);
+static GLOBAL_INDEX: AtomicPtr<Arc<ModuleIndex>> = AtomicPtr::new(ptr::null_mut());
 
-                let eh_frame_hdr: &'static [u8] = std::slice::from_raw_parts(er.eh_frame_hdr.start as *const u8, er.eh_frame_hdr.len() as usize);
+impl ModuleIndex {
+    /// Finds the loaded objects and their CFI sections, and takes a snapshot
+    /// of the readable memory to check reads against.
+    ///
+    /// Objects loaded and memory mapped later are not included; build a new
+    /// index to see them.
+    pub fn new() -> ModuleIndex {
+        // Read first, so that objects loaded meanwhile make the index stale.
+        let generation = find_cfi::generation();
+        let cfi = find_cfi::find_cfi_sections().into_iter().filter_map(|er| {
+            ObjectRecord::new(er, |addr, len| Some(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) }))
+        }).collect();
 
-                let eh_frame_hdr = EhFrameHdr::new(eh_frame_hdr, NativeEndian).parse(&bases, 8).unwrap();
+        ModuleIndex { cfi, memory: MemoryMap::snapshot(), live: true, generation }
+    }
 
-                let eh_frame_addr = deref_ptr(eh_frame_hdr.eh_frame_ptr());
-                let eh_frame_sz = er.eh_frame_end.saturating_sub(eh_frame_addr);
+    /// Indexes object files mapped into another process, e.g. one recorded
+    /// with `perf`, to unwind `StackSnapshot`s taken there.
+    ///
+    /// Files that cannot be read or have no `PT_GNU_EH_FRAME` segment are
+    /// left out. The memory map is empty, so walks only read the snapshot,
+    /// and indirect personality and LSDA pointers are not resolved.
+    #[cfg(feature = "std")]
+    pub fn from_files(mappings: &[FileMapping]) -> ModuleIndex {
+        let cfi = mappings.iter().filter_map(find_cfi::file::load).filter_map(|(er, image)| {
+            // The sections point into the file contents, which the record
+            // keeps alive on the heap.
+            let record = ObjectRecord::new(er, |addr, len| {
+                image.bytes(addr, len).map(|bytes| unsafe { &*(bytes as *const [u8]) })
+            })?;
+            Some(ObjectRecord { _image: Some(image), ..record })
+        }).collect();
 
-                let eh_frame: &'static [u8] = std::slice::from_raw_parts(eh_frame_addr as *const u8, eh_frame_sz as usize);
-                trace!("eh_frame at {:p} sz {:x}", eh_frame_addr as *const u8, eh_frame_sz);
-                let eh_frame = EhFrame::new(eh_frame, NativeEndian);
+        ModuleIndex { cfi, memory: Some(MemoryMap::default()), live: false, generation: None }
+    }
+
+    /// The process-wide index, built on first use and rebuilt once objects
+    /// were loaded or unloaded since.
+    ///
+    /// This calls `dl_iterate_phdr`, which takes the dynamic loader's lock,
+    /// so it is not async-signal-safe. A replaced index is leaked, as other
+    /// threads may still be reading the pointer to it.
+    pub fn global() -> Arc<ModuleIndex> {
+        let mut index = GLOBAL_INDEX.load(Ordering::Acquire);
+        if index.is_null() || unsafe { (**index).is_stale() } {
+            let new = Box::into_raw(Box::new(Arc::new(ModuleIndex::new())));
+            index = match GLOBAL_INDEX.compare_exchange(index, new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => new,
+                Err(winner) => {
+                    // Another thread was faster.
+                    drop(unsafe { Box::from_raw(new) });
+                    winner
+                }
+            };
+        }
+        unsafe { (*index).clone() }
+    }
 
-                let bases = bases.set_eh_frame(eh_frame_addr);
+    /// Whether objects were loaded or unloaded since the index was built.
+    fn is_stale(&self) -> bool {
+        self.live && find_cfi::generation() != self.generation
+    }
 
-                ObjectRecord { er, eh_frame_hdr, eh_frame, bases }
-            }
-        }).collect();
+    fn object_for(&self, address: u64) -> Option<&ObjectRecord> {
+        self.cfi.iter().find(|x| x.er.text.contains(address))
+    }
 
+    /// The compact unwind table of the object containing `address`,
+    /// compiled on first use.
+    pub fn compact_table(&self, address: u64) -> Option<&CompactTable> {
+        self.object_for(address).map(ObjectRecord::compact_table)
+    }
+
+    /// Compiles the compact unwind table of every object now rather than on
+    /// first use. Walks that must not allocate, such as `trace_ips` and
+    /// those of `SignalSafeUnwinder`, then use the tables wherever a row has
+    /// a rule.
+    pub fn compile_compact_tables(&self) {
+        for rec in &self.cfi {
+            rec.compact_table();
+        }
+    }
+
+    /// The loaded objects, in the order the dynamic loader reported them.
+    pub fn modules(&self) -> impl Iterator<Item = &Module> {
+        self.cfi.iter().map(|rec| &*rec.module)
+    }
+
+    /// The readable memory when the index was built, if it could be read.
+    pub fn memory_map(&self) -> Option<&MemoryMap> {
+        self.memory.as_ref()
+    }
+}
+
+impl Default for ModuleIndex {
+    fn default() -> ModuleIndex {
+        ModuleIndex::new()
+    }
+}
+
+/// A `ModuleIndex` plus the scratch space needed to evaluate CFI.
+///
+/// The scratch space is per thread, the index is shared: the default
+/// unwinder uses `ModuleIndex::global()`, so creating one does not index
+/// the objects again. It does ask the dynamic loader whether objects were
+/// loaded, under the loader's lock, so hot paths should keep an unwinder
+/// and reuse it.
+pub struct DwarfUnwinder {
+    index: Arc<ModuleIndex>,
+    ctx: RefCell<UninitializedUnwindContext<StaticReader>>,
+    process_vm_readv: bool,
+    guards: Guards,
+    compact_tables: bool,
+    #[cfg(feature = "std")]
+    stack_cache: bool,
+}
+
+impl DwarfUnwinder {
+    pub fn new(index: Arc<ModuleIndex>) -> DwarfUnwinder {
         DwarfUnwinder {
-            cfi,
-            ctx: UninitializedUnwindContext::new(),
+            index,
+            ctx: RefCell::new(UninitializedUnwindContext::new()),
+            process_vm_readv: false,
+            guards: Guards::default(),
+            compact_tables: false,
+            #[cfg(feature = "std")]
+            stack_cache: false,
+        }
+    }
+
+    pub fn index(&self) -> &Arc<ModuleIndex> {
+        &self.index
+    }
+
+    /// Reads memory outside the stack being walked and the index's memory
+    /// map with `process_vm_readv` instead of giving up on it. This costs a
+    /// system call per read, but reaches memory mapped after the snapshot.
+    pub fn set_process_vm_readv(&mut self, enabled: bool) {
+        self.process_vm_readv = enabled;
+    }
+
+    pub fn guards(&self) -> &Guards {
+        &self.guards
+    }
+
+    /// Sets the sanity checks applied to every walk made with this unwinder.
+    pub fn set_guards(&mut self, guards: Guards) {
+        self.guards = guards;
+    }
+
+    /// Looks frames up in the index's `CompactTable`s instead of evaluating
+    /// their CFI, wherever the row fits the compact form. Each table is
+    /// compiled the first time a walk enters its object.
+    ///
+    /// Frames unwound this way have no personality or LSDA, and callee-saved
+    /// registers other than RBP that they saved are undefined in their
+    /// callers.
+    pub fn set_compact_tables(&mut self, enabled: bool) {
+        self.compact_tables = enabled;
+    }
+
+    /// Makes `trace` remember the frames of the last complete walk on each
+    /// thread, and reuse them once a walk reaches a frame from it whose
+    /// callers are still in place.
+    ///
+    /// While reused frames are produced, `StackFrames::registers` and
+    /// `save_location` still describe the frame where the walk joined the
+    /// cached one.
+    #[cfg(feature = "std")]
+    pub fn set_stack_cache(&mut self, enabled: bool) {
+        self.stack_cache = enabled;
+    }
+
+    fn memory<'b>(&'b self, stacks: &'b [AddrRange], snapshot: Option<&'b StackSnapshot>) -> Memory<'b> {
+        Memory {
+            stacks,
+            snapshot: snapshot.map(|snapshot| (snapshot.base(), snapshot.bytes())),
+            map: self.index.memory_map(),
+            process_vm_readv: self.process_vm_readv,
         }
     }
 }
 
+impl Default for DwarfUnwinder {
+    fn default() -> DwarfUnwinder {
+        DwarfUnwinder::new(ModuleIndex::global())
+    }
+}
+
 impl Unwinder for DwarfUnwinder {
     fn trace<F>(&mut self, mut f: F) where F: FnMut(&mut StackFrames) {
-        glue::registers(|registers| {
+        // This instantiation's frame is hidden too, unless it was inlined.
+        let wrapper = <DwarfUnwinder as Unwinder>::trace::<F> as *const () as u64;
+        self.trace_dyn(wrapper, &mut f)
+    }
+}
+
+impl DwarfUnwinder {
+    // Like `glue::registers_dyn`, this is kept out of line and non-generic so
+    // that its frame can be recognised by address.
+    #[inline(never)]
+    fn trace_dyn(&mut self, wrapper: u64, f: &mut dyn FnMut(&mut StackFrames)) {
+        glue::registers_dyn(&mut |registers| {
+            #[cfg(feature = "std")]
+            let cache = self.stack_cache;
             let mut frames = StackFrames::new(self, registers);
+            frames.hide_internal = Some(wrapper);
+            #[cfg(feature = "std")]
+            {
+                if cache {
+                    frames.cache = Some(stack_cache::StackCache::load());
+                }
+            }
             f(&mut frames)
         });
     }
+
+    /// Writes the return addresses of the caller and its callers into `ips`
+    /// and returns how many were written.
+    ///
+    /// This only computes the CFA and return address of each frame, without
+    /// personality or LSDA pointers. The trace ends at the first frame that
+    /// cannot be unwound.
+    ///
+    /// It does not allocate, except that the first call on each thread looks
+    /// up the thread's stack bounds with `pthread_getattr_np`, which may. Use
+    /// `SignalSafeUnwinder` where that matters.
+    #[inline(never)]
+    pub fn trace_ips(&mut self, ips: &mut [u64]) -> usize {
+        let mut written = 0;
+        glue::registers_dyn(&mut |registers| {
+            let stacks = stacks::ThreadStacks::new(stacks::current_thread_stack(), stacks::current_altstack());
+            let memory = Memory {
+                stacks: stacks.regions(),
+                snapshot: None,
+                map: self.index.memory_map(),
+                process_vm_readv: self.process_vm_readv,
+            };
+            let walker = signal_safe::IpWalker::new(&self.index, self.ctx.get_mut(), memory,
+                                                    signal_safe::gprs(&registers), false);
+            written = walker.collect(Some(0), ips);
+        });
+        written
+    }
+}
+
+/// Whether a frame belongs to the unwinder itself: its FDE covers one of the
+/// functions between the trampoline and the caller of a trace, or `wrapper`,
+/// the generic `trace` the walk was started from (0 if there is none).
+fn is_internal_frame(fde_range: Option<AddrRange>, wrapper: u64) -> bool {
+    let internal = [
+        glue::registers_dyn as *const () as u64,
+        DwarfUnwinder::trace_dyn as *const () as u64,
+        DwarfUnwinder::trace_ips as *const () as u64,
+        signal_safe::SignalSafeUnwinder::trace as *const () as u64,
+        wrapper,
+    ];
+    match fde_range {
+        Some(range) => internal.iter().any(|&f| range.contains(f)),
+        None => false,
+    }
 }
 
 struct UnwindInfo<R: Reader> {
@@ -112,6 +613,8 @@ struct UnwindInfo<R: Reader> {
     personality: Option<Pointer>,
     lsda: Option<Pointer>,
     initial_address: u64,
+    len: u64,
+    signal_frame: bool,
 }
 
 impl ObjectRecord {
@@ -120,14 +623,14 @@ impl ObjectRecord {
         ctx: &mut UninitializedUnwindContext<StaticReader>,
         address: u64,
     ) -> gimli::Result<UnwindInfo<StaticReader>> {
-        let &ObjectRecord {
-            ref eh_frame_hdr,
-            ref eh_frame,
-            ref bases,
+        let ObjectRecord {
+            eh_frame_hdr,
+            eh_frame,
+            bases,
             ..
         } = self;
 
-        let fde = eh_frame_hdr.table().unwrap()
+        let fde = eh_frame_hdr.table().ok_or(gimli::Error::NoUnwindInfoForAddress)?
             .fde_for_address(eh_frame, bases, address, EhFrame::cie_from_offset)?;
         let mut result_row = None;
         {
@@ -146,16 +649,18 @@ impl ObjectRecord {
                 personality: fde.personality(),
                 lsda: fde.lsda(),
                 initial_address: fde.initial_address(),
+                len: fde.len(),
+                signal_frame: fde.is_signal_trampoline(),
             }),
             None => Err(gimli::Error::NoUnwindInfoForAddress)
         }
     }
 }
 
-unsafe fn deref_ptr(ptr: Pointer) -> u64 {
+fn deref_ptr(ptr: Pointer, memory: &Memory) -> Result<u64, UnwindError> {
     match ptr {
-        Pointer::Direct(x) => x,
-        Pointer::Indirect(x) => *(x as *const u64),
+        Pointer::Direct(x) => Ok(x),
+        Pointer::Indirect(x) => memory.read_data_u64(x),
     }
 }
 
@@ -164,72 +669,540 @@ impl<'a> StackFrames<'a> {
     pub fn new(unwinder: &'a mut DwarfUnwinder, registers: Registers) -> Self {
         StackFrames {
             unwinder,
+            cursor: UnwindCursor::new(registers),
+            hide_internal: None,
+            skip: 0,
+            #[cfg(feature = "std")]
+            cache: None,
+        }
+    }
+
+    /// Continues the walk of `cursor`, e.g. one from `UnwindCursor::from_snapshot`.
+    pub fn from_cursor(unwinder: &'a mut DwarfUnwinder, cursor: UnwindCursor) -> Self {
+        StackFrames {
+            unwinder,
+            cursor,
+            hide_internal: None,
+            skip: 0,
+            #[cfg(feature = "std")]
+            cache: None,
+        }
+    }
+
+    pub fn registers(&mut self) -> &mut Registers {
+        &mut self.cursor.registers
+    }
+
+    /// Where the current frame's value of `reg` is kept.
+    pub fn save_location(&self, reg: gimli::Register) -> Result<SaveLocation, RegisterError> {
+        self.cursor.save_location(reg)
+    }
+
+    /// The stacks the walk may run through, e.g. to add coroutine stacks.
+    pub fn stacks_mut(&mut self) -> &mut Stacks {
+        self.cursor.stacks_mut()
+    }
+
+    /// The position of the walk, to be cloned and continued later.
+    pub fn cursor(&self) -> &UnwindCursor {
+        &self.cursor
+    }
+
+    /// Skips the next `n` frames, e.g. wrappers around `trace` in the caller.
+    ///
+    /// Frames of the unwinder itself are already hidden and do not count.
+    pub fn skip_frames(&mut self, n: usize) -> &mut Self {
+        self.skip += n;
+        self
+    }
+
+    fn step(&mut self) -> Result<Option<StackFrame>, UnwindError> {
+        #[cfg(feature = "std")]
+        {
+            if let Some(ref mut cache) = self.cache {
+                let frame = if cache.replaying() {
+                    if cache.depth() >= self.unwinder.guards.max_depth {
+                        return Err(UnwindError::Guard(Guard::MaxDepth));
+                    }
+                    cache.replay()
+                } else {
+                    let frame = self.cursor.step(self.unwinder)?;
+                    if let Some(ref frame) = frame {
+                        let memory = self.unwinder.memory(self.cursor.stacks.regions(), self.cursor.snapshot.as_deref());
+                        cache.unwound(frame, &memory);
+                    }
+                    frame
+                };
+                if frame.is_none() {
+                    cache.finish();
+                }
+                return Ok(frame);
+            }
+        }
+        self.cursor.step(self.unwinder)
+    }
+}
+
+impl UnwindCursor {
+    /// Starts a walk at the frame that `registers` belong to, where the
+    /// return address column holds a return address.
+    ///
+    /// Registers saved from any `StackFrames` or cursor position can be
+    /// passed here to walk again from that frame.
+    ///
+    /// The walk may read the stacks of the calling thread, see `stacks`.
+    /// Registers from another thread need that thread's stacks added.
+    pub fn new(registers: Registers) -> UnwindCursor {
+        UnwindCursor {
             registers,
+            locations: live_locations(),
             state: None,
+            interrupted: false,
+            stacks: Stacks::current_thread(),
+            snapshot: None,
+            depth: 0,
+            last_cfa: None,
+            caller_cfa: None,
         }
     }
 
-    pub fn registers(&mut self) -> &mut Registers {
+    /// Like `new`, for registers of an interrupted frame, e.g. read from a
+    /// signal handler's `ucontext_t`: the return address column holds the
+    /// address of the interrupted instruction.
+    pub fn new_interrupted(registers: Registers) -> UnwindCursor {
+        UnwindCursor {
+            interrupted: true,
+            ..UnwindCursor::new(registers)
+        }
+    }
+
+    /// Starts a walk at the interrupted code in `snapshot`, reading stack
+    /// slots from its copy of the stack.
+    ///
+    /// `SaveLocation::Memory` addresses still refer to the original stack.
+    pub fn from_snapshot(snapshot: Arc<StackSnapshot>) -> UnwindCursor {
+        UnwindCursor {
+            registers: snapshot.registers().clone(),
+            locations: live_locations(),
+            state: None,
+            interrupted: true,
+            stacks: Stacks::new(),
+            snapshot: Some(snapshot),
+            depth: 0,
+            last_cfa: None,
+            caller_cfa: None,
+        }
+    }
+
+    /// Starts a walk where the cursor that `state` was taken from stands.
+    pub fn from_state(state: CursorState) -> UnwindCursor {
+        UnwindCursor {
+            registers: state.registers,
+            locations: state.locations,
+            state: None,
+            interrupted: state.interrupted,
+            stacks: state.stacks,
+            snapshot: state.snapshot,
+            depth: state.depth,
+            last_cfa: state.last_cfa,
+            caller_cfa: state.last_cfa,
+        }
+    }
+
+    /// The position of the cursor. A cursor rebuilt from it describes the
+    /// current frame again, then continues like this one.
+    pub fn state(&self) -> CursorState {
+        // Once the current frame was described, undo that for the guards.
+        let described = self.state.is_some();
+        CursorState {
+            registers: self.registers.clone(),
+            locations: self.locations,
+            interrupted: self.interrupted,
+            stacks: self.stacks.clone(),
+            snapshot: self.snapshot.clone(),
+            depth: if described { self.depth - 1 } else { self.depth },
+            last_cfa: if described { self.caller_cfa } else { self.last_cfa },
+        }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    /// The stacks the walk may run through. Reads inside them are always
+    /// allowed; other reads are checked against the unwinder's memory map.
+    ///
+    /// A new cursor knows the stack and alternate signal stack of the thread
+    /// creating it.
+    pub fn stacks(&self) -> &Stacks {
+        &self.stacks
+    }
+
+    pub fn stacks_mut(&mut self) -> &mut Stacks {
+        &mut self.stacks
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
         &mut self.registers
     }
-}
 
-impl<'a> FallibleIterator for StackFrames<'a> {
-    type Item = StackFrame;
-    type Error = gimli::Error;
+    /// Where the current frame's value of `reg` is kept.
+    ///
+    /// Writing to a `Memory` location changes the register in this frame
+    /// (and in callers that did not save it again) once control returns here.
+    pub fn save_location(&self, reg: gimli::Register) -> Result<SaveLocation, RegisterError> {
+        registers::check(reg)?;
+        Ok(self.locations[reg.0 as usize])
+    }
 
-    fn next(&mut self) -> Result<Option<StackFrame>, Self::Error> {
+    /// Moves to the caller of the current frame and describes it, or
+    /// returns `None` at the end of the stack.
+    ///
+    /// The first call only describes the frame the cursor was built for.
+    pub fn step(&mut self, unwinder: &DwarfUnwinder) -> Result<Option<StackFrame>, UnwindError> {
         let registers = &mut self.registers;
+        let locations = &mut self.locations;
+        let first = self.state.is_none();
+        let memory = unwinder.memory(self.stacks.regions(), self.snapshot.as_deref());
+        let region = match registers[X86_64::RSP] {
+            Some(sp) => self.stacks.region_of(sp),
+            None => None,
+        };
 
-        if let Some((row, cfa)) = self.state.take() {
+        if let Some((rule, cfa, signal_frame)) = self.state.take() {
+            // Callee-saved registers without a rule still hold the caller's
+            // values, caller-saved ones are only known if a rule restores them.
             let mut newregs = registers.clone();
+            newregs.clobber_caller_saved();
             newregs[X86_64::RA] = None;
-            for &(reg, ref rule) in row.registers() {
-                trace!("rule {:?} {:?}", reg, rule);
-                assert!(reg != X86_64::RSP); // stack = cfa
-                newregs[reg] = match *rule {
-                    RegisterRule::Undefined => unreachable!(), // registers[reg],
-                    RegisterRule::SameValue => Some(registers[reg].unwrap()), // not sure why this exists
-                    RegisterRule::Register(r) => registers[r],
-                    RegisterRule::Offset(n) => Some(unsafe { *((cfa.wrapping_add(n as u64)) as *const u64) }),
-                    RegisterRule::ValOffset(n) => Some(cfa.wrapping_add(n as u64)),
-                    RegisterRule::Expression(_) => unimplemented!(),
-                    RegisterRule::ValExpression(_) => unimplemented!(),
-                    RegisterRule::Architectural => unreachable!(),
-                };
+            let mut newlocs = *locations;
+            for reg in registers::caller_saved().chain(Some(X86_64::RA.0)) {
+                newlocs[reg as usize] = SaveLocation::Undefined;
+            }
+            match rule {
+                UnwindRule::Cfi(row) => {
+                    for &(reg, ref rule) in row.registers() {
+                        trace!("rule {:?} {:?}", reg, rule);
+                        if reg == X86_64::RSP {
+                            continue; // stack = cfa
+                        }
+                        registers::check(reg)?;
+                        newlocs[reg.0 as usize] = match *rule {
+                            RegisterRule::Undefined => SaveLocation::Undefined,
+                            RegisterRule::SameValue => locations[reg.0 as usize],
+                            RegisterRule::Register(r) => {
+                                registers::check(r)?;
+                                locations[r.0 as usize]
+                            }
+                            RegisterRule::Offset(n) => SaveLocation::Memory(cfa.wrapping_add(n as u64)),
+                            _ => SaveLocation::Computed,
+                        };
+                        if Registers::is_vector(reg) {
+                            let value = match *rule {
+                                RegisterRule::Undefined => None,
+                                RegisterRule::SameValue => registers.vector(reg)?,
+                                RegisterRule::Register(r) => registers.vector(r)?,
+                                RegisterRule::Offset(n) => Some(memory.read::<u128>(cfa.wrapping_add(n as u64))?),
+                                _ => return Err(UnwindError::Register(RegisterError::WrongWidth(reg.0))),
+                            };
+                            newregs.set_vector(reg, value)?;
+                            continue;
+                        }
+                        let value = match *rule {
+                            RegisterRule::Undefined => None,
+                            RegisterRule::SameValue => registers.get(reg)?,
+                            RegisterRule::Register(r) => registers.get(r)?,
+                            RegisterRule::Offset(n) => Some(memory.read_u64(cfa.wrapping_add(n as u64))?),
+                            RegisterRule::ValOffset(n) => Some(cfa.wrapping_add(n as u64)),
+                            RegisterRule::Expression(_) | RegisterRule::ValExpression(_) =>
+                                return Err(UnwindError::UnsupportedExpression),
+                            RegisterRule::Architectural => None,
+                        };
+                        newregs.set(reg, value)?;
+                    }
+                }
+                #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+                UnwindRule::SignalContext(uc) => {
+                    // Copy the context (and the FP state it points to) so
+                    // that all reads of it are checked.
+                    let mut ctx: libc::ucontext_t = memory.read(uc)?;
+                    let mut fpstate = None;
+                    if !ctx.uc_mcontext.fpregs.is_null() {
+                        let fp = fpstate.insert(memory.read::<libc::_libc_fpstate>(ctx.uc_mcontext.fpregs as u64)?);
+                        ctx.uc_mcontext.fpregs = fp;
+                    }
+                    newregs = unsafe { Registers::from_ucontext(&ctx) };
+                    for (reg, &slot) in unsafe { registers::ucontext_gregs(uc) }.iter().enumerate() {
+                        newlocs[reg] = SaveLocation::Memory(slot);
+                    }
+                }
+                UnwindRule::FramePointer => {
+                    newregs[X86_64::RBP] = Some(memory.read_u64(cfa.wrapping_sub(16))?);
+                    newregs[X86_64::RA] = Some(memory.read_u64(cfa.wrapping_sub(8))?);
+                    newlocs[X86_64::RBP.0 as usize] = SaveLocation::Memory(cfa.wrapping_sub(16));
+                    newlocs[X86_64::RA.0 as usize] = SaveLocation::Memory(cfa.wrapping_sub(8));
+                }
+                UnwindRule::Heuristic => {
+                    newregs[X86_64::RA] = Some(memory.read_u64(cfa.wrapping_sub(8))?);
+                    newlocs[X86_64::RA.0 as usize] = SaveLocation::Memory(cfa.wrapping_sub(8));
+                }
+                UnwindRule::Compact(row) => {
+                    for reg in row.clobbered() {
+                        newregs[reg] = None;
+                        newlocs[reg as usize] = SaveLocation::Undefined;
+                    }
+                    if let Some(rule) = row.rule() {
+                        if let Some(n) = rule.rbp {
+                            let slot = cfa.wrapping_add(n as u64);
+                            newregs[X86_64::RBP] = Some(memory.read_u64(slot)?);
+                            newlocs[X86_64::RBP.0 as usize] = SaveLocation::Memory(slot);
+                        }
+                        if let Some(n) = rule.return_address {
+                            let slot = cfa.wrapping_add(n as u64);
+                            newregs[X86_64::RA] = Some(memory.read_u64(slot)?);
+                            newlocs[X86_64::RA.0 as usize] = SaveLocation::Memory(slot);
+                        }
+                    }
+                }
             }
             newregs[7] = Some(cfa);
+            newlocs[7] = SaveLocation::Computed;
 
             *registers = newregs;
+            *locations = newlocs;
             trace!("registers:{:?}", registers);
+
+            // Below a signal frame, RA is the interrupted instruction rather
+            // than a return address.
+            self.interrupted = signal_frame;
         }
 
+        // At the outermost frame of a stack with a switch point, or once the
+        // walk left that stack, continue at the frame that switched to it.
+        if let Some(region) = region {
+            let outermost = matches!(registers[X86_64::RA], None | Some(0));
+            let left = registers[X86_64::RSP].is_none_or(|sp| !region.contains(sp));
+            if outermost || left {
+                if let Some(resumer) = self.stacks.take_switch(region) {
+                    debug!("switching from stack 0x{:x} - 0x{:x}", region.start, region.end);
+                    for (reg, location) in locations.iter_mut().enumerate() {
+                        let reg = gimli::Register(reg as u16);
+                        let known = if Registers::is_vector(reg) {
+                            resumer.vector(reg).ok().flatten().is_some()
+                        } else {
+                            resumer.get(reg).ok().flatten().is_some()
+                        };
+                        *location = if known { SaveLocation::Computed } else { SaveLocation::Undefined };
+                    }
+                    *registers = resumer;
+                    self.interrupted = false;
+                }
+            }
+        }
+        let interrupted = self.interrupted;
+        let memory = unwinder.memory(self.stacks.regions(), self.snapshot.as_deref());
+
+        if let Some(return_address) = registers[X86_64::RA] {
+            let guards = &unwinder.guards;
+            if self.depth >= guards.max_depth {
+                return Err(UnwindError::Guard(Guard::MaxDepth));
+            }
 
-        if let Some(mut caller) = registers[X86_64::RA] {
-            caller -= 1; // THIS IS NECESSARY
+            let caller = if interrupted { return_address } else { return_address.wrapping_sub(1) }; // THIS IS NECESSARY
             debug!("caller is 0x{:x}", caller);
 
-            let rec = self.unwinder.cfi.iter().filter(|x| x.er.text.contains(caller)).next().ok_or(gimli::Error::NoUnwindInfoForAddress)?;
+            let rec = unwinder.index.object_for(caller);
+            let compact = match rec {
+                Some(rec) if unwinder.compact_tables => rec.compact_table().lookup(caller)
+                    .and_then(|row| Some((row, row.function()?, row.rule()?))),
+                _ => None,
+            };
+            let info = match rec {
+                _ if compact.is_some() => None,
+                Some(rec) => match rec.unwind_info_for_address(&mut unwinder.ctx.borrow_mut(), caller) {
+                    Ok(info) => Some(info),
+                    Err(gimli::Error::NoUnwindInfoForAddress) => None,
+                    Err(e) => return Err(e.into()),
+                },
+                // Without a module we cannot tell code from garbage.
+                None if guards.return_address => return Err(UnwindError::Guard(Guard::ReturnAddress)),
+                None => None,
+            };
+            let module = rec.map(|rec| rec.module.clone());
 
-            let UnwindInfo { row, personality, lsda, initial_address } = rec.unwind_info_for_address(&mut self.unwinder.ctx, caller)?;
+            let frame = match (compact, info) {
+                (Some((row, function, rule)), _) => {
+                    let cfa = match registers.get(rule.cfa_register)? {
+                        Some(value) => value.wrapping_add(rule.cfa_offset as u64),
+                        None => return Err(UnwindError::UndefinedRegister(rule.cfa_register.0)),
+                    };
+                    trace!("compact: {:?}, cfa is 0x{:x}", rule, cfa);
+                    check_cfa(guards, self.last_cfa, cfa, interrupted, &self.stacks)?;
+                    self.state = Some((UnwindRule::Compact(row.clone()), cfa, false));
 
-            trace!("ok: {:?} (0x{:x} - 0x{:x})", row.cfa(), row.start_address(), row.end_address());
-            let cfa = match *row.cfa() {
-                CfaRule::RegisterAndOffset { register, offset } =>
-                    registers[register].unwrap().wrapping_add(offset as u64),
-                _ => unimplemented!(),
-            };
-            trace!("cfa is 0x{:x}", cfa);
+                    StackFrame {
+                        personality: None,
+                        lsda: None,
+                        initial_address: function.start,
+                        args_size: 0,
+                        return_address,
+                        lookup_address: caller,
+                        cfa,
+                        fde_range: Some(function),
+                        module,
+                        signal_frame: false,
+                        kind: FrameKind::Cfi,
+                    }
+                }
+                (None, Some(UnwindInfo { row, personality, lsda, initial_address, len, signal_frame })) => {
+                    trace!("ok: {:?} (0x{:x} - 0x{:x})", row.cfa(), row.start_address(), row.end_address());
+                    let (rule, cfa) = if signal_frame {
+                        signal_context(registers[X86_64::RSP], row, &memory)?
+                    } else {
+                        let cfa = cfa(&row, |reg| registers.get(reg))?;
+                        (UnwindRule::Cfi(Box::new(row)), cfa)
+                    };
+                    trace!("cfa is 0x{:x}", cfa);
+                    check_cfa(guards, self.last_cfa, cfa, interrupted, &self.stacks)?;
+
+                    let args_size = match rule {
+                        UnwindRule::Cfi(ref row) => row.saved_args_size(),
+                        _ => 0,
+                    };
+                    // Pointers into another process's data cannot be followed.
+                    let resolve = |ptr: Option<Pointer>| match ptr {
+                        Some(Pointer::Indirect(_)) if !unwinder.index.live => Ok(None),
+                        ptr => ptr.map(|x| deref_ptr(x, &memory)).transpose(),
+                    };
+                    let personality = resolve(personality)?;
+                    let lsda = resolve(lsda)?;
+                    self.state = Some((rule, cfa, signal_frame));
+
+                    StackFrame {
+                        personality,
+                        lsda,
+                        initial_address,
+                        args_size,
+                        return_address,
+                        lookup_address: caller,
+                        cfa,
+                        fde_range: Some(AddrRange { start: initial_address, end: initial_address + len }),
+                        module,
+                        signal_frame,
+                        kind: FrameKind::Cfi,
+                    }
+                }
+                (None, None) => {
+                    let (rule, cfa, kind) = fallback(registers[X86_64::RSP], registers[X86_64::RBP], first || interrupted)?;
+                    debug!("no CFI for 0x{:x}, falling back to {:?}", caller, kind);
+                    check_cfa(guards, self.last_cfa, cfa, interrupted, &self.stacks)?;
+                    self.state = Some((rule, cfa, false));
 
-            self.state = Some((row, cfa));
+                    StackFrame {
+                        personality: None,
+                        lsda: None,
+                        initial_address: 0,
+                        args_size: 0,
+                        return_address,
+                        lookup_address: caller,
+                        cfa,
+                        fde_range: None,
+                        module,
+                        signal_frame: false,
+                        kind,
+                    }
+                }
+            };
+            self.depth += 1;
+            self.caller_cfa = self.last_cfa;
+            self.last_cfa = Some(frame.cfa);
 
-            Ok(Some(StackFrame {
-                personality: personality.map(|x| unsafe { deref_ptr(x) }),
-                lsda: lsda.map(|x| unsafe { deref_ptr(x) }),
-                initial_address,
-            }))
+            Ok(Some(frame))
         } else {
             Ok(None)
         }
     }
 }
+
+/// Computes the CFA of a frame described by `row`.
+fn cfa<F>(row: &UnwindTableRow<StaticReader>, get: F) -> Result<u64, UnwindError>
+    where F: Fn(gimli::Register) -> Result<Option<u64>, RegisterError>
+{
+    match *row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } => match get(register)? {
+            Some(value) => Ok(value.wrapping_add(offset as u64)),
+            None => Err(UnwindError::UndefinedRegister(register.0)),
+        },
+        CfaRule::Expression(_) => Err(UnwindError::UnsupportedExpression),
+    }
+}
+
+/// Applies the CFA checks of `guards` to a frame.
+///
+/// The CFA may only drop when the walk moves to another stack: below a
+/// signal frame, which may have run on an alternate stack, or when the two
+/// CFAs are not on the same known stack.
+fn check_cfa(guards: &Guards, previous: Option<u64>, cfa: u64, interrupted: bool, stacks: &Stacks) -> Result<(), UnwindError> {
+    if guards.alignment && cfa & 7 != 0 {
+        return Err(UnwindError::Guard(Guard::Alignment));
+    }
+    if let Some(previous) = previous {
+        let switched = interrupted || stacks.region_of(previous) != stacks.region_of(cfa);
+        if guards.cfa_order && cfa <= previous && !switched {
+            return Err(UnwindError::Guard(Guard::CfaOrder));
+        }
+    }
+    Ok(())
+}
+
+/// Unwinds a signal trampoline (the frame of `__restore_rt`).
+///
+/// Its CFI describes the `ucontext_t` the kernel saved at the stack pointer
+/// with DWARF expressions, so read the `ucontext_t` ourselves instead.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn signal_context(sp: Option<u64>, _row: UnwindTableRow<StaticReader>, memory: &Memory) -> Result<(UnwindRule, u64), UnwindError> {
+    let uc = sp.ok_or(UnwindError::UndefinedRegister(X86_64::RSP.0))?;
+    let cfa = memory.read_u64(unsafe { registers::ucontext_gregs(uc) }[X86_64::RSP.0 as usize])?;
+    Ok((UnwindRule::SignalContext(uc), cfa))
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn signal_context(_sp: Option<u64>, _row: UnwindTableRow<StaticReader>, _memory: &Memory) -> Result<(UnwindRule, u64), UnwindError> {
+    Err(UnwindError::UnsupportedExpression)
+}
+
+/// Picks how to unwind a frame without CFI: by following the frame pointer
+/// if it looks valid, or by assuming a leaf function if `may_be_leaf`.
+fn fallback(sp: Option<u64>, bp: Option<u64>, may_be_leaf: bool) -> Result<(UnwindRule, u64, FrameKind), UnwindError> {
+    let sp = sp.ok_or(gimli::Error::NoUnwindInfoForAddress)?;
+    match bp {
+        Some(bp) if bp > sp && bp % 8 == 0 =>
+            Ok((UnwindRule::FramePointer, bp.wrapping_add(16), FrameKind::FramePointer)),
+        _ if may_be_leaf =>
+            Ok((UnwindRule::Heuristic, sp.wrapping_add(8), FrameKind::Heuristic)),
+        _ => Err(gimli::Error::NoUnwindInfoForAddress.into()),
+    }
+}
+
+impl<'a> FallibleIterator for StackFrames<'a> {
+    type Item = StackFrame;
+    type Error = UnwindError;
+
+    fn next(&mut self) -> Result<Option<StackFrame>, Self::Error> {
+        while let Some(frame) = self.step()? {
+            if let Some(wrapper) = self.hide_internal {
+                if is_internal_frame(frame.fde_range, wrapper) {
+                    continue;
+                }
+            }
+            self.hide_internal = None;
+
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+            return Ok(Some(frame));
+        }
+        Ok(None)
+    }
+}
@@ -4,8 +4,12 @@ use libc::{c_void, c_int};
 use fallible_iterator::FallibleIterator;
 use gimli::X86_64;
 
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
+use core::cell::RefCell;
+
 use registers::Registers;
-use super::{DwarfUnwinder, Unwinder, StackFrames};
+use super::{DwarfUnwinder, ModuleIndex, Unwinder, StackFrames};
 
 #[repr(C)]
 #[derive(Copy, Clone, PartialEq)]
@@ -38,8 +42,10 @@ pub type _Unwind_Exception_Cleanup_Fn = extern "C" fn(unwind_code: _Unwind_Reaso
 pub struct _Unwind_Exception {
     pub exception_class: _Unwind_Exception_Class,
     pub exception_cleanup: _Unwind_Exception_Cleanup_Fn,
-    pub private_contptr: Option<u64>,
-    //pub private: [_Unwind_Word; 20],
+    // Like libgcc, we use these to hold the stop function and its argument
+    // during a forced unwind. Both are zero for ordinary exceptions.
+    pub private_1: _Unwind_Word,
+    pub private_2: _Unwind_Word,
 }
 
 pub type _Unwind_Word = usize;
@@ -48,18 +54,48 @@ pub struct _Unwind_Context {
     pub lsda: u64,
     pub ip: u64,
     pub initial_address: u64,
+    pub args_size: u64,
+    pub cfa: u64,
     pub registers: *mut Registers,
 }
-pub type _Unwind_Trace_Fn = extern "C" fn(ctx: *mut _Unwind_Context, arg: *mut c_void)
+pub type _Unwind_Trace_Fn = extern "C-unwind" fn(ctx: *mut _Unwind_Context, arg: *mut c_void)
                                           -> _Unwind_Reason_Code;
+pub type _Unwind_Stop_Fn = extern "C" fn(version: c_int, actions: c_int, class: _Unwind_Exception_Class,
+                                         exception: *mut _Unwind_Exception, context: *mut _Unwind_Context,
+                                         stop_argument: *mut c_void) -> _Unwind_Reason_Code;
+
+// The index the exception being unwound on this thread was raised with.
+// `ModuleIndex::global()` asks the dynamic loader whether objects were
+// loaded, under its lock, and `_Unwind_Resume` runs after every cleanup;
+// the frames still to unwind were all loaded when the exception was raised.
+#[cfg(feature = "std")]
+std::thread_local!(static RAISED_WITH: RefCell<Option<Arc<ModuleIndex>>> = const { RefCell::new(None) });
+
+fn raise_index() -> Arc<ModuleIndex> {
+    let index = ModuleIndex::global();
+    #[cfg(feature = "std")]
+    let _ = RAISED_WITH.try_with(|raised| *raised.borrow_mut() = Some(index.clone()));
+    index
+}
+
+fn resume_index() -> Arc<ModuleIndex> {
+    #[cfg(feature = "std")]
+    {
+        if let Ok(Some(index)) = RAISED_WITH.try_with(|raised| raised.borrow().clone()) {
+            return index;
+        }
+    }
+    ModuleIndex::global()
+}
+
 type PersonalityRoutine = extern "C" fn(version: c_int, actions: c_int, class: u64, object: *mut _Unwind_Exception, context: *mut _Unwind_Context) -> _Unwind_Reason_Code;
 
 // FIXME: we skip over this function when unwinding, so we should ensure
 // it never needs any cleanup. Currently this is not true.
 #[no_mangle]
-pub unsafe extern "C" fn _Unwind_Resume(exception: *mut _Unwind_Exception) -> ! {
+pub unsafe extern "C-unwind" fn _Unwind_Resume(exception: *mut _Unwind_Exception) -> ! {
     ::glue::registers(|registers| {
-        if let Some(registers) = unwind_tracer(registers, exception) {
+        if let Ok(registers) = unwind_tracer(resume_index(), registers, exception, true) {
             ::glue::land(&registers);
         }
     });
@@ -94,12 +130,16 @@ pub unsafe extern "C" fn _Unwind_GetLanguageSpecificData(ctx: *mut _Unwind_Conte
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_SetGR(ctx: *mut _Unwind_Context, reg_index: c_int, value: _Unwind_Word) {
-    (*(*ctx).registers)[reg_index as u16] = Some(value as u64);
+    let registers = &mut *(*ctx).registers;
+    if let Err(e) = registers.set(gimli::Register(reg_index as u16), Some(value as u64)) {
+        error!("_Unwind_SetGR: {}", e);
+    }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_SetIP(ctx: *mut _Unwind_Context, value: _Unwind_Word) {
-    (*(*ctx).registers)[X86_64::RA] = Some(value as u64);
+    let registers = &mut *(*ctx).registers;
+    registers[X86_64::RA] = Some(value as u64);
 }
 
 #[no_mangle]
@@ -109,81 +149,152 @@ pub unsafe extern "C" fn _Unwind_GetIPInfo(ctx: *mut _Unwind_Context, ip_before_
     (*ctx).ip as usize
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetIP(ctx: *mut _Unwind_Context) -> _Unwind_Word {
+    (*ctx).ip as usize
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetCFA(ctx: *mut _Unwind_Context) -> _Unwind_Word {
+    (*ctx).cfa as usize
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_FindEnclosingFunction(pc: *mut c_void) -> *mut c_void {
     pc // FIXME: implement this
 }
 
-// FIXME: Set `unwind(allowed)` because we need to be able to unwind this function as
-// part of its operation. But this means any panics in this function are undefined
-// behaviour, and we don't currently ensure it doesn't panic.
-//
-// On stable (1.32), `unwind(allowed)` is the default, but this will change in 1.33, with
-// no stable way of setting `unwind(allowed)`, so this function will always abort in 1.33.
-#[cfg_attr(feature = "nightly", unwind(allowed))]
+// The entry points that start or continue unwinding use the `C-unwind` ABI:
+// personality routines walk over (and may land in) the frames of their callers,
+// and with plain `extern "C"` rustc would treat those frames as abort points.
 #[no_mangle]
-pub unsafe extern "C" fn _Unwind_RaiseException(exception: *mut _Unwind_Exception) -> _Unwind_Reason_Code {
-    (*exception).private_contptr = None;
+pub unsafe extern "C-unwind" fn _Unwind_RaiseException(exception: *mut _Unwind_Exception) -> _Unwind_Reason_Code {
+    (*exception).private_1 = 0;
+    (*exception).private_2 = 0;
+    let mut reason = _Unwind_Reason_Code::_URC_END_OF_STACK;
     ::glue::registers(|registers| {
-        if let Some(registers) = unwind_tracer(registers, exception) {
-            ::glue::land(&registers);
+        match unwind_tracer(raise_index(), registers, exception, false) {
+            Ok(registers) => ::glue::land(&registers),
+            Err(code) => reason = code,
         }
     });
-    unreachable!();
+    reason
 }
 
-unsafe fn unwind_tracer(registers: Registers, exception: *mut _Unwind_Exception) -> Option<Registers> {
-    let mut unwinder = DwarfUnwinder::default();
+#[no_mangle]
+pub unsafe extern "C-unwind" fn _Unwind_ForcedUnwind(exception: *mut _Unwind_Exception, stop: _Unwind_Stop_Fn,
+                                              stop_argument: *mut c_void) -> _Unwind_Reason_Code {
+    (*exception).private_1 = stop as _Unwind_Word;
+    (*exception).private_2 = stop_argument as _Unwind_Word;
+    let mut reason = _Unwind_Reason_Code::_URC_END_OF_STACK;
+    ::glue::registers(|registers| {
+        match unwind_tracer(raise_index(), registers, exception, false) {
+            Ok(registers) => ::glue::land(&registers),
+            Err(code) => reason = code,
+        }
+    });
+    reason
+}
+
+unsafe fn unwind_tracer(index: Arc<ModuleIndex>, registers: Registers, exception: *mut _Unwind_Exception,
+                        resuming: bool) -> Result<Registers, _Unwind_Reason_Code> {
+    let mut unwinder = DwarfUnwinder::new(index);
     let mut frames = StackFrames::new(&mut unwinder, registers);
 
-    if let Some(contptr) = (*exception).private_contptr {
+    if resuming {
+        // Skip our own frames up to `_Unwind_Resume`, and then the frame whose
+        // landing pad called it: its cleanup has already run.
+        let resume = _Unwind_Resume as *const () as u64;
         loop {
-            if let Some(frame) = frames.next().unwrap() {
-                if frames.registers()[X86_64::RSP].unwrap() == contptr {
-                    break;
-                }
-            } else {
-                return None;
+            match frames.next().unwrap() {
+                Some(ref frame) if frame.initial_address == resume => break,
+                Some(_) => (),
+                None => return Err(_Unwind_Reason_Code::_URC_END_OF_STACK),
             }
         }
+        if frames.next().unwrap().is_none() {
+            return Err(_Unwind_Reason_Code::_URC_END_OF_STACK);
+        }
     }
 
+    let stop: Option<_Unwind_Stop_Fn> = match (*exception).private_1 {
+        0 => None,
+        stop => Some(::core::mem::transmute::<_Unwind_Word, _Unwind_Stop_Fn>(stop)),
+    };
+    let stop_argument = (*exception).private_2 as *mut c_void;
+    let actions = match stop {
+        // ABI specifies that phase 1 is optional, so we just run phase 2 (CLEANUP_PHASE)
+        None => _Unwind_Action::_UA_CLEANUP_PHASE as c_int,
+        Some(_) => _Unwind_Action::_UA_CLEANUP_PHASE as c_int | _Unwind_Action::_UA_FORCE_UNWIND as c_int,
+    };
+
     while let Some(frame) = frames.next().unwrap() {
-        if let Some(personality) = frame.personality {
-            trace!("HAS PERSONALITY");
-            let personality: PersonalityRoutine = ::std::mem::transmute(personality);
+        let mut ctx = _Unwind_Context {
+            lsda: frame.lsda.unwrap_or(0),
+            ip: frame.return_address,
+            initial_address: frame.initial_address,
+            args_size: frame.args_size,
+            cfa: frame.cfa,
+            registers: frames.registers(),
+        };
 
-            let mut ctx = _Unwind_Context {
-                lsda: frame.lsda.unwrap(),
-                ip: frames.registers()[X86_64::RA].unwrap(),
-                initial_address: frame.initial_address,
-                registers: frames.registers(),
-            };
+        if let Some(stop) = stop {
+            match stop(1, actions, (*exception).exception_class, exception, &mut ctx, stop_argument) {
+                _Unwind_Reason_Code::_URC_NO_REASON => (),
+                // Not part of the ABI: our own stop function asks to land at
+                // the registers it put into the context, once the unwinder
+                // is dropped.
+                _Unwind_Reason_Code::_URC_INSTALL_CONTEXT => return Ok(frames.registers().clone()),
+                _ => return Err(_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR),
+            }
+        }
 
-            (*exception).private_contptr = frames.registers()[X86_64::RSP];
+        if let Some(personality) = frame.personality {
+            trace!("HAS PERSONALITY");
+            let personality: PersonalityRoutine = ::core::mem::transmute(personality);
 
-            // ABI specifies that phase 1 is optional, so we just run phase 2 (CLEANUP_PHASE)
-            match personality(1, _Unwind_Action::_UA_CLEANUP_PHASE as c_int, (*exception).exception_class,
-                              exception, &mut ctx) {
+            match personality(1, actions, (*exception).exception_class, exception, &mut ctx) {
                 _Unwind_Reason_Code::_URC_CONTINUE_UNWIND => (),
-                _Unwind_Reason_Code::_URC_INSTALL_CONTEXT => return Some(frames.registers),
+                _Unwind_Reason_Code::_URC_INSTALL_CONTEXT => {
+                    // Like libgcc, pop the outgoing arguments that were pushed
+                    // for the call (DW_CFA_GNU_args_size): the landing pad
+                    // expects them to be gone.
+                    let mut registers = frames.registers().clone();
+                    registers[X86_64::RSP] = registers[X86_64::RSP].map(|sp| sp + ctx.args_size);
+                    return Ok(registers);
+                }
                 x => panic!("wtf reason code {:?}", x),
             }
         }
     }
-    None
+
+    if let Some(stop) = stop {
+        let mut ctx = _Unwind_Context {
+            lsda: 0,
+            ip: 0,
+            initial_address: 0,
+            args_size: 0,
+            cfa: 0,
+            registers: frames.registers(),
+        };
+        stop(1, actions | _Unwind_Action::_UA_END_OF_STACK as c_int, (*exception).exception_class,
+             exception, &mut ctx, stop_argument);
+    }
+    Err(_Unwind_Reason_Code::_URC_END_OF_STACK)
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn _Unwind_Backtrace(trace: _Unwind_Trace_Fn,
+pub unsafe extern "C-unwind" fn _Unwind_Backtrace(trace: _Unwind_Trace_Fn,
                                     trace_argument: *mut c_void)
                                            -> _Unwind_Reason_Code {
-    DwarfUnwinder::default().trace(|frames| {
+    DwarfUnwinder::new(ModuleIndex::global()).trace(|frames| {
         while let Some(frame) = frames.next().unwrap() {
             let mut ctx = _Unwind_Context {
                 lsda: frame.lsda.unwrap_or(0),
-                ip: frames.registers()[X86_64::RA].unwrap(),
+                ip: frame.return_address,
                 initial_address: frame.initial_address,
+                args_size: frame.args_size,
+                cfa: frame.cfa,
                 registers: frames.registers(),
             };
 
@@ -0,0 +1,162 @@
+// Parser for the GCC-style LSDA (`.gcc_except_table`) that rustc and g++ emit.
+//
+// Layout, as documented in the Itanium C++ ABI and libgcc's unwind-c.c:
+//
+//   lpstart encoding (u8), lpstart (encoded, if not omitted)
+//   ttype encoding (u8), ttype offset (uleb128, if not omitted)
+//   call site encoding (u8), call site table length (uleb128)
+//   call site records: start, length, landing pad (encoded), action (uleb128)
+//   action records: type filter (sleb128), next action offset (sleb128)
+
+use core::{mem, ptr};
+use gimli::{self, constants};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EHAction {
+    None,
+    Cleanup(u64),
+    Catch(u64),
+    Terminate,
+}
+
+struct LsdaReader {
+    ptr: *const u8,
+}
+
+impl LsdaReader {
+    unsafe fn read<T: Copy>(&mut self) -> T {
+        let value = ptr::read_unaligned(self.ptr as *const T);
+        self.ptr = self.ptr.add(mem::size_of::<T>());
+        value
+    }
+
+    unsafe fn read_uleb128(&mut self) -> u64 {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let byte: u8 = self.read();
+            if shift < 64 {
+                result |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return result;
+            }
+        }
+    }
+
+    unsafe fn read_sleb128(&mut self) -> i64 {
+        let mut result = 0;
+        let mut shift = 0;
+        let mut byte: u8;
+        loop {
+            byte = self.read();
+            if shift < 64 {
+                result |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        if shift < 64 && byte & 0x40 != 0 {
+            result |= -1 << shift;
+        }
+        result
+    }
+
+    unsafe fn read_encoded_pointer(&mut self, encoding: constants::DwEhPe, func_start: u64) -> gimli::Result<u64> {
+        if encoding == constants::DW_EH_PE_omit {
+            return Err(gimli::Error::CannotParseOmitPointerEncoding);
+        }
+
+        let here = self.ptr as u64;
+        let value = match encoding.format() {
+            constants::DW_EH_PE_absptr => self.read::<u64>(),
+            constants::DW_EH_PE_uleb128 => self.read_uleb128(),
+            constants::DW_EH_PE_udata2 => u64::from(self.read::<u16>()),
+            constants::DW_EH_PE_udata4 => u64::from(self.read::<u32>()),
+            constants::DW_EH_PE_udata8 => self.read::<u64>(),
+            constants::DW_EH_PE_sleb128 => self.read_sleb128() as u64,
+            constants::DW_EH_PE_sdata2 => i64::from(self.read::<i16>()) as u64,
+            constants::DW_EH_PE_sdata4 => i64::from(self.read::<i32>()) as u64,
+            constants::DW_EH_PE_sdata8 => self.read::<i64>() as u64,
+            _ => return Err(gimli::Error::UnknownPointerEncoding),
+        };
+
+        let value = match encoding.application() {
+            constants::DW_EH_PE_absptr => value,
+            constants::DW_EH_PE_pcrel => here.wrapping_add(value),
+            constants::DW_EH_PE_funcrel => func_start.wrapping_add(value),
+            _ => return Err(gimli::Error::UnsupportedPointerEncoding),
+        };
+
+        if encoding.is_indirect() {
+            Ok(*(value as *const u64))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+/// Looks up the call site containing `ip` in the LSDA at `lsda`.
+///
+/// `ip` must already point into the call instruction (i.e. return address - 1).
+pub unsafe fn find_eh_action(lsda: u64, ip: u64, func_start: u64) -> gimli::Result<EHAction> {
+    if lsda == 0 {
+        return Ok(EHAction::None);
+    }
+
+    let mut reader = LsdaReader { ptr: lsda as *const u8 };
+
+    let lpstart_encoding = constants::DwEhPe(reader.read());
+    let lpad_base = if lpstart_encoding != constants::DW_EH_PE_omit {
+        reader.read_encoded_pointer(lpstart_encoding, func_start)?
+    } else {
+        func_start
+    };
+
+    let ttype_encoding = constants::DwEhPe(reader.read());
+    if ttype_encoding != constants::DW_EH_PE_omit {
+        // We only care about the type filter being zero or not, so the
+        // type table itself is never consulted.
+        reader.read_uleb128();
+    }
+
+    let call_site_encoding = constants::DwEhPe(reader.read());
+    let call_site_table_length = reader.read_uleb128();
+    let action_table = reader.ptr.add(call_site_table_length as usize);
+
+    while reader.ptr < action_table {
+        let cs_start = reader.read_encoded_pointer(call_site_encoding, 0)?;
+        let cs_len = reader.read_encoded_pointer(call_site_encoding, 0)?;
+        let cs_lpad = reader.read_encoded_pointer(call_site_encoding, 0)?;
+        let cs_action = reader.read_uleb128();
+
+        // The call site table is sorted by start address.
+        if ip < func_start.wrapping_add(cs_start) {
+            break;
+        }
+        if ip < func_start.wrapping_add(cs_start).wrapping_add(cs_len) {
+            if cs_lpad == 0 {
+                return Ok(EHAction::None);
+            }
+
+            let lpad = lpad_base.wrapping_add(cs_lpad);
+            if cs_action == 0 {
+                return Ok(EHAction::Cleanup(lpad));
+            }
+
+            let mut action = LsdaReader { ptr: action_table.add(cs_action as usize - 1) };
+            let ttype_filter = action.read_sleb128();
+            return Ok(if ttype_filter == 0 {
+                EHAction::Cleanup(lpad)
+            } else {
+                EHAction::Catch(lpad)
+            });
+        }
+    }
+
+    // An ip that is not covered by the call site table must not throw.
+    Ok(EHAction::Terminate)
+}
@@ -0,0 +1,157 @@
+//! Checked memory reads.
+//!
+//! Unwinding a corrupt stack must not crash the unwinder, so every read of
+//! a stack slot or pointer goes through `Memory`. It accepts addresses in
+//! the stack being walked and in the readable mappings recorded by a
+//! `MemoryMap`. Anything else either fails with `UnwindError::InvalidRead`,
+//! or, if enabled, is read with `process_vm_readv` on our own process,
+//! which reports bad addresses instead of faulting.
+//!
+//! When unwinding a `StackSnapshot`, stack slots are read from the copy
+//! instead, and only pointers into the modules are read live.
+
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+use alloc::vec::Vec;
+use range::AddrRange;
+use error::UnwindError;
+
+/// A snapshot of the readable mappings of the process.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMap {
+    ranges: Vec<AddrRange>,
+}
+
+impl MemoryMap {
+    /// Builds a map from (possibly overlapping) readable ranges.
+    pub fn new(mut ranges: Vec<AddrRange>) -> MemoryMap {
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<AddrRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        MemoryMap { ranges: merged }
+    }
+
+    /// Reads the readable mappings from `/proc/self/maps`.
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    pub fn snapshot() -> Option<MemoryMap> {
+        let maps = ::std::fs::read_to_string("/proc/self/maps").ok()?;
+        let ranges = maps.lines().filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mut range = fields.next()?.split('-');
+            let perms = fields.next()?;
+            // vvar is readable on paper, but some of its pages fault.
+            if !perms.starts_with('r') || line.ends_with("[vvar]") || line.ends_with("[vvar_vclock]") {
+                return None;
+            }
+            let start = u64::from_str_radix(range.next()?, 16).ok()?;
+            let end = u64::from_str_radix(range.next()?, 16).ok()?;
+            Some(AddrRange { start, end })
+        }).collect();
+        Some(MemoryMap::new(ranges))
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "std")))]
+    pub fn snapshot() -> Option<MemoryMap> {
+        None
+    }
+
+    /// Whether all of `start..start + len` was readable.
+    pub fn contains(&self, start: u64, len: u64) -> bool {
+        let end = match start.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        let i = match self.ranges.binary_search_by_key(&start, |range| range.start) {
+            Ok(i) => i,
+            Err(0) => return false,
+            Err(i) => i - 1,
+        };
+        end <= self.ranges[i].end
+    }
+}
+
+/// Where the unwinder may read from.
+///
+/// Without stacks and a map there is nothing to check against, and reads
+/// are not checked at all.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Memory<'a> {
+    pub stacks: &'a [AddrRange],
+    /// The start address and contents of a stack copy.
+    pub snapshot: Option<(u64, &'a [u8])>,
+    pub map: Option<&'a MemoryMap>,
+    pub process_vm_readv: bool,
+}
+
+impl<'a> Memory<'a> {
+    fn readable(&self, addr: u64, len: u64) -> bool {
+        let end = match addr.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        if self.stacks.iter().any(|stack| addr >= stack.start && end <= stack.end) {
+            return true;
+        }
+        match self.map {
+            Some(map) => map.contains(addr, len),
+            None => self.stacks.is_empty() && !self.process_vm_readv,
+        }
+    }
+
+    /// Reads a stack slot.
+    pub fn read<T: Copy>(&self, addr: u64) -> Result<T, UnwindError> {
+        match self.snapshot {
+            Some((base, bytes)) => {
+                let offset = addr.wrapping_sub(base) as usize;
+                match offset.checked_add(mem::size_of::<T>()) {
+                    Some(end) if addr >= base && end <= bytes.len() =>
+                        Ok(unsafe { ptr::read_unaligned(bytes[offset..].as_ptr() as *const T) }),
+                    _ => Err(UnwindError::InvalidRead(addr)),
+                }
+            }
+            None => self.read_live(addr),
+        }
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Result<u64, UnwindError> {
+        self.read(addr)
+    }
+
+    /// Reads a pointer from a module's data, never from a snapshot.
+    pub fn read_data_u64(&self, addr: u64) -> Result<u64, UnwindError> {
+        self.read_live(addr)
+    }
+
+    fn read_live<T: Copy>(&self, addr: u64) -> Result<T, UnwindError> {
+        let len = mem::size_of::<T>();
+        if self.readable(addr, len as u64) {
+            return Ok(unsafe { ptr::read_unaligned(addr as *const T) });
+        }
+        if self.process_vm_readv {
+            let mut value = MaybeUninit::<T>::uninit();
+            if read_via_syscall(addr, value.as_mut_ptr() as *mut u8, len) {
+                return Ok(unsafe { value.assume_init() });
+            }
+        }
+        Err(UnwindError::InvalidRead(addr))
+    }
+}
+
+/// Copies memory of our own process, failing instead of faulting on bad
+/// addresses. This is async-signal-safe.
+#[cfg(target_os = "linux")]
+pub(crate) fn read_via_syscall(addr: u64, buf: *mut u8, len: usize) -> bool {
+    let local = ::libc::iovec { iov_base: buf as *mut ::libc::c_void, iov_len: len };
+    let remote = ::libc::iovec { iov_base: addr as *mut ::libc::c_void, iov_len: len };
+    unsafe { ::libc::process_vm_readv(::libc::getpid(), &local, 1, &remote, 1, 0) == len as isize }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read_via_syscall(_addr: u64, _buf: *mut u8, _len: usize) -> bool {
+    false
+}
@@ -0,0 +1,243 @@
+//! A panic runtime for `no_std` targets, built on the libunwind shim.
+//!
+//! `rust_eh_personality` understands the LSDA that rustc emits, so a
+//! `no_std` crate compiled with `panic=unwind` can forward its
+//! `eh_personality` lang item to it:
+//!
+//! ```ignore
+//! #[lang = "eh_personality"]
+//! unsafe extern "C" fn eh_personality(version: c_int, actions: c_int, class: u64,
+//!                                     exception: *mut _Unwind_Exception,
+//!                                     context: *mut _Unwind_Context) -> _Unwind_Reason_Code {
+//!     unwind::panic_runtime::rust_eh_personality(version, actions, class, exception, context)
+//! }
+//!
+//! #[panic_handler]
+//! fn panic(info: &PanicInfo) -> ! {
+//!     unwind::panic_runtime::begin_panic(Box::new(format!("{}", info)))
+//! }
+//! ```
+//!
+//! Panics raised with `begin_panic` are caught by the innermost enclosing
+//! `catch_unwind`. Without the `try` intrinsic we cannot emit a catching
+//! landing pad, so `catch_unwind` records its registers instead, and
+//! `begin_panic` runs a forced unwind that stops and lands there once all
+//! cleanups in between have run. If there is no enclosing `catch_unwind`,
+//! the exception is raised normally and the runtime aborts when nobody
+//! handles it.
+//!
+//! Without `std` this is single-core only, see `CatchState`.
+
+use core::any::Any;
+use core::ptr;
+use alloc::boxed::Box;
+use libc::{c_int, c_void};
+use gimli::X86_64;
+
+use glue;
+use registers::Registers;
+use lsda::{self, EHAction};
+use libunwind_shim::*;
+
+// "UNWDRUST". Distinct from libstd's "MOZ\0RUST" so that libstd's
+// `catch_unwind` never mistakes our exceptions for its own.
+pub const RUST_EXCEPTION_CLASS: _Unwind_Exception_Class = 0x554e_5744_5255_5354;
+
+#[repr(C)]
+struct Exception {
+    header: _Unwind_Exception,
+    payload: Box<dyn Any + Send>,
+}
+
+struct CatchFrame {
+    registers: Registers,
+    payload: Option<Box<dyn Any + Send>>,
+    prev: *mut CatchFrame,
+}
+
+#[cfg(feature = "std")]
+std::thread_local!(static CATCH_TOP: ::core::cell::Cell<*mut CatchFrame> = ::core::cell::Cell::new(ptr::null_mut()));
+
+#[cfg(feature = "std")]
+fn catch_top() -> *mut CatchFrame {
+    CATCH_TOP.with(|top| top.get())
+}
+
+#[cfg(feature = "std")]
+fn set_catch_top(frame: *mut CatchFrame) {
+    CATCH_TOP.with(|top| top.set(frame))
+}
+
+#[cfg(not(feature = "std"))]
+static CATCH_TOP: ::core::sync::atomic::AtomicPtr<CatchFrame> = ::core::sync::atomic::AtomicPtr::new(ptr::null_mut());
+
+#[cfg(not(feature = "std"))]
+fn catch_top() -> *mut CatchFrame {
+    CATCH_TOP.load(::core::sync::atomic::Ordering::Relaxed)
+}
+
+#[cfg(not(feature = "std"))]
+fn set_catch_top(frame: *mut CatchFrame) {
+    CATCH_TOP.store(frame, ::core::sync::atomic::Ordering::Relaxed)
+}
+
+/// The chain of active `catch_unwind` calls of one task.
+///
+/// Without `std` there are no thread locals, so the chain is a single
+/// global shared by every CPU. Only one CPU may run code that uses
+/// `catch_unwind` or `begin_panic`, and a scheduler that switches between
+/// tasks that may panic has to swap the chain out on every switch.
+#[cfg(not(feature = "std"))]
+pub struct CatchState(*mut CatchFrame);
+
+#[cfg(not(feature = "std"))]
+impl Default for CatchState {
+    fn default() -> CatchState {
+        CatchState(ptr::null_mut())
+    }
+}
+
+/// Installs `state` as the current task's catch chain and returns the previous one.
+#[cfg(not(feature = "std"))]
+pub unsafe fn swap_catch_state(state: CatchState) -> CatchState {
+    let prev = catch_top();
+    set_catch_top(state.0);
+    CatchState(prev)
+}
+
+fn abort() -> ! {
+    #[cfg(feature = "std")]
+    ::std::process::abort();
+    #[cfg(not(feature = "std"))]
+    loop {
+        ::core::hint::spin_loop();
+    }
+}
+
+extern "C" fn exception_cleanup(_unwind_code: _Unwind_Reason_Code, exception: *mut _Unwind_Exception) {
+    unsafe { drop(Box::from_raw(exception as *mut Exception)) };
+}
+
+/// Starts unwinding with `payload`, which is handed to the innermost `catch_unwind`.
+pub fn begin_panic(payload: Box<dyn Any + Send>) -> ! {
+    let exception = Box::into_raw(Box::new(Exception {
+        header: _Unwind_Exception {
+            exception_class: RUST_EXCEPTION_CLASS,
+            exception_cleanup,
+            private_1: 0,
+            private_2: 0,
+        },
+        payload,
+    })) as *mut _Unwind_Exception;
+
+    unsafe {
+        let frame = catch_top();
+        let code = if frame.is_null() {
+            _Unwind_RaiseException(exception)
+        } else {
+            _Unwind_ForcedUnwind(exception, catch_stop, frame as *mut c_void)
+        };
+        error!("failed to unwind: {:?}", code);
+    }
+    abort()
+}
+
+extern "C" fn catch_stop(_version: c_int, actions: c_int, _class: _Unwind_Exception_Class,
+                         exception: *mut _Unwind_Exception, context: *mut _Unwind_Context,
+                         stop_argument: *mut c_void) -> _Unwind_Reason_Code {
+    if actions & _Unwind_Action::_UA_END_OF_STACK as c_int != 0 {
+        return _Unwind_Reason_Code::_URC_END_OF_STACK;
+    }
+
+    unsafe {
+        let frame = &mut *(stop_argument as *mut CatchFrame);
+        let registers = &*(*context).registers;
+
+        // The catch frame is the first one whose stack pointer reaches the
+        // one recorded by `catch_unwind`.
+        match (registers[X86_64::RSP], frame.registers[X86_64::RSP]) {
+            (Some(sp), Some(catch_sp)) if sp >= catch_sp => (),
+            _ => return _Unwind_Reason_Code::_URC_NO_REASON,
+        }
+
+        let exception = Box::from_raw(exception as *mut Exception);
+        frame.payload = Some(exception.payload);
+        set_catch_top(frame.prev);
+        // Landing from here would skip the destructors of the unwinder that
+        // called us, so the shim lands at these registers after returning.
+        *(*context).registers = frame.registers.clone();
+    }
+    _Unwind_Reason_Code::_URC_INSTALL_CONTEXT
+}
+
+/// Invokes `f`, catching panics raised with `begin_panic`.
+pub fn catch_unwind<F: FnOnce() -> R, R>(f: F) -> Result<R, Box<dyn Any + Send>> {
+    let mut f = Some(f);
+    let mut result = None;
+    let mut frame = CatchFrame {
+        registers: Registers::default(),
+        payload: None,
+        prev: catch_top(),
+    };
+
+    // If `f` panics, the unwind lands right after the trampoline call in
+    // `glue::registers`, so this returns a second time with `result` unset.
+    glue::registers(|registers| {
+        frame.registers = registers;
+        set_catch_top(&mut frame);
+        result = Some((f.take().unwrap())());
+        set_catch_top(frame.prev);
+    });
+
+    match result {
+        Some(result) => Ok(result),
+        None => Err(frame.payload.take().unwrap()),
+    }
+}
+
+/// A personality routine compatible with the LSDA emitted by rustc.
+pub unsafe extern "C" fn rust_eh_personality(version: c_int, actions: c_int, _class: _Unwind_Exception_Class,
+                                             exception: *mut _Unwind_Exception,
+                                             context: *mut _Unwind_Context) -> _Unwind_Reason_Code {
+    if version != 1 {
+        return _Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR;
+    }
+
+    let lsda = _Unwind_GetLanguageSpecificData(context) as u64;
+    let mut ip_before_insn = 0;
+    let mut ip = _Unwind_GetIPInfo(context, &mut ip_before_insn) as u64;
+    if ip_before_insn == 0 {
+        ip -= 1;
+    }
+    let func_start = _Unwind_GetRegionStart(context) as u64;
+
+    let action = match lsda::find_eh_action(lsda, ip, func_start) {
+        Ok(action) => action,
+        Err(e) => {
+            error!("bad LSDA at 0x{:x}: {}", lsda, e);
+            return _Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR;
+        }
+    };
+    trace!("personality: 0x{:x} -> {:?}", ip, action);
+
+    if actions & _Unwind_Action::_UA_SEARCH_PHASE as c_int != 0 {
+        match action {
+            EHAction::None | EHAction::Cleanup(_) => _Unwind_Reason_Code::_URC_CONTINUE_UNWIND,
+            EHAction::Catch(_) => _Unwind_Reason_Code::_URC_HANDLER_FOUND,
+            EHAction::Terminate => _Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR,
+        }
+    } else {
+        let forced = actions & _Unwind_Action::_UA_FORCE_UNWIND as c_int != 0;
+        match action {
+            EHAction::None => _Unwind_Reason_Code::_URC_CONTINUE_UNWIND,
+            EHAction::Catch(_) if forced => _Unwind_Reason_Code::_URC_CONTINUE_UNWIND,
+            EHAction::Cleanup(lpad) | EHAction::Catch(lpad) => {
+                _Unwind_SetGR(context, X86_64::RAX.0 as c_int, exception as _Unwind_Word);
+                _Unwind_SetGR(context, X86_64::RDX.0 as c_int, 0);
+                _Unwind_SetIP(context, lpad as _Unwind_Word);
+                _Unwind_Reason_Code::_URC_INSTALL_CONTEXT
+            }
+            EHAction::Terminate => _Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+        }
+    }
+}
@@ -0,0 +1,542 @@
+//! Unwinding the samples of a `perf.data` file offline.
+//!
+//! Samples recorded with `perf record --call-graph dwarf` carry the user
+//! registers and a copy of the top of the user stack. The `MMAP2` records
+//! say where each object file was mapped, so the samples can be unwound
+//! long after the process exited: each one becomes a `StackSnapshot`,
+//! walked against a `ModuleIndex::from_files` of the mappings its process
+//! had at that time.
+//!
+//! Only files written on a little-endian x86-64 machine are supported, and
+//! only the user-space part of each stack is unwound.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::string::String;
+use std::sync::Arc;
+use std::vec::Vec;
+use std::borrow::ToOwned;
+use std::boxed::Box;
+
+use gimli::X86_64;
+use flamegraph::{self, Flamegraph};
+use registers::{self, Registers};
+use {AddrRange, DwarfUnwinder, FileMapping, Module, ModuleIndex, StackSnapshot, UnwindCursor};
+
+const MAGIC: &[u8; 8] = b"PERFILE2";
+
+const PERF_RECORD_MMAP: u32 = 1;
+const PERF_RECORD_COMM: u32 = 3;
+const PERF_RECORD_FORK: u32 = 7;
+const PERF_RECORD_SAMPLE: u32 = 9;
+const PERF_RECORD_MMAP2: u32 = 10;
+
+const PERF_RECORD_MISC_MMAP_DATA: u16 = 1 << 13;
+const PERF_RECORD_MISC_COMM_EXEC: u16 = 1 << 13;
+
+const PERF_SAMPLE_IP: u64 = 1 << 0;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_ADDR: u64 = 1 << 3;
+const PERF_SAMPLE_READ: u64 = 1 << 4;
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+const PERF_SAMPLE_ID: u64 = 1 << 6;
+const PERF_SAMPLE_CPU: u64 = 1 << 7;
+const PERF_SAMPLE_PERIOD: u64 = 1 << 8;
+const PERF_SAMPLE_STREAM_ID: u64 = 1 << 9;
+const PERF_SAMPLE_RAW: u64 = 1 << 10;
+const PERF_SAMPLE_BRANCH_STACK: u64 = 1 << 11;
+const PERF_SAMPLE_REGS_USER: u64 = 1 << 12;
+const PERF_SAMPLE_STACK_USER: u64 = 1 << 13;
+const PERF_SAMPLE_IDENTIFIER: u64 = 1 << 16;
+
+const PERF_SAMPLE_BRANCH_HW_INDEX: u64 = 1 << 17;
+
+const PROT_EXEC: u32 = 4;
+
+/// DWARF numbers of the registers in `perf_event_x86_regs` order.
+const PERF_REGS: [u16; 24] = [
+    0, 3, 2, 1, 4, 5, 6, 7, // ax, bx, cx, dx, si, di, bp, sp
+    16, registers::RFLAGS, registers::CS, registers::SS, // ip, flags, cs, ss
+    registers::DS, registers::ES, registers::FS, registers::GS,
+    8, 9, 10, 11, 12, 13, 14, 15, // r8 - r15
+];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads native-endian fields front to back.
+struct Fields<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if n > self.rest.len() {
+            return Err(invalid("truncated record"));
+        }
+        let (bytes, rest) = self.rest.split_at(n);
+        self.rest = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_ne_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let b = self.bytes(8)?;
+        Ok(u64::from_ne_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    /// A NUL-terminated string, the last field we read of a record.
+    fn string(&mut self) -> io::Result<String> {
+        let end = self.rest.iter().position(|&b| b == 0).ok_or_else(|| invalid("unterminated string"))?;
+        Ok(String::from_utf8_lossy(&self.rest[..end]).into_owned())
+    }
+}
+
+/// The attributes that decide how samples are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Format {
+    sample_type: u64,
+    regs_user: u64,
+    branch_sample_type: u64,
+    sample_id_all: bool,
+}
+
+impl Format {
+    fn parse(attr: &[u8]) -> io::Result<Format> {
+        let mut f = Fields { rest: attr };
+        let _type = f.u32()?;
+        let size = f.u32()? as usize;
+        let mut f = Fields { rest: attr.get(..size).ok_or_else(|| invalid("truncated attribute"))? };
+        f.bytes(24)?;
+        let sample_type = f.u64()?;
+        let _read_format = f.u64()?;
+        let flags = f.u64()?;
+        f.bytes(24)?;
+        let branch_sample_type = f.u64().unwrap_or(0);
+        let regs_user = f.u64().unwrap_or(0);
+        if sample_type & PERF_SAMPLE_READ != 0 {
+            return Err(invalid("PERF_SAMPLE_READ is not supported"));
+        }
+        Ok(Format { sample_type, regs_user, branch_sample_type, sample_id_all: flags & (1 << 18) != 0 })
+    }
+
+    fn has(&self, bit: u64) -> bool {
+        self.sample_type & bit != 0
+    }
+
+    /// The time in the `sample_id` fields at the end of a non-sample record.
+    fn trailer_time(&self, body: &[u8]) -> Option<u64> {
+        if !self.sample_id_all || !self.has(PERF_SAMPLE_TIME) {
+            return None;
+        }
+        let ids = [PERF_SAMPLE_TID, PERF_SAMPLE_TIME, PERF_SAMPLE_ID, PERF_SAMPLE_STREAM_ID,
+                   PERF_SAMPLE_CPU, PERF_SAMPLE_IDENTIFIER];
+        let size = 8 * ids.iter().filter(|&&bit| self.has(bit)).count();
+        let start = body.len().checked_sub(size)? + if self.has(PERF_SAMPLE_TID) { 8 } else { 0 };
+        Fields { rest: &body[start..] }.u64().ok()
+    }
+}
+
+/// An object file mapped into a process.
+#[derive(Debug, Clone)]
+pub struct Mmap {
+    pub pid: u32,
+    pub tid: u32,
+    pub time: Option<u64>,
+    pub range: AddrRange,
+    /// The file offset mapped at `range.start`.
+    pub offset: u64,
+    pub executable: bool,
+    pub path: String,
+}
+
+/// A thread got a new name, e.g. because its process called `exec`.
+#[derive(Debug, Clone)]
+pub struct Comm {
+    pub pid: u32,
+    pub tid: u32,
+    pub time: Option<u64>,
+    pub name: String,
+    /// The process called `exec`, so all its mappings are gone.
+    pub exec: bool,
+}
+
+/// A new process or thread.
+#[derive(Debug, Clone)]
+pub struct Fork {
+    pub pid: u32,
+    pub ppid: u32,
+    pub tid: u32,
+    pub time: u64,
+}
+
+/// A sample. Fields that were not recorded are `None` or empty.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub pid: u32,
+    pub tid: u32,
+    pub time: Option<u64>,
+    pub ip: Option<u64>,
+    pub period: Option<u64>,
+    /// The user-space registers, by DWARF register number.
+    pub registers: Option<Registers>,
+    /// A copy of the user stack, starting at the stack pointer.
+    pub stack: Vec<u8>,
+}
+
+/// The records of a `perf.data` file that matter for unwinding.
+#[derive(Debug, Clone)]
+pub enum Record {
+    Mmap(Mmap),
+    Comm(Comm),
+    Fork(Fork),
+    Sample(Box<Sample>),
+}
+
+impl Record {
+    pub fn time(&self) -> Option<u64> {
+        match *self {
+            Record::Mmap(ref m) => m.time,
+            Record::Comm(ref c) => c.time,
+            Record::Fork(ref f) => Some(f.time),
+            Record::Sample(ref s) => s.time,
+        }
+    }
+}
+
+/// A parsed `perf.data` file.
+#[derive(Debug, Clone)]
+pub struct PerfData {
+    records: Vec<Record>,
+}
+
+impl PerfData {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<PerfData> {
+        PerfData::parse(&fs::read(path)?)
+    }
+
+    /// Parses the header, the event attributes and the records of the data
+    /// section. Records other than the ones in `Record` are skipped.
+    ///
+    /// All events must lay out their samples the same way, and if every
+    /// record has a time stamp, the records are sorted by it.
+    pub fn parse(data: &[u8]) -> io::Result<PerfData> {
+        let mut header = Fields { rest: data };
+        if header.bytes(8)? != MAGIC {
+            return Err(invalid("not a perf.data file, or not in native byte order"));
+        }
+        let _header_size = header.u64()?;
+        let attr_size = header.u64()? as usize;
+        let attrs = (header.u64()? as usize, header.u64()? as usize);
+        let records = (header.u64()? as usize, header.u64()? as usize);
+        let section = |(offset, size): (usize, usize)| {
+            offset.checked_add(size).and_then(|end| data.get(offset..end)).ok_or_else(|| invalid("section out of bounds"))
+        };
+
+        if attr_size == 0 {
+            return Err(invalid("no event attributes"));
+        }
+        let mut format = None;
+        for attr in section(attrs)?.chunks(attr_size) {
+            let this = Format::parse(attr)?;
+            if format.is_some_and(|format| format != this) {
+                return Err(invalid("events with different sample formats are not supported"));
+            }
+            format = Some(this);
+        }
+        let format = format.ok_or_else(|| invalid("no event attributes"))?;
+
+        let mut parsed = Vec::new();
+        let mut rest = Fields { rest: section(records)? };
+        while !rest.rest.is_empty() {
+            let type_ = rest.u32()?;
+            let misc = rest.u16()?;
+            let size = rest.u16()? as usize;
+            let body = rest.bytes(size.checked_sub(8).ok_or_else(|| invalid("bad record size"))?)?;
+            if let Some(record) = parse_record(&format, type_, misc, body)? {
+                parsed.push(record);
+            }
+        }
+        if parsed.iter().all(|record| record.time().is_some()) {
+            parsed.sort_by_key(|record| record.time());
+        }
+        Ok(PerfData { records: parsed })
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Unwinds every sample with user registers and stack.
+    ///
+    /// Replays the records to know the mappings of each process at the
+    /// time of each sample, and reads the mapped files from disk, so they
+    /// must not have changed since they were recorded.
+    pub fn unwind(&self) -> Vec<UnwoundSample> {
+        let mut processes: HashMap<u32, Process> = HashMap::new();
+        let mut names: HashMap<u32, String> = HashMap::new();
+        let mut unwound = Vec::new();
+        for record in &self.records {
+            match *record {
+                Record::Mmap(ref m) if m.executable && m.path.starts_with('/') => {
+                    let process = processes.entry(m.pid).or_default();
+                    process.mappings.retain(|x| x.range.end <= m.range.start || m.range.end <= x.range.start);
+                    process.mappings.push(FileMapping { path: m.path.clone(), range: m.range, offset: m.offset });
+                    process.unwinder = None;
+                }
+                Record::Mmap(_) => (),
+                Record::Comm(ref c) => {
+                    if c.exec {
+                        processes.insert(c.pid, Process::default());
+                    }
+                    names.insert(c.tid, c.name.clone());
+                }
+                Record::Fork(ref f) => {
+                    if f.pid != f.ppid {
+                        let mappings = processes.get(&f.ppid).map(|p| p.mappings.clone()).unwrap_or_default();
+                        processes.insert(f.pid, Process { mappings, unwinder: None });
+                    }
+                    if let Some(name) = names.get(&f.ppid).cloned() {
+                        names.insert(f.tid, name);
+                    }
+                }
+                Record::Sample(ref s) => {
+                    let process = processes.entry(s.pid).or_default();
+                    let mappings = &process.mappings;
+                    let unwinder = process.unwinder.get_or_insert_with(|| {
+                        DwarfUnwinder::new(Arc::new(ModuleIndex::from_files(mappings)))
+                    });
+                    unwound.push(UnwoundSample {
+                        pid: s.pid,
+                        tid: s.tid,
+                        comm: names.get(&s.tid).or_else(|| names.get(&s.pid)).cloned().unwrap_or_default(),
+                        frames: unwind_sample(unwinder, s),
+                    });
+                }
+            }
+        }
+        unwound
+    }
+}
+
+#[derive(Default)]
+struct Process {
+    mappings: Vec<FileMapping>,
+    /// Built on the first sample after the mappings changed.
+    unwinder: Option<DwarfUnwinder>,
+}
+
+fn parse_record(format: &Format, type_: u32, misc: u16, body: &[u8]) -> io::Result<Option<Record>> {
+    let mut f = Fields { rest: body };
+    let record = match type_ {
+        PERF_RECORD_MMAP | PERF_RECORD_MMAP2 => {
+            let (pid, tid) = (f.u32()?, f.u32()?);
+            let (start, len, offset) = (f.u64()?, f.u64()?, f.u64()?);
+            let executable = if type_ == PERF_RECORD_MMAP2 {
+                // Device and inode, or the build id.
+                f.bytes(24)?;
+                let prot = f.u32()?;
+                let _flags = f.u32()?;
+                prot & PROT_EXEC != 0
+            } else {
+                misc & PERF_RECORD_MISC_MMAP_DATA == 0
+            };
+            Record::Mmap(Mmap {
+                pid,
+                tid,
+                time: format.trailer_time(body),
+                range: AddrRange { start, end: start.checked_add(len).ok_or_else(|| invalid("mapping wraps around"))? },
+                offset,
+                executable,
+                path: f.string()?,
+            })
+        }
+        PERF_RECORD_COMM => Record::Comm(Comm {
+            pid: f.u32()?,
+            tid: f.u32()?,
+            time: format.trailer_time(body),
+            name: f.string()?,
+            exec: misc & PERF_RECORD_MISC_COMM_EXEC != 0,
+        }),
+        PERF_RECORD_FORK => Record::Fork(Fork {
+            pid: f.u32()?,
+            ppid: f.u32()?,
+            tid: f.u32()?,
+            time: {
+                let _ptid = f.u32()?;
+                f.u64()?
+            },
+        }),
+        PERF_RECORD_SAMPLE => Record::Sample(Box::new(parse_sample(format, &mut f)?)),
+        _ => return Ok(None),
+    };
+    Ok(Some(record))
+}
+
+fn parse_sample(format: &Format, f: &mut Fields) -> io::Result<Sample> {
+    let mut sample = Sample {
+        pid: 0,
+        tid: 0,
+        time: None,
+        ip: None,
+        period: None,
+        registers: None,
+        stack: Vec::new(),
+    };
+    if format.has(PERF_SAMPLE_IDENTIFIER) {
+        f.u64()?;
+    }
+    if format.has(PERF_SAMPLE_IP) {
+        sample.ip = Some(f.u64()?);
+    }
+    if format.has(PERF_SAMPLE_TID) {
+        sample.pid = f.u32()?;
+        sample.tid = f.u32()?;
+    }
+    if format.has(PERF_SAMPLE_TIME) {
+        sample.time = Some(f.u64()?);
+    }
+    for &bit in &[PERF_SAMPLE_ADDR, PERF_SAMPLE_ID, PERF_SAMPLE_STREAM_ID, PERF_SAMPLE_CPU] {
+        if format.has(bit) {
+            f.u64()?;
+        }
+    }
+    if format.has(PERF_SAMPLE_PERIOD) {
+        sample.period = Some(f.u64()?);
+    }
+    if format.has(PERF_SAMPLE_CALLCHAIN) {
+        let n = f.u64()? as usize;
+        f.bytes(n.checked_mul(8).ok_or_else(|| invalid("bad callchain"))?)?;
+    }
+    if format.has(PERF_SAMPLE_RAW) {
+        // The size field and the data are padded to 8 bytes together.
+        let size = f.u32()? as usize;
+        f.bytes(size)?;
+    }
+    if format.has(PERF_SAMPLE_BRANCH_STACK) {
+        let n = f.u64()? as usize;
+        if format.branch_sample_type & PERF_SAMPLE_BRANCH_HW_INDEX != 0 {
+            f.u64()?;
+        }
+        f.bytes(n.checked_mul(24).ok_or_else(|| invalid("bad branch stack"))?)?;
+    }
+    if format.has(PERF_SAMPLE_REGS_USER) && f.u64()? != 0 {
+        let mut registers = Registers::default();
+        for bit in 0..64 {
+            if format.regs_user & (1 << bit) != 0 {
+                let value = f.u64()?;
+                if let Some(&reg) = PERF_REGS.get(bit) {
+                    registers[reg] = Some(value);
+                }
+            }
+        }
+        sample.registers = Some(registers);
+    }
+    if format.has(PERF_SAMPLE_STACK_USER) {
+        let size = f.u64()? as usize;
+        let stack = f.bytes(size)?;
+        if size != 0 {
+            // How much of the copy the kernel actually filled in.
+            let dyn_size = f.u64()? as usize;
+            sample.stack = stack[..dyn_size.min(size)].to_owned();
+        }
+    }
+    Ok(sample)
+}
+
+/// A frame of an unwound sample.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// The sampled instruction for the innermost frame, a return address
+    /// for the others.
+    pub address: u64,
+    pub module: Option<Module>,
+}
+
+impl fmt::Display for Frame {
+    /// `file+0xoffset`, with the offset relative to the object's link-time
+    /// addresses, or just the address if the module is unknown.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&flamegraph::address_name(self.module.as_ref(), self.address))
+    }
+}
+
+/// The user-space stack of a sample.
+#[derive(Debug, Clone)]
+pub struct UnwoundSample {
+    pub pid: u32,
+    pub tid: u32,
+    /// The name of the thread, if a `COMM` record named it.
+    pub comm: String,
+    /// The innermost frame first. Empty without user registers.
+    pub frames: Vec<Frame>,
+}
+
+fn unwind_sample(unwinder: &DwarfUnwinder, sample: &Sample) -> Vec<Frame> {
+    let registers = match sample.registers {
+        Some(ref registers) => registers,
+        None => return Vec::new(),
+    };
+    let sp = registers[X86_64::RSP].unwrap_or(0);
+    let snapshot = StackSnapshot::new(registers.clone(), sp, sample.stack.clone());
+    let mut cursor = UnwindCursor::from_snapshot(Arc::new(snapshot));
+    let mut frames = Vec::new();
+    loop {
+        match cursor.step(unwinder) {
+            Ok(Some(frame)) => frames.push(Frame {
+                address: frame.return_address(),
+                module: frame.module().cloned(),
+            }),
+            Ok(None) => break,
+            Err(e) => {
+                debug!("sample of {} at {:?} stops after {} frames: {}", sample.tid, sample.time, frames.len(), e);
+                break;
+            }
+        }
+    }
+    // Even without CFI the sampled instruction is known.
+    if frames.is_empty() {
+        if let Some(ip) = registers[X86_64::RA] {
+            frames.push(Frame { address: ip, module: None });
+        }
+    }
+    frames
+}
+
+/// Writes `samples` in the folded format read by `flamegraph.pl`: one line
+/// per distinct stack, with the thread name and the frames from the
+/// outermost one in, separated by `;`, followed by the number of samples.
+pub fn write_folded<W: Write>(samples: &[UnwoundSample], out: W) -> io::Result<()> {
+    // Every process has its own mappings, so each is folded on its own.
+    type Stacks = Vec<(Vec<u64>, i64)>;
+    let mut processes: BTreeMap<(u32, &str), (Stacks, Vec<Module>)> = BTreeMap::new();
+    for sample in samples.iter().filter(|sample| !sample.frames.is_empty()) {
+        let (stacks, modules) = processes.entry((sample.pid, &sample.comm)).or_default();
+        stacks.push((sample.frames.iter().map(|frame| frame.address).collect(), 1));
+        for module in sample.frames.iter().filter_map(|frame| frame.module.as_ref()) {
+            if !modules.iter().any(|m| m.path() == module.path() && m.base() == module.base()) {
+                modules.push(module.clone());
+            }
+        }
+    }
+    let flamegraph = Flamegraph::default();
+    let mut folded = BTreeMap::new();
+    for ((_, comm), (stacks, modules)) in processes {
+        flamegraph.fold(&stacks, &modules, Some(comm), &mut folded);
+    }
+    flamegraph::write_folded(&folded, out)
+}
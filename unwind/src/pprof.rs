@@ -0,0 +1,244 @@
+//! Export of sampled stacks as pprof profiles.
+//!
+//! `go tool pprof` and the tools built on it read Google's `profile.proto`,
+//! gzip-compressed. Each stack becomes a `Sample` of `Location`s, and each
+//! module a `Mapping`, so that the tools can symbolize the addresses
+//! themselves. With a `Symbolizer`, the locations also get `Line`s and
+//! `Function`s.
+
+use std::collections::HashMap;
+use std::string::{String, ToString};
+use std::vec::Vec;
+use std::vec;
+use std::fmt::Write;
+
+use miniz_oxide::deflate::compress_to_vec;
+use find_cfi::Module;
+use symbolize::Symbolizer;
+
+/// How to build a profile.
+pub struct Pprof<'a> {
+    /// What the sample values count, as type and unit.
+    pub sample_type: (String, String),
+    /// What the sampling period counts, its unit and length, e.g. `("cpu",
+    /// "nanoseconds", 10_000_000)` for 100 samples per second of CPU time.
+    pub period: Option<(String, String, i64)>,
+    /// When the profile started, in nanoseconds since the epoch.
+    pub time_nanos: i64,
+    pub duration_nanos: i64,
+    pub symbolizer: Option<&'a dyn Symbolizer>,
+}
+
+impl<'a> Default for Pprof<'a> {
+    fn default() -> Pprof<'a> {
+        Pprof {
+            sample_type: ("samples".into(), "count".into()),
+            period: None,
+            time_nanos: 0,
+            duration_nanos: 0,
+            symbolizer: None,
+        }
+    }
+}
+
+/// A protobuf message being encoded.
+#[derive(Default)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    /// A varint field, left out if zero like every proto3 default.
+    fn uint(&mut self, field: u64, value: u64) {
+        if value != 0 {
+            self.varint(field << 3);
+            self.varint(value);
+        }
+    }
+
+    fn int(&mut self, field: u64, value: i64) {
+        self.uint(field, value as u64);
+    }
+
+    fn bytes(&mut self, field: u64, bytes: &[u8]) {
+        self.varint(field << 3 | 2);
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn message(&mut self, field: u64, message: Message) {
+        self.bytes(field, &message.buf);
+    }
+
+    fn packed<I: IntoIterator<Item = u64>>(&mut self, field: u64, values: I) {
+        let mut packed = Message::default();
+        for value in values {
+            packed.varint(value);
+        }
+        if !packed.buf.is_empty() {
+            self.message(field, packed);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Strings {
+    table: Vec<String>,
+    index: HashMap<String, i64>,
+}
+
+impl Strings {
+    fn get(&mut self, s: &str) -> i64 {
+        if let Some(&i) = self.index.get(s) {
+            return i;
+        }
+        let i = self.table.len() as i64;
+        self.table.push(s.to_string());
+        self.index.insert(s.to_string(), i);
+        i
+    }
+}
+
+fn value_type(strings: &mut Strings, type_: &str, unit: &str) -> Message {
+    let mut message = Message::default();
+    message.int(1, strings.get(type_));
+    message.int(2, strings.get(unit));
+    message
+}
+
+impl<'a> Pprof<'a> {
+    /// Encodes a gzip-compressed `Profile` of `stacks`, each the sampled
+    /// instruction followed by return addresses, and its value.
+    ///
+    /// `modules` should include every object the stacks run through, e.g.
+    /// `ModuleIndex::modules()`; addresses outside them get no mapping.
+    pub fn encode(&self, stacks: &[(Vec<u64>, i64)], modules: &[Module]) -> Vec<u8> {
+        let mut strings = Strings::default();
+        strings.get("");
+        let mut profile = Message::default();
+        profile.message(1, value_type(&mut strings, &self.sample_type.0, &self.sample_type.1));
+
+        // Locations are keyed by lookup address: for callers that is the
+        // return address minus one, which is inside the call.
+        let mut locations: Vec<u64> = Vec::new();
+        let mut location_ids: HashMap<u64, u64> = HashMap::new();
+        for &(ref ips, value) in stacks {
+            let ids: Vec<u64> = ips.iter().enumerate().map(|(i, &ip)| {
+                let address = if i == 0 { ip } else { ip.wrapping_sub(1) };
+                *location_ids.entry(address).or_insert_with(|| {
+                    locations.push(address);
+                    locations.len() as u64
+                })
+            }).collect();
+            let mut sample = Message::default();
+            sample.packed(1, ids);
+            sample.packed(2, Some(value as u64));
+            profile.message(2, sample);
+        }
+
+        let mut functions: HashMap<(String, String), u64> = HashMap::new();
+        let mut function_list = Vec::new();
+        let mut symbolized = vec![false; modules.len()];
+        let mut encoded_locations = Vec::new();
+        for (i, &address) in locations.iter().enumerate() {
+            let module = modules.iter().position(|module| module.text().contains(address));
+            let mut location = Message::default();
+            location.uint(1, i as u64 + 1);
+            location.uint(2, module.map_or(0, |m| m as u64 + 1));
+            location.uint(3, address);
+            let symbols = match self.symbolizer {
+                Some(symbolizer) => symbolizer.symbolize(module.map(|m| &modules[m]), address),
+                None => Vec::new(),
+            };
+            if let (Some(m), false) = (module, symbols.is_empty()) {
+                symbolized[m] = true;
+            }
+            for symbol in symbols {
+                let key = (symbol.name, symbol.file);
+                let id = match functions.get(&key) {
+                    Some(&id) => id,
+                    None => {
+                        let id = function_list.len() as u64 + 1;
+                        let mut function = Message::default();
+                        function.uint(1, id);
+                        function.int(2, strings.get(&key.0));
+                        function.int(3, strings.get(&key.0));
+                        function.int(4, strings.get(&key.1));
+                        function_list.push(function);
+                        functions.insert(key, id);
+                        id
+                    }
+                };
+                let mut line = Message::default();
+                line.uint(1, id);
+                line.int(2, i64::from(symbol.line));
+                location.message(4, line);
+            }
+            encoded_locations.push(location);
+        }
+
+        for (i, module) in modules.iter().enumerate() {
+            let mut build_id = String::new();
+            for byte in module.build_id() {
+                let _ = write!(build_id, "{:02x}", byte);
+            }
+            let mut mapping = Message::default();
+            mapping.uint(1, i as u64 + 1);
+            mapping.uint(2, module.text().start);
+            mapping.uint(3, module.text().end);
+            mapping.uint(4, module.text_offset());
+            mapping.int(5, strings.get(module.path()));
+            mapping.int(6, strings.get(&build_id));
+            for field in 7..10 {
+                mapping.uint(field, symbolized[i] as u64);
+            }
+            profile.message(3, mapping);
+        }
+        for location in encoded_locations {
+            profile.message(4, location);
+        }
+        for function in function_list {
+            profile.message(5, function);
+        }
+
+        profile.int(9, self.time_nanos);
+        profile.int(10, self.duration_nanos);
+        if let Some((ref type_, ref unit, period)) = self.period {
+            profile.message(11, value_type(&mut strings, type_, unit));
+            profile.int(12, period);
+        }
+        // Strings are added up to here, so the table goes last.
+        for s in &strings.table {
+            profile.bytes(6, s.as_bytes());
+        }
+        gzip(&profile.buf)
+    }
+}
+
+/// Wraps deflate-compressed `data` in a gzip member (RFC 1952).
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend_from_slice(&compress_to_vec(data, 6));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
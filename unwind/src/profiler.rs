@@ -0,0 +1,512 @@
+//! A sampling CPU profiler.
+//!
+//! A CPU-time timer interrupts the program with a signal. The handler walks
+//! the interrupted stack with a `SignalSafeUnwinder`, straight into a slot
+//! of a lock-free ring buffer, and a background thread drains the buffer
+//! into a call tree keyed by return address.
+//!
+//! With `Timer::PerThread` every thread gets a timer on its own CPU clock
+//! (`timer_create` with `SIGEV_THREAD_ID`), so each thread is sampled in
+//! proportion to the CPU time it uses. The background thread looks for new
+//! threads every 100ms. With `Timer::Process` there is one `setitimer`
+//! timer, and the kernel sends its signals to whichever thread is running.
+//!
+//! The handler stays installed after the profiler stops, and ignores
+//! signals that arrive late. Nothing else in the process may use the signal.
+
+use std::collections::HashMap;
+use std::cell::UnsafeCell;
+use std::fs;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::boxed::Box;
+use std::vec::Vec;
+use std::vec;
+
+use ModuleIndex;
+use signal_safe::SignalSafeUnwinder;
+
+/// Which timer drives the sampling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    /// One timer per thread, on that thread's CPU clock.
+    PerThread,
+    /// One `ITIMER_PROF` timer for the whole process. It always sends
+    /// `SIGPROF`, so `signal` has to be that.
+    Process,
+}
+
+/// How to profile.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    /// Samples per second of CPU time.
+    pub frequency: u32,
+    pub timer: Timer,
+    /// The signal the timers send. Only `SIGPROF` with `Timer::Process`.
+    pub signal: i32,
+    /// The most addresses recorded per sample.
+    pub max_frames: usize,
+    /// How many samples may wait for the background thread. Samples taken
+    /// while the buffer is full are counted as lost. Rounded up to a power
+    /// of two.
+    pub buffer_size: usize,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler {
+            frequency: 99,
+            timer: Timer::PerThread,
+            signal: ::libc::SIGPROF,
+            max_frames: 128,
+            buffer_size: 4096,
+        }
+    }
+}
+
+struct Slot {
+    /// Vyukov's sequence number: the position this slot may be written at,
+    /// or one more once it holds a sample.
+    seq: AtomicUsize,
+    len: UnsafeCell<usize>,
+    ips: UnsafeCell<Box<[u64]>>,
+}
+
+/// A bounded queue that signal handlers on any thread push to, and one
+/// thread pops from, without locks or allocation.
+struct Ring {
+    slots: Box<[Slot]>,
+    tail: AtomicUsize,
+    head: AtomicUsize,
+    lost: AtomicU64,
+}
+
+// A slot's contents are only touched by the thread that claimed it, until
+// it is published by storing its sequence number.
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn new(capacity: usize, max_frames: usize) -> Ring {
+        let capacity = capacity.max(2).next_power_of_two();
+        let slots = (0..capacity).map(|i| Slot {
+            seq: AtomicUsize::new(i),
+            len: UnsafeCell::new(0),
+            ips: UnsafeCell::new(vec![0; max_frames].into_boxed_slice()),
+        }).collect();
+        Ring { slots, tail: AtomicUsize::new(0), head: AtomicUsize::new(0), lost: AtomicU64::new(0) }
+    }
+
+    /// Reserves the next free slot and returns its position, or `None` if
+    /// the ring is full. Async-signal-safe.
+    fn claim(&self) -> Option<usize> {
+        let mask = self.slots.len() - 1;
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let seq = self.slots[pos & mask].seq.load(Ordering::Acquire);
+            match (seq.wrapping_sub(pos) as isize).signum() {
+                0 => match self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => return Some(pos),
+                    Err(current) => pos = current,
+                },
+                -1 => return None,
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Hands a claimed slot to the consumer.
+    fn publish(&self, pos: usize) {
+        self.slots[pos & (self.slots.len() - 1)].seq.store(pos.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Passes the oldest sample to `f` and frees its slot. Only one thread
+    /// may pop.
+    fn pop<F: FnOnce(&[u64])>(&self, f: F) -> bool {
+        let pos = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[pos & (self.slots.len() - 1)];
+        if slot.seq.load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return false;
+        }
+        unsafe { f(&(&*slot.ips.get())[..*slot.len.get()]) };
+        slot.seq.store(pos.wrapping_add(self.slots.len()), Ordering::Release);
+        self.head.store(pos.wrapping_add(1), Ordering::Relaxed);
+        true
+    }
+}
+
+struct State {
+    unwinder: SignalSafeUnwinder,
+    ring: Ring,
+}
+
+static STATE: AtomicPtr<State> = AtomicPtr::new(ptr::null_mut());
+/// Handlers currently using `STATE`, so it is only freed once they are done.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_signal(_sig: ::libc::c_int, _info: *mut ::libc::siginfo_t, uc: *mut ::libc::c_void) {
+    let errno = unsafe { *::libc::__errno_location() };
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    let state = STATE.load(Ordering::SeqCst);
+    if !state.is_null() {
+        let state = unsafe { &*state };
+        match state.ring.claim() {
+            Some(pos) => {
+                let slot = &state.ring.slots[pos & (state.ring.slots.len() - 1)];
+                unsafe {
+                    let ips = &mut **slot.ips.get();
+                    *slot.len.get() = state.unwinder.trace_from_ucontext(uc as *const ::libc::ucontext_t, ips);
+                }
+                state.ring.publish(pos);
+            }
+            None => {
+                state.ring.lost.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    unsafe { *::libc::__errno_location() = errno };
+}
+
+/// A node of the call tree: one address in the context of all its callers.
+#[derive(Debug, Clone, Default)]
+pub struct CallNode {
+    address: u64,
+    total: u64,
+    own: u64,
+    children: Vec<CallNode>,
+}
+
+impl CallNode {
+    /// The return address of the frame, or, for a leaf frame, the address
+    /// of the interrupted instruction. Zero for the root.
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// Samples with this node on the stack.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Samples taken in this node itself.
+    pub fn own(&self) -> u64 {
+        self.own
+    }
+
+    /// The nodes called from here, most samples first.
+    pub fn children(&self) -> &[CallNode] {
+        &self.children
+    }
+}
+
+#[derive(Default)]
+struct Tree {
+    total: u64,
+    own: u64,
+    children: HashMap<u64, Tree>,
+}
+
+impl Tree {
+    /// Adds a sample, given the innermost frame first.
+    fn add(&mut self, ips: &[u64]) {
+        let mut node = self;
+        node.total += 1;
+        for &ip in ips.iter().rev() {
+            node = node.children.entry(ip).or_default();
+            node.total += 1;
+        }
+        node.own += 1;
+    }
+
+    fn to_node(&self, address: u64) -> CallNode {
+        let mut children: Vec<CallNode> = self.children.iter().map(|(&address, child)| child.to_node(address)).collect();
+        children.sort_by(|a, b| b.total.cmp(&a.total).then(a.address.cmp(&b.address)));
+        CallNode { address, total: self.total, own: self.own, children }
+    }
+}
+
+/// The samples collected so far.
+#[derive(Debug, Clone)]
+pub struct Report {
+    root: CallNode,
+    lost: u64,
+}
+
+impl Report {
+    /// The root of the call tree. Its children are the outermost frames.
+    pub fn root(&self) -> &CallNode {
+        &self.root
+    }
+
+    /// The samples in the tree.
+    pub fn samples(&self) -> u64 {
+        self.root.total
+    }
+
+    /// Samples dropped because the buffer was full or the stack could not
+    /// be walked at all.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Every sampled stack, the innermost frame first, with its number of
+    /// samples, as taken by the exporters.
+    pub fn stacks(&self) -> Vec<(Vec<u64>, i64)> {
+        fn visit(node: &CallNode, path: &mut Vec<u64>, stacks: &mut Vec<(Vec<u64>, i64)>) {
+            if node.own > 0 {
+                stacks.push((path.iter().rev().cloned().collect(), node.own as i64));
+            }
+            for child in &node.children {
+                path.push(child.address);
+                visit(child, path, stacks);
+                path.pop();
+            }
+        }
+        let mut stacks = Vec::new();
+        for child in &self.root.children {
+            visit(child, &mut vec![child.address], &mut stacks);
+        }
+        stacks
+    }
+}
+
+struct Shared {
+    tree: Mutex<Tree>,
+    empty: AtomicU64,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn drain(&self, ring: &Ring) {
+        let mut tree = self.tree.lock().unwrap_or_else(|e| e.into_inner());
+        while ring.pop(|ips| if ips.is_empty() {
+            self.empty.fetch_add(1, Ordering::Relaxed);
+        } else {
+            tree.add(ips);
+        }) {}
+    }
+}
+
+/// A profiler that is running. Dropping it stops it.
+pub struct RunningProfiler {
+    state: *mut State,
+    shared: Arc<Shared>,
+    timer: Timer,
+    aggregator: Option<JoinHandle<()>>,
+}
+
+fn interval(frequency: u32) -> ::libc::timespec {
+    let nanos = 1_000_000_000 / u64::from(frequency.max(1));
+    ::libc::timespec { tv_sec: (nanos / 1_000_000_000) as _, tv_nsec: (nanos % 1_000_000_000) as _ }
+}
+
+fn gettid() -> i32 {
+    unsafe { ::libc::syscall(::libc::SYS_gettid) as i32 }
+}
+
+fn thread_ids() -> io::Result<Vec<i32>> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir("/proc/self/task")? {
+        if let Ok(tid) = entry?.file_name().to_string_lossy().parse::<i32>() {
+            tids.push(tid);
+        }
+    }
+    Ok(tids)
+}
+
+/// Starts a timer on the CPU clock of thread `tid` that sends it `signal`.
+fn thread_timer(tid: i32, signal: i32, interval: ::libc::timespec) -> io::Result<usize> {
+    // MAKE_THREAD_CPUCLOCK(tid, CPUCLOCK_SCHED) from the kernel.
+    let clock = (!tid << 3) | 6;
+    unsafe {
+        let mut event: ::libc::sigevent = mem::zeroed();
+        event.sigev_notify = ::libc::SIGEV_THREAD_ID;
+        event.sigev_signo = signal;
+        event.sigev_notify_thread_id = tid;
+        let mut timer: ::libc::timer_t = ptr::null_mut();
+        if ::libc::timer_create(clock, &mut event, &mut timer) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let spec = ::libc::itimerspec { it_interval: interval, it_value: interval };
+        if ::libc::timer_settime(timer, 0, &spec, ptr::null_mut()) != 0 {
+            let error = io::Error::last_os_error();
+            ::libc::timer_delete(timer);
+            return Err(error);
+        }
+        Ok(timer as usize)
+    }
+}
+
+/// The per-thread timers, kept in sync with the threads of the process.
+struct ThreadTimers {
+    signal: i32,
+    interval: ::libc::timespec,
+    timers: HashMap<i32, usize>,
+}
+
+impl ThreadTimers {
+    fn update(&mut self, skip: Option<i32>) -> io::Result<()> {
+        let tids = thread_ids()?;
+        self.timers.retain(|tid, &mut timer| {
+            let alive = tids.contains(tid);
+            if !alive {
+                unsafe { ::libc::timer_delete(timer as ::libc::timer_t) };
+            }
+            alive
+        });
+        for tid in tids {
+            if Some(tid) == skip || self.timers.contains_key(&tid) {
+                continue;
+            }
+            match thread_timer(tid, self.signal, self.interval) {
+                Ok(timer) => {
+                    self.timers.insert(tid, timer);
+                }
+                // The thread exited since we listed it.
+                Err(ref e) if e.raw_os_error() == Some(::libc::EINVAL) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ThreadTimers {
+    fn drop(&mut self) {
+        for &timer in self.timers.values() {
+            unsafe { ::libc::timer_delete(timer as ::libc::timer_t) };
+        }
+    }
+}
+
+impl Profiler {
+    /// Installs the signal handler, starts the timers and the background
+    /// thread. Fails if another profiler is running, or if `Timer::Process`
+    /// is combined with a signal other than `SIGPROF`.
+    ///
+    /// The module index is built here if it does not exist yet; objects
+    /// loaded later are not seen.
+    pub fn start(&self) -> io::Result<RunningProfiler> {
+        if self.timer == Timer::Process && self.signal != ::libc::SIGPROF {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the process timer only sends SIGPROF"));
+        }
+        // Threads started later have stacks outside the index's memory
+        // map, so those are read with process_vm_readv.
+        let concurrency = thread::available_parallelism().map_or(8, |n| n.get()) * 2;
+        let mut unwinder = SignalSafeUnwinder::new(ModuleIndex::global(), concurrency);
+        unwinder.set_process_vm_readv(true);
+        let state = Box::into_raw(Box::new(State {
+            unwinder,
+            ring: Ring::new(self.buffer_size, self.max_frames),
+        }));
+        if STATE.compare_exchange(ptr::null_mut(), state, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            drop(unsafe { Box::from_raw(state) });
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a profiler is already running"));
+        }
+        let shared = Arc::new(Shared { tree: Mutex::new(Tree::default()), empty: AtomicU64::new(0), stop: AtomicBool::new(false) });
+        let mut running = RunningProfiler { state, shared: shared.clone(), timer: self.timer, aggregator: None };
+
+        unsafe {
+            let mut action: ::libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as usize;
+            // Not SA_ONSTACK: the alternate stacks Rust sets up for its
+            // stack overflow handler are too small to unwind on.
+            action.sa_flags = ::libc::SA_SIGINFO | ::libc::SA_RESTART;
+            ::libc::sigemptyset(&mut action.sa_mask);
+            if ::libc::sigaction(self.signal, &action, ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let interval = interval(self.frequency);
+        let mut timers = match self.timer {
+            Timer::PerThread => {
+                let mut timers = ThreadTimers { signal: self.signal, interval, timers: HashMap::new() };
+                timers.update(None)?;
+                Some(timers)
+            }
+            Timer::Process => {
+                let timer = ::libc::itimerval {
+                    it_interval: ::libc::timeval { tv_sec: interval.tv_sec, tv_usec: interval.tv_nsec / 1000 },
+                    it_value: ::libc::timeval { tv_sec: interval.tv_sec, tv_usec: interval.tv_nsec / 1000 },
+                };
+                if unsafe { ::libc::setitimer(::libc::ITIMER_PROF, &timer, ptr::null_mut()) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                None
+            }
+        };
+
+        let ring = state as usize;
+        running.aggregator = Some(thread::Builder::new().name("unwind-profiler".into()).spawn(move || {
+            // The state outlives this thread: `stop` joins it first.
+            let ring = unsafe { &(*(ring as *const State)).ring };
+            let me = gettid();
+            let mut scanned = Instant::now();
+            while !shared.stop.load(Ordering::Acquire) {
+                shared.drain(ring);
+                if let Some(ref mut timers) = timers {
+                    if scanned.elapsed() >= Duration::from_millis(100) {
+                        if let Err(e) = timers.update(Some(me)) {
+                            warn!("cannot start profiler timers: {}", e);
+                        }
+                        scanned = Instant::now();
+                    }
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        })?);
+        Ok(running)
+    }
+}
+
+impl RunningProfiler {
+    /// The samples the background thread has collected so far. Samples
+    /// taken in the last few milliseconds may be missing.
+    pub fn report(&self) -> Report {
+        let tree = self.shared.tree.lock().unwrap_or_else(|e| e.into_inner());
+        let state = unsafe { &*self.state };
+        Report {
+            root: tree.to_node(0),
+            lost: state.ring.lost.load(Ordering::Relaxed) + self.shared.empty.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stops the timers and returns all samples.
+    pub fn stop(mut self) -> Report {
+        self.finish();
+        self.report()
+    }
+
+    fn finish(&mut self) {
+        if self.shared.stop.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if self.timer == Timer::Process {
+            let off: ::libc::itimerval = unsafe { mem::zeroed() };
+            unsafe { ::libc::setitimer(::libc::ITIMER_PROF, &off, ptr::null_mut()) };
+        }
+        // The thread deletes the per-thread timers as it exits.
+        if let Some(aggregator) = self.aggregator.take() {
+            let _ = aggregator.join();
+        }
+
+        STATE.store(ptr::null_mut(), Ordering::SeqCst);
+        while ACTIVE.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        self.shared.drain(unsafe { &(*self.state).ring });
+    }
+}
+
+impl Drop for RunningProfiler {
+    fn drop(&mut self) {
+        self.finish();
+        drop(unsafe { Box::from_raw(self.state) });
+    }
+}
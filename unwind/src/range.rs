@@ -12,4 +12,8 @@ impl AddrRange {
     pub fn len(&self) -> u64 {
         self.end - self.start
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
 }
@@ -1,20 +1,294 @@
 use gimli;
-use std::fmt::{Debug, Formatter, Result as FmtResult};
-use std::ops::{Index, IndexMut};
+use gimli::X86_64;
+use core::fmt::{self, Debug, Display, Formatter, Result as FmtResult};
+use core::ops::{Index, IndexMut};
 
-#[derive(Default, Clone, PartialEq, Eq)]
+/// One past the highest DWARF register number we know about (K7).
+pub(crate) const REGISTER_COUNT: usize = 126;
+/// The 64-bit registers among those numbers, see `slot`.
+const SCALAR_COUNT: usize = 47;
+/// XMM0-15, ST0-7 and XMM16-31.
+const WIDE_COUNT: usize = 40;
+
+/// Registers that the System V x86-64 ABI lets a callee clobber.
+const CALLER_SAVED: [gimli::Register; 9] = [
+    X86_64::RAX, X86_64::RDX, X86_64::RCX, X86_64::RSI, X86_64::RDI,
+    X86_64::R8, X86_64::R9, X86_64::R10, X86_64::R11,
+];
+
+// DWARF register numbers from the System V x86-64 psABI, beyond the ones gimli names.
+pub const XMM0: u16 = 17;
+pub const ST0: u16 = 33;
+pub const MM0: u16 = 41;
+pub const RFLAGS: u16 = 49;
+pub const ES: u16 = 50;
+pub const CS: u16 = 51;
+pub const SS: u16 = 52;
+pub const DS: u16 = 53;
+pub const FS: u16 = 54;
+pub const GS: u16 = 55;
+pub const FS_BASE: u16 = 58;
+pub const GS_BASE: u16 = 59;
+pub const TR: u16 = 62;
+pub const LDTR: u16 = 63;
+pub const MXCSR: u16 = 64;
+pub const FCW: u16 = 65;
+pub const FSW: u16 = 66;
+pub const XMM16: u16 = 67;
+pub const K0: u16 = 118;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// The number does not name an x86-64 register.
+    Unknown(u16),
+    /// The register is 128 bits wide and must be accessed with `vector`/`set_vector`,
+    /// or it is 64 bits wide and must not be.
+    WrongWidth(u16),
+}
+
+impl Display for RegisterError {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            RegisterError::Unknown(reg) => write!(fmt, "unknown register {}", reg),
+            RegisterError::WrongWidth(reg) => write!(fmt, "wrong access width for register {}", reg),
+        }
+    }
+}
+
+/// Where a `ucontext_t` keeps each general purpose register, with the
+/// interrupted instruction pointer in the return address column.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const UCONTEXT_GREGS: [(gimli::Register, ::libc::c_int); 17] = {
+    use libc::{REG_RAX, REG_RDX, REG_RCX, REG_RBX, REG_RSI, REG_RDI, REG_RBP, REG_RSP,
+               REG_R8, REG_R9, REG_R10, REG_R11, REG_R12, REG_R13, REG_R14, REG_R15, REG_RIP};
+    [
+        (X86_64::RAX, REG_RAX), (X86_64::RDX, REG_RDX), (X86_64::RCX, REG_RCX), (X86_64::RBX, REG_RBX),
+        (X86_64::RSI, REG_RSI), (X86_64::RDI, REG_RDI), (X86_64::RBP, REG_RBP), (X86_64::RSP, REG_RSP),
+        (X86_64::R8, REG_R8), (X86_64::R9, REG_R9), (X86_64::R10, REG_R10), (X86_64::R11, REG_R11),
+        (X86_64::R12, REG_R12), (X86_64::R13, REG_R13), (X86_64::R14, REG_R14), (X86_64::R15, REG_R15),
+        (X86_64::RA, REG_RIP),
+    ]
+};
+
+/// The addresses at which the `ucontext_t` at `uc` keeps the general
+/// purpose registers, indexed by DWARF number.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub(crate) unsafe fn ucontext_gregs(uc: u64) -> [u64; 17] {
+    let gregs = &(*(uc as *const ::libc::ucontext_t)).uc_mcontext.gregs;
+    let mut slots = [0; 17];
+    for &(reg, i) in &UCONTEXT_GREGS {
+        slots[reg.0 as usize] = &gregs[i as usize] as *const _ as u64;
+    }
+    slots
+}
+
+enum Slot {
+    Scalar(usize),
+    Wide(usize),
+}
+
+/// Fails with `Unknown` unless `reg` names an x86-64 register.
+pub(crate) fn check(reg: gimli::Register) -> Result<(), RegisterError> {
+    slot(reg.0).map(|_| ())
+}
+
+/// Every register a callee may clobber: the general purpose ones listed in
+/// `CALLER_SAVED`, every vector, x87, MMX and mask register, RFLAGS and the
+/// x87 status word. MXCSR and the x87 control word are callee-saved.
+pub(crate) fn caller_saved() -> impl Iterator<Item = u16> {
+    CALLER_SAVED.iter().map(|reg| reg.0)
+        .chain(XMM0..MM0 + 8)
+        .chain(Some(RFLAGS))
+        .chain(Some(FSW))
+        .chain(XMM16..XMM16 + 16)
+        .chain(K0..K0 + 8)
+}
+
+/// The numbers of the 64-bit registers, in the order `Registers` keeps them.
+fn scalars() -> impl Iterator<Item = u16> {
+    (0..=16).chain(MM0..=GS).chain(FS_BASE..=GS_BASE).chain(TR..=FSW).chain(K0..K0 + 8)
+}
+
+// The numbers in between are reserved, so the registers are packed.
+fn slot(reg: u16) -> Result<Slot, RegisterError> {
+    match reg {
+        0..=16 => Ok(Slot::Scalar(reg as usize)),
+        41..=55 => Ok(Slot::Scalar(reg as usize - 24)),
+        58 | 59 => Ok(Slot::Scalar(reg as usize - 26)),
+        62..=66 => Ok(Slot::Scalar(reg as usize - 28)),
+        118..=125 => Ok(Slot::Scalar(reg as usize - 79)),
+        17..=40 => Ok(Slot::Wide((reg - XMM0) as usize)),
+        67..=82 => Ok(Slot::Wide((reg - XMM16) as usize + 24)),
+        _ => Err(RegisterError::Unknown(reg)),
+    }
+}
+
+/// The register file of one frame, indexed by DWARF register number.
+///
+/// Indexing covers the 64-bit registers and panics for anything else; use
+/// `get`/`set` for checked access and `vector`/`set_vector` for the XMM
+/// and x87 registers.
+#[derive(Clone, PartialEq, Eq)]
 pub struct Registers {
-    registers: [Option<u64>; 17],
+    registers: [Option<u64>; SCALAR_COUNT],
+    /// The vector registers, 0 unless their bit in `wide_known` is set.
+    wide: [u128; WIDE_COUNT],
+    wide_known: u64,
+}
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers {
+            registers: [None; SCALAR_COUNT],
+            wide: [0; WIDE_COUNT],
+            wide_known: 0,
+        }
+    }
+}
+
+impl Registers {
+    /// Whether `reg` is one of the 128-bit registers (XMM0-31, ST0-7).
+    pub fn is_vector(reg: gimli::Register) -> bool {
+        matches!(slot(reg.0), Ok(Slot::Wide(_)))
+    }
+
+    pub fn get(&self, reg: gimli::Register) -> Result<Option<u64>, RegisterError> {
+        match slot(reg.0)? {
+            Slot::Scalar(i) => Ok(self.registers[i]),
+            Slot::Wide(_) => Err(RegisterError::WrongWidth(reg.0)),
+        }
+    }
+
+    pub fn set(&mut self, reg: gimli::Register, value: Option<u64>) -> Result<(), RegisterError> {
+        match slot(reg.0)? {
+            Slot::Scalar(i) => {
+                self.registers[i] = value;
+                Ok(())
+            }
+            Slot::Wide(_) => Err(RegisterError::WrongWidth(reg.0)),
+        }
+    }
+
+    pub fn vector(&self, reg: gimli::Register) -> Result<Option<u128>, RegisterError> {
+        match slot(reg.0)? {
+            Slot::Wide(i) if self.wide_known & 1 << i != 0 => Ok(Some(self.wide[i])),
+            Slot::Wide(_) => Ok(None),
+            Slot::Scalar(_) => Err(RegisterError::WrongWidth(reg.0)),
+        }
+    }
+
+    pub fn set_vector(&mut self, reg: gimli::Register, value: Option<u128>) -> Result<(), RegisterError> {
+        match slot(reg.0)? {
+            Slot::Wide(i) => {
+                self.set_wide(i, value);
+                Ok(())
+            }
+            Slot::Scalar(_) => Err(RegisterError::WrongWidth(reg.0)),
+        }
+    }
+
+    fn set_wide(&mut self, i: usize, value: Option<u128>) {
+        match value {
+            Some(value) => {
+                self.wide[i] = value;
+                self.wide_known |= 1 << i;
+            }
+            None => {
+                self.wide[i] = 0;
+                self.wide_known &= !(1 << i);
+            }
+        }
+    }
+
+    /// Forgets the values of caller-saved registers: after a call returns,
+    /// they no longer hold what the caller put there.
+    pub(crate) fn clobber_caller_saved(&mut self) {
+        for reg in caller_saved() {
+            match slot(reg) {
+                Ok(Slot::Scalar(i)) => self.registers[i] = None,
+                Ok(Slot::Wide(i)) => self.set_wide(i, None),
+                Err(_) => unreachable!(),
+            }
+        }
+    }
+
+    /// Reads the registers saved in a signal handler's `ucontext_t`.
+    ///
+    /// The interrupted instruction pointer ends up in the return address
+    /// column. FS and GS bases, TR, LDTR and the mask registers are not part
+    /// of the context and stay unknown.
+    ///
+    /// # Safety
+    ///
+    /// `uc` must point to a valid `ucontext_t`, such as the one passed to a
+    /// `SA_SIGINFO` signal handler. If its `fpregs` pointer is not null, it
+    /// must point to valid FP state as well.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub unsafe fn from_ucontext(uc: *const ::libc::ucontext_t) -> Registers {
+        use libc::{c_ulong, REG_EFL, REG_CSGSFS};
+        const UC_SIGCONTEXT_SS: c_ulong = 2;
+
+        let mut regs = Registers::default();
+        let gregs = &(*uc).uc_mcontext.gregs;
+        for &(reg, i) in &UCONTEXT_GREGS {
+            regs[reg] = Some(gregs[i as usize] as u64);
+        }
+        regs[RFLAGS] = Some(gregs[REG_EFL as usize] as u64);
+
+        // CS, GS, FS and (if the kernel says so) SS, 16 bits each.
+        let csgsfs = gregs[REG_CSGSFS as usize] as u64;
+        regs[CS] = Some(csgsfs & 0xffff);
+        regs[GS] = Some((csgsfs >> 16) & 0xffff);
+        regs[FS] = Some((csgsfs >> 32) & 0xffff);
+        if (*uc).uc_flags & UC_SIGCONTEXT_SS != 0 {
+            regs[SS] = Some(csgsfs >> 48);
+        }
+
+        let fp = (*uc).uc_mcontext.fpregs;
+        if !fp.is_null() {
+            let fp = &*fp;
+            regs[MXCSR] = Some(fp.mxcsr as u64);
+            regs[FCW] = Some(fp.cwd as u64);
+            regs[FSW] = Some(fp.swd as u64);
+
+            for (i, xmm) in fp._xmm.iter().enumerate() {
+                let value = xmm.element.iter().rev().fold(0u128, |acc, &e| acc << 32 | e as u128);
+                regs.set_wide(i, Some(value));
+            }
+
+            // The x87 registers are saved in stack order, while MMn aliases
+            // the mantissa of physical register n.
+            let top = (fp.swd as usize >> 11) & 7;
+            for (i, st) in fp._st.iter().enumerate() {
+                let mantissa = st.significand.iter().rev().fold(0u64, |acc, &s| acc << 16 | s as u64);
+                regs.set_wide((ST0 - XMM0) as usize + i, Some((st.exponent as u128) << 64 | mantissa as u128));
+                regs[MM0 + ((top + i) & 7) as u16] = Some(mantissa);
+            }
+        }
+
+        regs
+    }
 }
 
 impl Debug for Registers {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        for reg in &self.registers {
+        for reg in &self.registers[..17] {
             match *reg {
                 None => write!(fmt, " XXX")?,
                 Some(x) => write!(fmt, " 0x{:x}", x)?,
             }
         }
+        for (reg, value) in scalars().zip(&self.registers).skip(17) {
+            if let Some(x) = *value {
+                write!(fmt, " r{}=0x{:x}", reg, x)?;
+            }
+        }
+        for reg in 0..WIDE_COUNT as u16 {
+            let reg = if reg < 24 { XMM0 + reg } else { XMM16 + reg - 24 };
+            if let Ok(Some(x)) = self.vector(gimli::Register(reg)) {
+                write!(fmt, " r{}=0x{:x}", reg, x)?;
+            }
+        }
         Ok(())
     }
 }
@@ -23,13 +297,19 @@ impl Index<u16> for Registers {
     type Output = Option<u64>;
 
     fn index(&self, index: u16) -> &Option<u64> {
-        &self.registers[index as usize]
+        match slot(index) {
+            Ok(Slot::Scalar(i)) => &self.registers[i],
+            _ => panic!("register {} is not a 64-bit register", index),
+        }
     }
 }
 
 impl IndexMut<u16> for Registers {
     fn index_mut(&mut self, index: u16) -> &mut Option<u64> {
-        &mut self.registers[index as usize]
+        match slot(index) {
+            Ok(Slot::Scalar(i)) => &mut self.registers[i],
+            _ => panic!("register {} is not a 64-bit register", index),
+        }
     }
 }
 
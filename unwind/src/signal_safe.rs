@@ -0,0 +1,276 @@
+//! Unwinding from signal handlers.
+//!
+//! `SignalSafeUnwinder` allocates everything it needs in `new`. Its `trace`
+//! and `trace_from_ucontext` then only read the module index and the stack:
+//! they do not allocate, take locks, log or panic, so they may be called
+//! from a signal handler, even one that interrupted `malloc`, or from a
+//! `GlobalAlloc` in the middle of an allocation.
+//!
+//! The module index has to be built before the first signal arrives, e.g.
+//! by passing `ModuleIndex::global()` to `new` during startup. Objects
+//! loaded after that are not seen.
+//!
+//! Only return addresses are collected. When a frame cannot be unwound the
+//! trace simply ends there.
+//!
+//! Without `pthread_getattr_np`, which is not async-signal-safe, the bounds
+//! of the interrupted thread's stack are unknown, so stack reads are checked
+//! against the index's memory map and the thread's alternate signal stack.
+//! Stacks of threads started after the index was built are only readable
+//! with `set_process_vm_readv(true)`.
+//!
+//! After `ModuleIndex::compile_compact_tables`, frames are looked up in the
+//! compact tables, and only those whose rows have no rule evaluate CFI.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use gimli::{UninitializedUnwindContext, RegisterRule, X86_64};
+
+use glue;
+use memory::Memory;
+use stacks::{self, ThreadStacks};
+use registers::{self, Registers};
+use super::{AddrRange, ModuleIndex, StaticReader, UnwindError, UnwindRule, cfa, fallback, signal_context, is_internal_frame};
+
+/// The general purpose registers and the return address column: all that
+/// the CFA and return address of a frame depend on in practice.
+pub(crate) type Gprs = [Option<u64>; 17];
+
+pub(crate) fn gprs(registers: &Registers) -> Gprs {
+    let mut gprs = [None; 17];
+    for (i, reg) in gprs.iter_mut().enumerate() {
+        *reg = registers[i as u16];
+    }
+    gprs
+}
+
+/// Walks the stack computing only return addresses, without allocating,
+/// logging or panicking.
+pub(crate) struct IpWalker<'a> {
+    index: &'a ModuleIndex,
+    ctx: &'a mut UninitializedUnwindContext<StaticReader>,
+    memory: Memory<'a>,
+    regs: Gprs,
+    interrupted: bool,
+    first: bool,
+}
+
+impl<'a> IpWalker<'a> {
+    pub(crate) fn new(index: &'a ModuleIndex, ctx: &'a mut UninitializedUnwindContext<StaticReader>,
+                      memory: Memory<'a>, regs: Gprs, interrupted: bool) -> IpWalker<'a> {
+        IpWalker { index, ctx, memory, regs, interrupted, first: true }
+    }
+
+    /// Returns the return address of the current frame and the range of its
+    /// FDE (`None` if there is no CFI for it), and moves to the caller.
+    pub(crate) fn next(&mut self) -> Result<Option<(u64, Option<AddrRange>)>, UnwindError> {
+        let ra = match self.regs[X86_64::RA.0 as usize] {
+            Some(ra) => ra,
+            None => return Ok(None),
+        };
+        let lookup = if self.interrupted { ra } else { ra.wrapping_sub(1) };
+        let rec = self.index.object_for(lookup).ok_or(gimli::Error::NoUnwindInfoForAddress)?;
+
+        let regs = &self.regs;
+        let memory = &self.memory;
+        let get = |reg: usize| regs.get(reg).cloned().unwrap_or(None);
+        let mut caller = *regs;
+        for reg in registers::caller_saved().filter(|&reg| reg < 17) {
+            caller[reg as usize] = None;
+        }
+        caller[X86_64::RA.0 as usize] = None;
+
+        // Only tables compiled already, as compiling one allocates.
+        let compact = rec.compact.peek().and_then(|table| table.lookup(lookup))
+            .and_then(|row| Some((row, row.function()?, row.rule()?)));
+        if let Some((row, function, rule)) = compact {
+            let cfa = get(rule.cfa_register.0 as usize)
+                .ok_or(UnwindError::UndefinedRegister(rule.cfa_register.0))?
+                .wrapping_add(rule.cfa_offset as u64);
+            for reg in row.clobbered() {
+                caller[reg as usize] = None;
+            }
+            if let Some(n) = rule.rbp {
+                caller[X86_64::RBP.0 as usize] = Some(memory.read_u64(cfa.wrapping_add(n as u64))?);
+            }
+            if let Some(n) = rule.return_address {
+                caller[X86_64::RA.0 as usize] = Some(memory.read_u64(cfa.wrapping_add(n as u64))?);
+            }
+            caller[X86_64::RSP.0 as usize] = Some(cfa);
+            self.regs = caller;
+            self.interrupted = false;
+            self.first = false;
+            return Ok(Some((ra, Some(function))));
+        }
+
+        let (fde_range, signal_frame) = match rec.unwind_info_for_address(self.ctx, lookup) {
+            Ok(info) => {
+                if info.signal_frame {
+                    let (rule, cfa) = signal_context(get(X86_64::RSP.0 as usize), info.row, memory)?;
+                    apply(rule, cfa, memory, &mut caller)?;
+                } else {
+                    let cfa = cfa(&info.row, |reg| Ok(get(reg.0 as usize)))?;
+                    for &(reg, ref rule) in info.row.registers() {
+                        let reg = reg.0 as usize;
+                        if reg >= caller.len() || reg == X86_64::RSP.0 as usize {
+                            continue;
+                        }
+                        caller[reg] = match *rule {
+                            RegisterRule::Undefined | RegisterRule::Architectural => None,
+                            RegisterRule::SameValue => get(reg),
+                            RegisterRule::Register(r) => get(r.0 as usize),
+                            RegisterRule::Offset(n) => Some(memory.read_u64(cfa.wrapping_add(n as u64))?),
+                            RegisterRule::ValOffset(n) => Some(cfa.wrapping_add(n as u64)),
+                            RegisterRule::Expression(_) | RegisterRule::ValExpression(_) =>
+                                return Err(UnwindError::UnsupportedExpression),
+                        };
+                    }
+                    caller[X86_64::RSP.0 as usize] = Some(cfa);
+                }
+                (Some(AddrRange { start: info.initial_address, end: info.initial_address + info.len }), info.signal_frame)
+            }
+            Err(gimli::Error::NoUnwindInfoForAddress) => {
+                let sp = get(X86_64::RSP.0 as usize);
+                let bp = get(X86_64::RBP.0 as usize);
+                let (rule, cfa, _) = fallback(sp, bp, self.first || self.interrupted)?;
+                apply(rule, cfa, memory, &mut caller)?;
+                (None, false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        self.regs = caller;
+        self.interrupted = signal_frame;
+        self.first = false;
+        Ok(Some((ra, fde_range)))
+    }
+}
+
+impl<'a> IpWalker<'a> {
+    /// Writes return addresses into `ips` until it is full or the walk ends,
+    /// and returns how many were written.
+    /// While `hide_internal` is set, leading frames for which
+    /// `is_internal_frame` holds with it as the wrapper are left out.
+    pub(crate) fn collect(mut self, mut hide_internal: Option<u64>, ips: &mut [u64]) -> usize {
+        let mut written = 0;
+        while written < ips.len() {
+            match self.next() {
+                Ok(Some((_, fde_range))) if hide_internal.is_some_and(|wrapper| is_internal_frame(fde_range, wrapper)) => continue,
+                Ok(Some((ip, _))) => {
+                    hide_internal = None;
+                    ips[written] = ip;
+                    written += 1;
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+        written
+    }
+}
+
+fn apply(rule: UnwindRule, cfa: u64, memory: &Memory, caller: &mut Gprs) -> Result<(), UnwindError> {
+    match rule {
+        // `signal_context` and `fallback` never ask for the CFI or compact
+        // rules.
+        UnwindRule::Cfi(_) | UnwindRule::Compact(_) => (),
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        UnwindRule::SignalContext(uc) => {
+            for (reg, &slot) in unsafe { registers::ucontext_gregs(uc) }.iter().enumerate() {
+                caller[reg] = Some(memory.read_u64(slot)?);
+            }
+        }
+        UnwindRule::FramePointer => {
+            caller[X86_64::RBP.0 as usize] = Some(memory.read_u64(cfa.wrapping_sub(16))?);
+            caller[X86_64::RA.0 as usize] = Some(memory.read_u64(cfa.wrapping_sub(8))?);
+        }
+        UnwindRule::Heuristic => {
+            caller[X86_64::RA.0 as usize] = Some(memory.read_u64(cfa.wrapping_sub(8))?);
+        }
+    }
+    caller[X86_64::RSP.0 as usize] = Some(cfa);
+    Ok(())
+}
+
+struct Slot {
+    busy: AtomicBool,
+    ctx: UnsafeCell<UninitializedUnwindContext<StaticReader>>,
+}
+
+/// An unwinder that can be shared by signal handlers on all threads.
+pub struct SignalSafeUnwinder {
+    index: Arc<ModuleIndex>,
+    slots: Vec<Slot>,
+    process_vm_readv: bool,
+}
+
+// A slot's context is only touched by whoever set its `busy` flag.
+unsafe impl Sync for SignalSafeUnwinder {}
+
+impl SignalSafeUnwinder {
+    /// Creates an unwinder for up to `concurrency` simultaneous traces:
+    /// threads tracing at the same time plus nested signal handlers.
+    /// Traces beyond that find no free scratch space and return no frames.
+    pub fn new(index: Arc<ModuleIndex>, concurrency: usize) -> SignalSafeUnwinder {
+        let slots = (0..concurrency).map(|_| Slot {
+            busy: AtomicBool::new(false),
+            ctx: UnsafeCell::new(UninitializedUnwindContext::new()),
+        }).collect();
+        SignalSafeUnwinder { index, slots, process_vm_readv: false }
+    }
+
+    /// Reads memory outside the index's memory map with `process_vm_readv`
+    /// (which is async-signal-safe) instead of ending the trace there.
+    pub fn set_process_vm_readv(&mut self, enabled: bool) {
+        self.process_vm_readv = enabled;
+    }
+
+    /// Writes the return addresses of the caller and its callers into `ips`
+    /// and returns how many were written.
+    #[inline(never)]
+    pub fn trace(&self, ips: &mut [u64]) -> usize {
+        let mut written = 0;
+        glue::registers_dyn(&mut |registers| written = self.walk(gprs(&registers), false, Some(0), ips));
+        written
+    }
+
+    /// Like `trace`, but starts at the code interrupted by a signal, whose
+    /// registers the kernel passed to the handler in `uc`. The first address
+    /// is the interrupted instruction rather than a return address.
+    ///
+    /// # Safety
+    ///
+    /// `uc` must point to a valid `ucontext_t`, such as the third argument
+    /// of a `SA_SIGINFO` handler, for the duration of the call.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub unsafe fn trace_from_ucontext(&self, uc: *const ::libc::ucontext_t, ips: &mut [u64]) -> usize {
+        let mut regs = [None; 17];
+        for (reg, &slot) in registers::ucontext_gregs(uc as u64).iter().enumerate() {
+            regs[reg] = Some(*(slot as *const u64));
+        }
+        self.walk(regs, true, None, ips)
+    }
+
+    fn walk(&self, regs: Gprs, interrupted: bool, hide_internal: Option<u64>, ips: &mut [u64]) -> usize {
+        let slot = match self.slots.iter().find(|slot| {
+            slot.busy.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        }) {
+            Some(slot) => slot,
+            None => return 0,
+        };
+
+        let stacks = ThreadStacks::new(None, stacks::current_altstack());
+        let memory = Memory {
+            stacks: stacks.regions(),
+            snapshot: None,
+            map: self.index.memory_map(),
+            process_vm_readv: self.process_vm_readv,
+        };
+        let walker = IpWalker::new(&self.index, unsafe { &mut *slot.ctx.get() }, memory, regs, interrupted);
+        let written = walker.collect(hide_internal, ips);
+
+        slot.busy.store(false, Ordering::Release);
+        written
+    }
+}
@@ -0,0 +1,81 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use registers::Registers;
+
+/// The registers and a copy of the top of the stack of interrupted code,
+/// for unwinding it later.
+///
+/// Taking a snapshot in a signal handler is cheap and cannot fail halfway
+/// through a walk; `UnwindCursor::from_snapshot` then walks it on a normal
+/// thread, reading stack slots from the copy and CFI from the live modules.
+/// The walk ends with `UnwindError::InvalidRead` where the copy ends.
+///
+/// Like a signal context, the return address column holds the address of
+/// the interrupted instruction.
+#[derive(Clone)]
+pub struct StackSnapshot {
+    registers: Registers,
+    base: u64,
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl StackSnapshot {
+    /// A snapshot of `bytes` of stack starting at `base`, usually the stack
+    /// pointer in `registers`.
+    pub fn new(registers: Registers, base: u64, bytes: Vec<u8>) -> StackSnapshot {
+        let len = bytes.len();
+        StackSnapshot { registers, base, bytes, len }
+    }
+
+    /// An empty snapshot with room for `size` bytes of stack, to be filled
+    /// in a signal handler.
+    pub fn with_capacity(size: usize) -> StackSnapshot {
+        StackSnapshot { registers: Registers::default(), base: 0, bytes: vec![0; size], len: 0 }
+    }
+
+    /// Takes the registers from `uc`, and copies as much of the stack above
+    /// its stack pointer as fits, or as is mapped. This does not allocate,
+    /// so it may be called from a signal handler.
+    ///
+    /// # Safety
+    ///
+    /// `uc` must point to a valid `ucontext_t`, such as the third argument
+    /// of a `SA_SIGINFO` handler. The stack is read with a system call, so
+    /// its stack pointer may be garbage.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub unsafe fn capture_from_ucontext(&mut self, uc: *const ::libc::ucontext_t) {
+        self.registers = Registers::from_ucontext(uc);
+        self.base = self.registers[::gimli::X86_64::RSP].unwrap_or(0);
+        self.len = copy_stack(self.base, &mut self.bytes);
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    /// The address the first byte was copied from.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Copies from `base` up to the first unreadable page.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn copy_stack(base: u64, buf: &mut [u8]) -> usize {
+    let mut copied = 0;
+    while copied < buf.len() {
+        let addr = base + copied as u64;
+        let page_end = (addr | 0xfff) + 1;
+        let n = ::core::cmp::min(buf.len() - copied, (page_end - addr) as usize);
+        if !::memory::read_via_syscall(addr, buf[copied..].as_mut_ptr(), n) {
+            break;
+        }
+        copied += n;
+    }
+    copied
+}
@@ -0,0 +1,106 @@
+//! Reuse of the previous walk on the same thread.
+//!
+//! Consecutive walks from a sampling loop share most of their frames. Once
+//! a walk reaches a frame with the CFA and return address of a frame of the
+//! last walk, the callers of that frame are most likely the same as last
+//! time. That is checked cheaply, with one stack read per frame instead of
+//! a CFI lookup: each cached return address must still be in its slot just
+//! below the CFA of the frame it returns to. If they all are, the rest of
+//! the last walk is replayed.
+
+use core::cell::RefCell;
+use core::mem;
+use std::vec::Vec;
+
+use memory::Memory;
+use StackFrame;
+
+// The last walk on this thread that ran to the end of the stack.
+std::thread_local!(static LAST_WALK: RefCell<Vec<StackFrame>> = const { RefCell::new(Vec::new()) });
+
+pub(crate) struct StackCache {
+    /// The last walk, by increasing CFA.
+    previous: Vec<StackFrame>,
+    /// The frames of this walk unwound so far.
+    walk: Vec<StackFrame>,
+    /// The next frame of `previous` to replay, once the walk joined it.
+    replay: Option<usize>,
+    joined_at: usize,
+    finished: bool,
+}
+
+impl StackCache {
+    pub(crate) fn load() -> StackCache {
+        StackCache {
+            previous: LAST_WALK.try_with(|last| last.replace(Vec::new())).unwrap_or_default(),
+            walk: Vec::new(),
+            replay: None,
+            joined_at: 0,
+            finished: false,
+        }
+    }
+
+    pub(crate) fn replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Frames so far, unwound or replayed.
+    pub(crate) fn depth(&self) -> usize {
+        self.walk.len() + self.replay.map_or(0, |next| next - self.joined_at)
+    }
+
+    /// The next replayed frame, or `None` at the end of the stack.
+    pub(crate) fn replay(&mut self) -> Option<StackFrame> {
+        let next = self.replay?;
+        let frame = self.previous.get(next)?.clone();
+        self.replay = Some(next + 1);
+        Some(frame)
+    }
+
+    /// Records a frame unwound by this walk, and joins the last walk if
+    /// the frame and all its callers are still as they were then.
+    pub(crate) fn unwound(&mut self, frame: &StackFrame, memory: &Memory) {
+        self.walk.push(frame.clone());
+        let k = match self.previous.binary_search_by_key(&frame.cfa, |cached| cached.cfa) {
+            Ok(k) if self.previous[k].return_address == frame.return_address => k,
+            _ => return,
+        };
+        for pair in self.previous[k..].windows(2) {
+            // Past a signal trampoline the return address comes from the
+            // saved context rather than the stack slot.
+            if pair[0].signal_frame || memory.read_u64(pair[0].cfa.wrapping_sub(8)).ok() != Some(pair[1].return_address) {
+                return;
+            }
+        }
+        self.replay = Some(k + 1);
+        self.joined_at = k + 1;
+    }
+
+    /// Keeps this walk for the next one on this thread. Call this only when
+    /// the walk reached the end of the stack.
+    pub(crate) fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        let mut walk = mem::take(&mut self.walk);
+        if self.replay.is_some() {
+            walk.extend(self.previous.drain(self.joined_at..));
+        }
+        self.previous.clear();
+        // Walks across stacks cannot be searched by CFA.
+        if walk.windows(2).all(|pair| pair[0].cfa < pair[1].cfa) {
+            let _ = LAST_WALK.try_with(|last| *last.borrow_mut() = walk);
+        }
+    }
+}
+
+impl Drop for StackCache {
+    fn drop(&mut self) {
+        // A walk that ended early leaves the last one in place.
+        if !self.previous.is_empty() {
+            let previous = mem::take(&mut self.previous);
+            let _ = LAST_WALK.try_with(|last| *last.borrow_mut() = previous);
+        }
+    }
+}
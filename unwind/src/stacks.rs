@@ -0,0 +1,140 @@
+//! The stacks a walk may run through.
+//!
+//! A thread does not always run on one stack: signal handlers may run on an
+//! alternate signal stack, and stackful coroutines each have their own. When
+//! the walk crosses from one to the next, the CFA jumps to an unrelated
+//! region, which is only fine if both regions are known stacks.
+
+use alloc::vec::Vec;
+use range::AddrRange;
+use registers::Registers;
+
+/// Known stack regions, and the switch points between them.
+///
+/// CFAs only have to grow while the walk stays within one region. A region
+/// registered with `add_switch` also says where to go once the walk leaves
+/// it or reaches its outermost frame: to the frame that switched to it.
+#[derive(Debug, Clone, Default)]
+pub struct Stacks {
+    regions: Vec<AddrRange>,
+    switches: Vec<(AddrRange, Registers)>,
+}
+
+impl Stacks {
+    pub fn new() -> Stacks {
+        Stacks::default()
+    }
+
+    /// The calling thread's stack and its alternate signal stack, if any.
+    pub fn current_thread() -> Stacks {
+        let mut stacks = Stacks::new();
+        stacks.regions.extend(current_thread_stack());
+        stacks.regions.extend(current_altstack());
+        stacks
+    }
+
+    /// Adds a stack the walk may move to.
+    pub fn add(&mut self, region: AddrRange) {
+        self.regions.push(region);
+    }
+
+    /// Adds a stack that was switched to from the frame `resumer` describes,
+    /// e.g. a coroutine stack and the registers its `resume` saved, taken
+    /// the way `UnwindCursor::new` expects them.
+    pub fn add_switch(&mut self, region: AddrRange, resumer: Registers) {
+        self.regions.push(region);
+        self.switches.push((region, resumer));
+    }
+
+    pub fn regions(&self) -> &[AddrRange] {
+        &self.regions
+    }
+
+    /// The known stack containing `addr`.
+    pub fn region_of(&self, addr: u64) -> Option<AddrRange> {
+        self.regions.iter().find(|region| region.contains(addr)).cloned()
+    }
+
+    /// Removes the switch point out of `region`, so each is taken only once.
+    pub(crate) fn take_switch(&mut self, region: AddrRange) -> Option<Registers> {
+        let i = self.switches.iter().position(|&(r, _)| r == region)?;
+        Some(self.switches.remove(i).1)
+    }
+}
+
+/// The stacks of the calling thread, for walks that must not allocate.
+pub(crate) struct ThreadStacks {
+    regions: [AddrRange; 2],
+    len: usize,
+}
+
+impl ThreadStacks {
+    pub(crate) fn new(stack: Option<AddrRange>, altstack: Option<AddrRange>) -> ThreadStacks {
+        let mut stacks = ThreadStacks { regions: [AddrRange { start: 0, end: 0 }; 2], len: 0 };
+        for region in stack.into_iter().chain(altstack) {
+            stacks.regions[stacks.len] = region;
+            stacks.len += 1;
+        }
+        stacks
+    }
+
+    pub(crate) fn regions(&self) -> &[AddrRange] {
+        &self.regions[..self.len]
+    }
+}
+
+/// The stack of the calling thread.
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub(crate) fn current_thread_stack() -> Option<AddrRange> {
+    use std::cell::Cell;
+
+    // For the main thread this parses /proc/self/maps, so only do it once.
+    std::thread_local!(static STACK: Cell<Option<Option<AddrRange>>> = const { Cell::new(None) });
+
+    STACK.with(|stack| {
+        if let Some(range) = stack.get() {
+            return range;
+        }
+        let range = unsafe {
+            let mut attr: ::libc::pthread_attr_t = core::mem::zeroed();
+            if ::libc::pthread_getattr_np(::libc::pthread_self(), &mut attr) != 0 {
+                return None;
+            }
+            let mut addr = core::ptr::null_mut();
+            let mut size = 0;
+            let ok = ::libc::pthread_attr_getstack(&attr, &mut addr, &mut size) == 0;
+            ::libc::pthread_attr_destroy(&mut attr);
+            if ok {
+                Some(AddrRange { start: addr as u64, end: addr as u64 + size as u64 })
+            } else {
+                None
+            }
+        };
+        stack.set(Some(range));
+        range
+    })
+}
+
+#[cfg(not(all(target_os = "linux", feature = "std")))]
+pub(crate) fn current_thread_stack() -> Option<AddrRange> {
+    None
+}
+
+/// The alternate signal stack of the calling thread, if one is set up.
+///
+/// This is a single system call, so it is async-signal-safe.
+#[cfg(target_os = "linux")]
+pub(crate) fn current_altstack() -> Option<AddrRange> {
+    unsafe {
+        let mut stack: ::libc::stack_t = core::mem::zeroed();
+        if ::libc::sigaltstack(core::ptr::null(), &mut stack) != 0 || stack.ss_flags & ::libc::SS_DISABLE != 0 {
+            return None;
+        }
+        Some(AddrRange { start: stack.ss_sp as u64, end: stack.ss_sp as u64 + stack.ss_size as u64 })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn current_altstack() -> Option<AddrRange> {
+    None
+}
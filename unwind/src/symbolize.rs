@@ -0,0 +1,24 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use find_cfi::Module;
+
+/// A function an address belongs to, and where in its source.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Symbol {
+    pub name: String,
+    /// The source file. Empty if unknown.
+    pub file: String,
+    /// The source line. Zero if unknown.
+    pub line: u32,
+}
+
+/// Turns code addresses into function names and source lines, e.g. with
+/// the `backtrace` or `addr2line` crates.
+///
+/// The exporters call this with lookup addresses: the sampled instruction
+/// for the innermost frame, and the return address minus one for callers.
+pub trait Symbolizer {
+    /// The functions at `address` in `module`, the innermost inlined one
+    /// first. Empty if unknown.
+    fn symbolize(&self, module: Option<&Module>, address: u64) -> Vec<Symbol>;
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "libunwind_shim")]
+
+extern crate unwind;
+extern crate fallible_iterator;
+extern crate unwind_fixtures;
+
+use std::cell::Cell;
+use std::panic::catch_unwind;
+use std::os::raw::c_void;
+use std::ptr;
+use fallible_iterator::FallibleIterator;
+use unwind::{Unwinder, DwarfUnwinder};
+
+extern "C" {
+    static mut unwind_args_size_cleanup_frame: *mut c_void;
+}
+
+extern "C-unwind" {
+    fn unwind_args_size_fixture();
+}
+
+thread_local! {
+    static THROW: Cell<bool> = const { Cell::new(false) };
+    static ARGS_SIZE: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+#[no_mangle]
+pub extern "C-unwind" fn unwind_args_size_callback(_a1: i64, _a2: i64, _a3: i64, _a4: i64,
+                                                   _a5: i64, _a6: i64, _a7: i64, _a8: i64) {
+    if THROW.with(|t| t.get()) {
+        // std raises the panic through the shim's _Unwind_RaiseException.
+        panic!("thrown through C");
+    }
+
+    DwarfUnwinder::default().trace(|frames| {
+        while let Some(frame) = frames.next().unwrap() {
+            if frame.initial_address() == unwind_args_size_fixture as *const () as u64 {
+                ARGS_SIZE.with(|s| s.set(Some(frame.args_size())));
+            }
+        }
+    });
+}
+
+#[inline(never)]
+fn run_fixture() {
+    unsafe { unwind_args_size_fixture() }
+}
+
+#[test]
+fn args_size() {
+    assert!(catch_unwind(run_fixture).is_ok());
+    assert_eq!(ARGS_SIZE.with(|s| s.get()), Some(16));
+    let expected = unsafe { unwind_args_size_cleanup_frame };
+    assert!(!expected.is_null());
+
+    unsafe { unwind_args_size_cleanup_frame = ptr::null_mut() };
+    THROW.with(|t| t.set(true));
+    let result = catch_unwind(run_fixture);
+    THROW.with(|t| t.set(false));
+
+    assert_eq!(*result.unwrap_err().downcast::<&str>().unwrap(), "thrown through C");
+    // The landing pad must run with the pushed arguments popped, exactly as
+    // the cleanup on the normal return path does.
+    assert_eq!(unsafe { unwind_args_size_cleanup_frame }, expected);
+}
@@ -0,0 +1,125 @@
+extern crate unwind;
+extern crate libc;
+
+use std::mem;
+use std::ptr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use unwind::DwarfUnwinder;
+use unwind::capture::{capture_all_threads, ThreadCapture};
+
+// Records the walk from here, then blocks until told to return.
+#[inline(never)]
+fn wait_here(started: mpsc::Sender<Vec<u64>>, stop: mpsc::Receiver<()>) {
+    let mut ips = [0u64; 64];
+    let n = DwarfUnwinder::default().trace_ips(&mut ips);
+    started.send(ips[..n].to_vec()).unwrap();
+    stop.recv().unwrap();
+}
+
+#[test]
+fn all_threads() {
+    let mut workers = Vec::new();
+    for i in 0..3 {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name(format!("capture-{}", i))
+            .spawn(move || wait_here(started_tx, stop_rx))
+            .unwrap();
+        workers.push((handle, started_rx.recv().unwrap(), stop_tx));
+    }
+
+    let stacks = capture_all_threads().unwrap();
+    let me = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+    assert!(stacks.iter().any(|stack| stack.tid() == me && !stack.ips().is_empty()));
+
+    for (_, own, _) in &workers {
+        // Below `wait_here`, the thread's stack is what it saw itself.
+        let callers = &own[1..];
+        assert!(stacks.iter().any(|stack| {
+            !stack.timed_out() && stack.name().starts_with("capture-") && stack.ips().ends_with(callers)
+        }), "no stack ends with {:x?}", callers);
+    }
+
+    for (handle, _, stop) in workers {
+        stop.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn blocked_signal_times_out() {
+    let capture = ThreadCapture { timeout: Duration::from_millis(100), ..ThreadCapture::default() };
+    let signal = capture.signal;
+    let (started_tx, started_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    let handle = thread::Builder::new().name("blocked".into()).spawn(move || {
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, signal);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        }
+        started_tx.send(()).unwrap();
+        stop_rx.recv().unwrap();
+    }).unwrap();
+    started_rx.recv().unwrap();
+
+    let stacks = capture.capture().unwrap();
+    let blocked = stacks.iter().find(|stack| stack.name() == "blocked").unwrap();
+    assert!(blocked.timed_out());
+    assert!(blocked.ips().is_empty());
+    assert!(stacks.iter().filter(|stack| stack.name() != "blocked").all(|stack| !stack.timed_out()));
+
+    stop_tx.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+static mut ORIGINAL_CALLS: usize = 0;
+
+extern "C" fn original(_sig: libc::c_int) {
+    unsafe { ORIGINAL_CALLS += 1 };
+}
+
+#[test]
+fn restores_the_original_handler_after_a_timeout() {
+    let capture = ThreadCapture {
+        signal: libc::SIGRTMAX() - 3,
+        timeout: Duration::from_millis(100),
+        ..ThreadCapture::default()
+    };
+    let signal = capture.signal;
+    let handler = |action: *const libc::sigaction| unsafe {
+        let mut old: libc::sigaction = mem::zeroed();
+        libc::sigaction(signal, action, &mut old);
+        old.sa_sigaction
+    };
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = original as *const () as usize;
+    handler(&action);
+
+    let (started_tx, started_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    let blocked = thread::spawn(move || {
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, signal);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        }
+        started_tx.send(()).unwrap();
+        stop_rx.recv().unwrap();
+    });
+    started_rx.recv().unwrap();
+    assert!(capture.capture().unwrap().iter().any(|stack| stack.timed_out()));
+    assert_ne!(handler(ptr::null()), original as *const () as usize);
+
+    // Another capture while our handler is still installed.
+    stop_tx.send(()).unwrap();
+    blocked.join().unwrap();
+    assert!(capture.capture().unwrap().iter().all(|stack| !stack.timed_out()));
+    assert_eq!(handler(ptr::null()), original as *const () as usize);
+    assert_eq!(unsafe { ORIGINAL_CALLS }, 0);
+}
@@ -0,0 +1,93 @@
+//! Helpers shared by the test binaries, each of which uses only some.
+
+#![allow(dead_code)]
+
+use backtrace;
+use libc;
+use std::hint::black_box;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use unwind::{AddrRange, DwarfUnwinder, Module, ModuleIndex, Registers, StackSnapshot, Symbol, Symbolizer, UnwindCursor};
+use unwind::signal_safe::SignalSafeUnwinder;
+
+static SNAPSHOT: AtomicPtr<StackSnapshot> = AtomicPtr::new(ptr::null_mut());
+static UNWINDER: AtomicPtr<SignalSafeUnwinder> = AtomicPtr::new(ptr::null_mut());
+static mut LIVE: [u64; 64] = [0; 64];
+static LIVE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_sigusr1(_sig: libc::c_int, _info: *mut libc::siginfo_t, uc: *mut libc::c_void) {
+    unsafe {
+        (*SNAPSHOT.load(Ordering::Acquire)).capture_from_ucontext(uc as *const libc::ucontext_t);
+        let live = &mut *ptr::addr_of_mut!(LIVE);
+        let n = (*UNWINDER.load(Ordering::Acquire)).trace_from_ucontext(uc as *const libc::ucontext_t, live);
+        LIVE_LEN.store(n, Ordering::Release);
+    }
+}
+
+/// Copies up to `capacity` bytes of this thread's stack when `raise` raises
+/// SIGUSR1, and unwinds it live for comparison.
+pub fn sample<F: FnOnce()>(capacity: usize, raise: F) -> (StackSnapshot, Vec<u64>) {
+    if UNWINDER.load(Ordering::Acquire).is_null() {
+        let mut unwinder = SignalSafeUnwinder::new(ModuleIndex::global(), 1);
+        unwinder.set_process_vm_readv(true);
+        UNWINDER.store(Box::into_raw(Box::new(unwinder)), Ordering::Release);
+    }
+    let snapshot = Box::into_raw(Box::new(StackSnapshot::with_capacity(capacity)));
+    SNAPSHOT.store(snapshot, Ordering::Release);
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sigusr1 as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        assert_eq!(libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()), 0);
+    }
+    raise();
+    SNAPSHOT.store(ptr::null_mut(), Ordering::Release);
+    let live: &[u64; 64] = unsafe { &*ptr::addr_of!(LIVE) };
+    let live = live[..LIVE_LEN.load(Ordering::Acquire)].to_vec();
+    (*unsafe { Box::from_raw(snapshot) }, live)
+}
+
+/// The range of the FDE covering `function`, found by unwinding a fake
+/// frame that was interrupted at its first instruction.
+pub fn fde_range(function: *const ()) -> AddrRange {
+    let stack = 0u64;
+    let mut registers = Registers::default();
+    // The stack pointer and the return address column.
+    registers[7] = Some(&stack as *const u64 as u64);
+    registers[16] = Some(function as u64);
+    let mut cursor = UnwindCursor::new_interrupted(registers);
+    let frame = cursor.step(&DwarfUnwinder::default()).unwrap().unwrap();
+    frame.fde_range().unwrap()
+}
+
+/// Names addresses with the `backtrace` crate.
+pub struct Backtrace;
+
+impl Symbolizer for Backtrace {
+    fn symbolize(&self, _module: Option<&Module>, address: u64) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+        backtrace::resolve(address as *mut _, |symbol| {
+            symbols.push(Symbol {
+                name: symbol.name().map(|name| name.to_string()).unwrap_or_default(),
+                file: symbol.filename().map(|file| file.display().to_string()).unwrap_or_default(),
+                line: symbol.lineno().unwrap_or(0),
+            });
+        });
+        symbols
+    }
+}
+
+#[inline(never)]
+pub fn sampled(unwinder: &mut DwarfUnwinder) -> Vec<u64> {
+    let mut ips = [0; 64];
+    let n = unwinder.trace_ips(&mut ips);
+    ips[..n].to_vec()
+}
+
+/// `sampled` from another caller, which stays on the stack rather than
+/// tail-calling it.
+#[inline(never)]
+pub fn sampled_elsewhere(unwinder: &mut DwarfUnwinder) -> Vec<u64> {
+    black_box(sampled(unwinder))
+}
@@ -0,0 +1,155 @@
+extern crate unwind;
+extern crate gimli;
+extern crate fallible_iterator;
+
+use std::arch::global_asm;
+use std::convert::TryInto;
+use std::hint::black_box;
+use std::sync::Arc;
+use fallible_iterator::FallibleIterator;
+use gimli::X86_64;
+use unwind::{Unwinder, DwarfUnwinder, ModuleIndex, CompactRule};
+
+// A function that sets up a frame pointer and saves RBX, and one whose CFA
+// is a DWARF expression, which the compact form cannot describe.
+global_asm!(
+    ".globl compact_framed",
+    "compact_framed:",
+    ".cfi_startproc",
+    "push rbp",
+    ".cfi_def_cfa_offset 16",
+    ".cfi_offset rbp, -16",
+    "mov rbp, rsp",
+    ".cfi_def_cfa_register rbp",
+    "push rbx",
+    ".cfi_offset rbx, -24",
+    "nop",
+    "pop rbx",
+    "pop rbp",
+    ".cfi_def_cfa rsp, 8",
+    "ret",
+    ".cfi_endproc",
+    ".globl compact_expression",
+    "compact_expression:",
+    ".cfi_startproc",
+    // DW_CFA_def_cfa_expression: DW_OP_breg7 (RSP) 8
+    ".cfi_escape 0x0f, 0x02, 0x77, 0x08",
+    "nop",
+    "ret",
+    ".cfi_endproc",
+);
+
+extern "C" {
+    fn compact_framed();
+    fn compact_expression();
+}
+
+fn rule(cfa_register: gimli::Register, cfa_offset: i32, rbp: Option<i16>) -> Option<CompactRule> {
+    Some(CompactRule { cfa_register, cfa_offset, rbp, return_address: Some(-8) })
+}
+
+#[test]
+fn rows_of_handwritten_cfi() {
+    let index = ModuleIndex::new();
+    let start = compact_framed as *const () as u64;
+    let table = index.compact_table(start).unwrap();
+    assert!(table.rows().windows(2).all(|pair| pair[0].address() < pair[1].address()));
+
+    let row = table.lookup(start).unwrap();
+    assert_eq!(row.address(), start);
+    assert_eq!(row.function().map(|f| f.start), Some(start));
+    assert_eq!(row.rule(), rule(X86_64::RSP, 8, None));
+    assert_eq!(table.lookup(start + 1).unwrap().rule(), rule(X86_64::RSP, 16, Some(-16)));
+    // Saving RBX changes nothing the table keeps.
+    assert_eq!(table.lookup(start + 4).unwrap().rule(), rule(X86_64::RBP, 16, Some(-16)));
+    assert_eq!(table.lookup(start + 6).unwrap().rule(), rule(X86_64::RBP, 16, Some(-16)));
+    assert_eq!(table.lookup(start + 8).unwrap().rule(), rule(X86_64::RSP, 8, Some(-16)));
+
+    let start = compact_expression as *const () as u64;
+    let row = table.lookup(start + 1).unwrap();
+    assert_eq!(row.function().map(|f| f.start), Some(start));
+    assert_eq!(row.rule(), None);
+}
+
+#[inline(never)]
+fn recurse(depth: usize, f: &mut dyn FnMut()) {
+    if depth == 0 {
+        f()
+    } else {
+        recurse(black_box(depth - 1), f);
+        black_box(());
+    }
+}
+
+#[inline(never)]
+fn frames(unwinder: &mut DwarfUnwinder) -> Vec<(u64, u64, u64)> {
+    let mut result = Vec::new();
+    unwinder.trace(|frames| {
+        result = frames.map(|frame| (frame.return_address(), frame.cfa(), frame.initial_address())).collect().unwrap();
+    });
+    result
+}
+
+#[test]
+fn same_frames_as_dwarf() {
+    let mut compact = DwarfUnwinder::default();
+    compact.set_compact_tables(true);
+    let mut unwinders = [DwarfUnwinder::default(), compact];
+    recurse(20, &mut || {
+        // From one call site, see `tests/stack_cache.rs`.
+        let walks: Vec<_> = black_box(&[0, 1][..]).iter().map(|&i| frames(&mut unwinders[i])).collect();
+        assert!(walks[0].len() > 20);
+        assert_eq!(walks[1], walks[0]);
+    });
+}
+
+#[inline(never)]
+fn ips(unwinder: &mut DwarfUnwinder) -> Vec<u64> {
+    let mut ips = [0; 64];
+    let n = unwinder.trace_ips(&mut ips);
+    ips[..n].to_vec()
+}
+
+#[test]
+fn compiled_tables_in_trace_ips() {
+    let index = Arc::new(ModuleIndex::new());
+    let mut unwinders = [DwarfUnwinder::new(index.clone()), DwarfUnwinder::new(index.clone())];
+    recurse(20, &mut || {
+        let walks: Vec<_> = black_box(&[0, 1][..]).iter().map(|&i| {
+            if i == 1 {
+                index.compile_compact_tables();
+            }
+            ips(&mut unwinders[i])
+        }).collect();
+        assert!(walks[0].len() > 20);
+        assert_eq!(walks[1], walks[0]);
+    });
+}
+
+#[test]
+fn written_table() {
+    let index = ModuleIndex::new();
+    let start = compact_framed as *const () as u64;
+    let table = index.compact_table(start).unwrap();
+    let mut file = Vec::new();
+    table.write(&mut file).unwrap();
+
+    let u32_at = |at: usize| u32::from_le_bytes(file[at..at + 4].try_into().unwrap());
+    assert_eq!(&file[..8], b"UNWCOMPT");
+    assert_eq!(u32_at(8), 1);
+    assert_eq!(u32_at(12) as usize, table.rows().len());
+    assert_eq!(file.len(), 16 + 24 * table.rows().len());
+
+    let base = table.module().base();
+    let entries: Vec<&[u8]> = file[16..].chunks(24).collect();
+    for (entry, row) in entries.iter().zip(table.rows()) {
+        assert_eq!(u64::from_le_bytes(entry[..8].try_into().unwrap()), row.address() - base);
+        assert_eq!(entry[16] == 0xff, row.rule().is_none());
+    }
+    let framed = table.rows().iter().position(|row| row.address() == start + 4).unwrap();
+    let entry = entries[framed];
+    assert_eq!(i32::from_le_bytes(entry[8..12].try_into().unwrap()), 16);
+    assert_eq!(i16::from_le_bytes(entry[12..14].try_into().unwrap()), -16);
+    assert_eq!(i16::from_le_bytes(entry[14..16].try_into().unwrap()), -8);
+    assert_eq!(&entry[16..19], &[6, 1, 1]);
+}
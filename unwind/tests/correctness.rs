@@ -1,8 +1,10 @@
 extern crate unwind;
 extern crate backtrace;
 extern crate fallible_iterator;
+extern crate gimli;
 
-use unwind::{Unwinder, DwarfUnwinder};
+use unwind::{Unwinder, DwarfUnwinder, FrameKind, SaveLocation, RegisterError};
+use gimli::X86_64;
 use fallible_iterator::FallibleIterator;
 
 #[test]
@@ -35,13 +37,11 @@ fn test_frame_3() {
     let mut our_trace = Vec::new();
 
     DwarfUnwinder::default().trace(|frames| {
-        // skip 3 (unwind-rs + test_frame_3)
-        frames.next().unwrap();
-        frames.next().unwrap();
-        frames.next().unwrap();
+        // test_frame_3 is at a different call site than in the reference trace
+        frames.skip_frames(1);
 
-        while let Some(_) = frames.next().unwrap() {
-            our_trace.push(frames.registers()[16].unwrap() - 1);
+        while let Some(frame) = frames.next().unwrap() {
+            our_trace.push(frame.return_address());
         }
     });
 
@@ -54,3 +54,123 @@ fn test_frame_3() {
     let ref_trace = &ref_trace[ref_trace_len - our_trace_len..][..our_trace_len];
     assert_eq!(our_trace, ref_trace);
 }
+
+#[test]
+fn panic_through_trampoline() {
+    let mut reached = false;
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        DwarfUnwinder::default().trace(|_| {
+            reached = true;
+            panic!("escaping the trampoline");
+        });
+    }));
+    assert!(reached);
+    assert!(result.is_err());
+}
+
+#[inline(never)]
+fn first_frame() -> u64 {
+    let mut first = 0;
+    DwarfUnwinder::default().trace(|frames| {
+        first = frames.next().unwrap().unwrap().initial_address();
+    });
+    first
+}
+
+#[inline(never)]
+fn wrapped_first_frame() -> u64 {
+    let mut first = 0;
+    DwarfUnwinder::default().trace(|frames| {
+        first = frames.skip_frames(1).next().unwrap().unwrap().initial_address();
+    });
+    first
+}
+
+#[test]
+fn starts_at_caller() {
+    assert_eq!(first_frame(), first_frame as *const () as u64);
+
+    // Skipping `wrapped_first_frame` lands in this test function.
+    let caller = starts_at_caller as *const () as u64;
+    let found = wrapped_first_frame();
+    assert!(found != wrapped_first_frame as *const () as u64);
+    assert_eq!(found, caller);
+}
+
+#[test]
+fn caller_saved_registers_are_unknown() {
+    DwarfUnwinder::default().trace(|frames| {
+        while frames.next().unwrap().is_some() {
+            let registers = frames.registers();
+            for &reg in &[X86_64::RAX, X86_64::RCX, X86_64::RDX, X86_64::RSI, X86_64::RDI,
+                          X86_64::R8, X86_64::R9, X86_64::R10, X86_64::R11] {
+                assert_eq!(registers[reg], None);
+            }
+            assert!(registers[X86_64::RBX].is_some());
+            assert!(registers[X86_64::RSP].is_some());
+        }
+    });
+}
+
+#[test]
+fn save_locations() {
+    DwarfUnwinder::default().trace(|frames| {
+        while frames.next().unwrap().is_some() {
+            let registers = frames.registers().clone();
+            for &reg in &[X86_64::RBX, X86_64::RBP, X86_64::R12, X86_64::RA] {
+                match frames.save_location(reg).unwrap() {
+                    SaveLocation::Memory(addr) => assert_eq!(Some(unsafe { *(addr as *const u64) }), registers[reg]),
+                    SaveLocation::Register(_) => (),
+                    location => panic!("{:?} of {:?}", location, reg),
+                }
+            }
+            assert_eq!(frames.save_location(X86_64::RSP), Ok(SaveLocation::Computed));
+            assert_eq!(frames.save_location(X86_64::RAX), Ok(SaveLocation::Undefined));
+            assert_eq!(frames.save_location(gimli::Register(100)), Err(RegisterError::Unknown(100)));
+        }
+    });
+}
+
+#[test]
+fn trace_ips() {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut ips = [0; 128];
+    let n = unwinder.trace_ips(&mut ips);
+
+    let mut expected = Vec::new();
+    unwinder.trace(|frames| {
+        while let Some(frame) = frames.next().unwrap() {
+            expected.push(frame.return_address());
+        }
+    });
+
+    // Called from different places in this function, and otherwise the same.
+    assert!(n > 1);
+    assert_eq!(&ips[1..n], &expected[1..]);
+    assert_eq!(unwinder.trace_ips(&mut ips[..2]), 2);
+}
+
+#[test]
+fn frame_details() {
+    DwarfUnwinder::default().trace(|frames| {
+        let frame = frames.next().unwrap().unwrap();
+        assert_eq!(frame.kind(), FrameKind::Cfi);
+        assert!(!frame.is_signal_frame());
+        assert_eq!(frame.lookup_address(), frame.return_address() - 1);
+        assert!(frame.cfa() > frames.registers()[7].unwrap());
+
+        let range = frame.fde_range().unwrap();
+        assert_eq!(range.start, frame_details as *const () as u64);
+        assert!(range.contains(frame.lookup_address()));
+
+        let module = frame.module().unwrap();
+        assert!(range.start >= module.base());
+        assert_eq!(std::path::Path::new(module.path()), std::env::current_exe().unwrap());
+
+        let mut last_cfa = frame.cfa();
+        while let Some(frame) = frames.next().unwrap() {
+            assert!(frame.cfa() > last_cfa);
+            last_cfa = frame.cfa();
+        }
+    });
+}
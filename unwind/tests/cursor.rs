@@ -0,0 +1,70 @@
+extern crate unwind;
+extern crate fallible_iterator;
+extern crate gimli;
+
+use fallible_iterator::FallibleIterator;
+use gimli::X86_64;
+use unwind::{Unwinder, DwarfUnwinder, UnwindCursor, UnwindError, Guard};
+
+fn walk(unwinder: &DwarfUnwinder, mut cursor: UnwindCursor) -> Vec<u64> {
+    let mut ips = Vec::new();
+    while let Some(frame) = cursor.step(unwinder).unwrap() {
+        ips.push(frame.return_address());
+    }
+    ips
+}
+
+// Returns a cursor positioned at our caller, and the frames above it.
+#[inline(never)]
+fn save_position(unwinder: &mut DwarfUnwinder) -> (UnwindCursor, u64, Vec<u64>) {
+    let mut saved = None;
+    unwinder.trace(|frames| {
+        let caller = frames.skip_frames(1).next().unwrap().unwrap();
+        let cursor = frames.cursor().clone();
+        let mut rest = Vec::new();
+        while let Some(frame) = frames.next().unwrap() {
+            rest.push(frame.return_address());
+        }
+        saved = Some((cursor, caller.return_address(), rest));
+    });
+    saved.unwrap()
+}
+
+#[test]
+fn resume_after_trace() {
+    let mut unwinder = DwarfUnwinder::default();
+    let (cursor, caller, rest) = save_position(&mut unwinder);
+    assert!(!rest.is_empty());
+
+    // The saved position continues where `trace` left off, any number of times.
+    assert_eq!(walk(&unwinder, cursor.clone()), rest);
+    assert_eq!(walk(&unwinder, cursor.clone()), rest);
+
+    // Rebuilding from the saved registers describes the caller frame again.
+    let rebuilt = walk(&unwinder, UnwindCursor::new(cursor.registers().clone()));
+    assert_eq!(rebuilt[0], caller);
+    assert_eq!(&rebuilt[1..], &rest[..]);
+}
+
+#[test]
+fn rebuild_from_state() {
+    let mut unwinder = DwarfUnwinder::default();
+    let (cursor, caller, rest) = save_position(&mut unwinder);
+
+    let state = cursor.state();
+    assert_eq!(state.registers(), cursor.registers());
+    assert_eq!(state.save_location(X86_64::RBX), cursor.save_location(X86_64::RBX));
+    let rebuilt = UnwindCursor::from_state(state.clone());
+    assert_eq!(rebuilt.save_location(X86_64::RBX), cursor.save_location(X86_64::RBX));
+    let ips = walk(&unwinder, rebuilt);
+    assert_eq!(ips[0], caller);
+    assert_eq!(&ips[1..], &rest[..]);
+
+    // The rebuilt walk counts the frames below it against the depth limit.
+    let mut guards = *unwinder.guards();
+    guards.max_depth = state.depth() + 1;
+    unwinder.set_guards(guards);
+    let mut rebuilt = UnwindCursor::from_state(state);
+    assert_eq!(rebuilt.step(&unwinder).unwrap().unwrap().return_address(), caller);
+    assert!(matches!(rebuilt.step(&unwinder), Err(UnwindError::Guard(Guard::MaxDepth))));
+}
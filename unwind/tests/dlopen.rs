@@ -0,0 +1,20 @@
+extern crate unwind;
+extern crate libc;
+
+use std::sync::Arc;
+use unwind::ModuleIndex;
+
+// In a test binary of its own, so that no other test sees the index change.
+#[test]
+fn global_index_sees_new_objects() {
+    let before = ModuleIndex::global();
+    assert!(Arc::ptr_eq(&before, &ModuleIndex::global()));
+    assert!(!before.modules().any(|m| m.path().contains("libresolv")));
+
+    let lib = unsafe { libc::dlopen(b"libresolv.so.2\0".as_ptr() as *const libc::c_char, libc::RTLD_NOW) };
+    assert!(!lib.is_null());
+    let after = ModuleIndex::global();
+    assert!(!Arc::ptr_eq(&before, &after));
+    assert!(after.modules().any(|m| m.path().contains("libresolv")));
+    assert!(Arc::ptr_eq(&after, &ModuleIndex::global()));
+}
@@ -0,0 +1,20 @@
+// Built with -mno-accumulate-outgoing-args, so the two stack arguments of the
+// callback are pushed right before the call and GCC records them with
+// DW_CFA_GNU_args_size. At -O0 the landing pad then calls the cleanup at the
+// same stack pointer as the normal path, which the test compares.
+
+extern void unwind_args_size_callback(long a1, long a2, long a3, long a4,
+                                      long a5, long a6, long a7, long a8);
+
+void *unwind_args_size_cleanup_frame;
+
+__attribute__((noinline))
+static void unwind_args_size_cleanup(int *guard) {
+    (void) guard;
+    unwind_args_size_cleanup_frame = __builtin_frame_address(0);
+}
+
+void unwind_args_size_fixture(void) {
+    int guard __attribute__((cleanup(unwind_args_size_cleanup))) = 0;
+    unwind_args_size_callback(1, 2, 3, 4, 5, 6, 7, 8);
+}
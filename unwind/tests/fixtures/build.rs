@@ -0,0 +1,10 @@
+extern crate cc;
+
+fn main() {
+    cc::Build::new()
+               .file("args_size.c")
+               .flag("-fexceptions")
+               .flag("-mno-accumulate-outgoing-args")
+               .opt_level(0)
+               .compile("unwind_fixtures");
+}
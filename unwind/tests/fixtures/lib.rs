@@ -0,0 +1,3 @@
+//! C code for the tests of `unwind`, built here so that the library itself
+//! does not need a C compiler. Tests link it with `extern crate`.
+#![no_std]
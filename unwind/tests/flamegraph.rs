@@ -0,0 +1,199 @@
+extern crate unwind;
+extern crate backtrace;
+extern crate libc;
+
+use std::collections::BTreeMap;
+use unwind::{DwarfUnwinder, Module, ModuleIndex, Symbol, Symbolizer};
+use unwind::flamegraph::Flamegraph;
+
+mod common;
+
+#[derive(Debug, PartialEq)]
+enum Json {
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> &Json {
+        match *self {
+            Json::Object(ref members) => &members[key],
+            _ => panic!("not an object"),
+        }
+    }
+
+    fn array(&self) -> &[Json] {
+        match *self {
+            Json::Array(ref items) => items,
+            _ => panic!("not an array"),
+        }
+    }
+
+    fn str(&self) -> &str {
+        match *self {
+            Json::String(ref s) => s,
+            _ => panic!("not a string"),
+        }
+    }
+
+    fn number(&self) -> f64 {
+        match *self {
+            Json::Number(n) => n,
+            _ => panic!("not a number"),
+        }
+    }
+}
+
+/// Parses the JSON the exporter writes: no literals, no whitespace.
+fn parse(s: &mut &str) -> Json {
+    let c = s.chars().next().unwrap();
+    *s = &s[c.len_utf8()..];
+    match c {
+        '{' | '[' => {
+            let mut members = BTreeMap::new();
+            let mut items = Vec::new();
+            let close = if c == '{' { '}' } else { ']' };
+            while !s.starts_with(close) {
+                if c == '{' {
+                    let key = match parse(s) {
+                        Json::String(key) => key,
+                        other => panic!("bad key {:?}", other),
+                    };
+                    assert!(s.starts_with(':'));
+                    *s = &s[1..];
+                    members.insert(key, parse(s));
+                } else {
+                    items.push(parse(s));
+                }
+                if s.starts_with(',') {
+                    *s = &s[1..];
+                }
+            }
+            *s = &s[1..];
+            if c == '{' { Json::Object(members) } else { Json::Array(items) }
+        }
+        '"' => {
+            let mut string = String::new();
+            let mut chars = s.char_indices();
+            loop {
+                match chars.next().unwrap() {
+                    (i, '"') => {
+                        *s = &s[i + 1..];
+                        return Json::String(string);
+                    }
+                    (_, '\\') => match chars.next().unwrap().1 {
+                        'u' => {
+                            let hex: String = (0..4).map(|_| chars.next().unwrap().1).collect();
+                            string.push(std::char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                        }
+                        c => string.push(c),
+                    },
+                    (_, c) => string.push(c),
+                }
+            }
+        }
+        _ => {
+            let end = s.find(|c: char| !c.is_ascii_digit() && c != '.' && c != '-').unwrap_or(s.len());
+            let number = format!("{}{}", c, &s[..end]).parse().unwrap();
+            *s = &s[end..];
+            Json::Number(number)
+        }
+    }
+}
+
+/// Knows nothing but one address, with a name to escape.
+struct OneName(u64);
+
+impl Symbolizer for OneName {
+    fn symbolize(&self, _module: Option<&Module>, address: u64) -> Vec<Symbol> {
+        if address != self.0 {
+            return Vec::new();
+        }
+        vec![
+            Symbol { name: "inlined<[u8; 4]>".into(), file: "src/\"quoted\".rs".into(), line: 2 },
+            Symbol { name: "outer".into(), file: String::new(), line: 1 },
+        ]
+    }
+}
+
+fn modules() -> Vec<Module> {
+    ModuleIndex::global().modules().cloned().collect()
+}
+
+#[test]
+fn folded_without_symbols() {
+    let mut unwinder = DwarfUnwinder::default();
+    let stack = common::sampled(&mut unwinder);
+    let stacks = vec![(stack.clone(), 2), (stack.clone(), 3), (common::sampled_elsewhere(&mut unwinder), 1)];
+    let mut out = Vec::new();
+    Flamegraph::default().write_folded(&stacks, &modules(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", out);
+    let merged = lines.iter().find(|line| line.ends_with(" 5")).unwrap();
+    assert_eq!(merged.matches(';').count(), stack.len() - 1);
+    let exe = std::env::current_exe().unwrap();
+    let leaf = merged.rsplit(';').next().unwrap();
+    assert!(leaf.starts_with(&format!("{}+0x", exe.file_name().unwrap().to_str().unwrap())), "{}", leaf);
+}
+
+#[test]
+fn folded_with_symbols() {
+    let mut unwinder = DwarfUnwinder::default();
+    let stacks = vec![(common::sampled(&mut unwinder), 1), (common::sampled_elsewhere(&mut unwinder), 1)];
+    let flamegraph = Flamegraph { symbolizer: Some(&common::Backtrace), ..Flamegraph::default() };
+    let mut out = Vec::new();
+    flamegraph.write_folded(&stacks, &modules(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    let elsewhere = out.lines().find(|line| line.contains("sampled_elsewhere")).unwrap();
+    let frames: Vec<&str> = elsewhere.rsplit_once(' ').unwrap().0.split(';').collect();
+    assert!(frames[frames.len() - 1].contains("common::sampled"));
+    assert!(frames[frames.len() - 2].contains("sampled_elsewhere"));
+}
+
+#[test]
+fn speedscope() {
+    let mut unwinder = DwarfUnwinder::default();
+    let stack = common::sampled(&mut unwinder);
+    let stacks = vec![(stack.clone(), 4), (common::sampled_elsewhere(&mut unwinder), 6)];
+    let flamegraph = Flamegraph {
+        name: "test".into(),
+        symbolizer: Some(&OneName(stack[0])),
+        ..Flamegraph::default()
+    };
+    let mut out = Vec::new();
+    flamegraph.write_speedscope(&stacks, &modules(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let file = parse(&mut out.trim_end());
+
+    assert_eq!(file.get("$schema").str(), "https://www.speedscope.app/file-format-schema.json");
+    let frames = file.get("shared").get("frames").array();
+    let profile = &file.get("profiles").array()[0];
+    assert_eq!(profile.get("type").str(), "sampled");
+    assert_eq!(profile.get("name").str(), "test");
+    assert_eq!(profile.get("unit").str(), "none");
+    assert_eq!(profile.get("endValue").number(), 10.0);
+    let weights: Vec<f64> = profile.get("weights").array().iter().map(Json::number).collect();
+    assert_eq!(weights, vec![4.0, 6.0]);
+
+    let samples = profile.get("samples").array();
+    assert_eq!(samples.len(), 2);
+    let names = |sample: &Json| -> Vec<String> {
+        sample.array().iter().map(|i| frames[i.number() as usize].get("name").str().to_string()).collect()
+    };
+    // Both inlined functions, the outer one first, then the rest unknown.
+    let first = names(&samples[0]);
+    assert_eq!(first.len(), stack.len() + 1);
+    assert_eq!(&first[first.len() - 2..], &["outer".to_string(), "inlined<[u8; 4]>".to_string()]);
+    let leaf = &frames[samples[0].array().last().unwrap().number() as usize];
+    assert_eq!(leaf.get("file").str(), "src/\"quoted\".rs");
+    assert!(first[..first.len() - 2].iter().all(|name| name.contains("+0x")));
+    // The shared callers are shared frames.
+    let second = names(&samples[1]);
+    assert_eq!(first[..3], second[..3]);
+    assert!(frames.len() < first.len() + second.len());
+}
@@ -0,0 +1,116 @@
+extern crate unwind;
+extern crate gimli;
+
+use std::arch::global_asm;
+use gimli::X86_64;
+use unwind::{DwarfUnwinder, UnwindCursor, UnwindError, Registers, Guard, Guards, FrameKind};
+
+// A function with a frame pointer, for building fake stacks: in its body
+// the CFA is RBP + 16, and the caller's RBP and return address are at RBP
+// and RBP + 8.
+global_asm!(
+    ".globl guards_framed",
+    "guards_framed:",
+    ".cfi_startproc",
+    "push rbp",
+    ".cfi_def_cfa_offset 16",
+    ".cfi_offset rbp, -16",
+    "mov rbp, rsp",
+    ".cfi_def_cfa_register rbp",
+    "nop",
+    "nop",
+    "pop rbp",
+    ".cfi_def_cfa rsp, 8",
+    "ret",
+    ".cfi_endproc",
+);
+
+extern "C" {
+    fn guards_framed();
+}
+
+// A return address into the body of `guards_framed`.
+fn in_body() -> u64 {
+    guards_framed as *const () as u64 + 5
+}
+
+// Walks a fake stack of `guards_framed` frames whose innermost frame
+// pointer is at `stack[bp]`, and returns the number of frames and the error.
+fn walk(unwinder: &DwarfUnwinder, stack: &[u64], bp: usize) -> (usize, UnwindError) {
+    let mut registers = Registers::default();
+    registers.set(X86_64::RA, Some(in_body())).unwrap();
+    registers.set(X86_64::RSP, Some(&stack[0] as *const u64 as u64)).unwrap();
+    registers.set(X86_64::RBP, Some(&stack[bp] as *const u64 as u64)).unwrap();
+    let mut cursor = UnwindCursor::new(registers);
+    let mut frames = 0;
+    loop {
+        match cursor.step(unwinder) {
+            Ok(Some(_)) => frames += 1,
+            Ok(None) => panic!("the fake stack has no end"),
+            Err(e) => return (frames, e),
+        }
+    }
+}
+
+fn addr(stack: &[u64], i: usize) -> u64 {
+    &stack[i] as *const u64 as u64
+}
+
+#[test]
+fn cfa_must_grow() {
+    let unwinder = DwarfUnwinder::default();
+    let mut stack = [0u64; 32];
+    // The second frame's saved RBP points back down the stack.
+    stack[16] = addr(&stack, 0);
+    stack[17] = in_body();
+    assert_eq!(walk(&unwinder, &stack, 16), (1, UnwindError::Guard(Guard::CfaOrder)));
+}
+
+#[test]
+fn loops_hit_max_depth() {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut stack = [0u64; 32];
+    // A frame that is its own caller.
+    stack[16] = addr(&stack, 16);
+    stack[17] = in_body();
+    assert_eq!(walk(&unwinder, &stack, 16), (1, UnwindError::Guard(Guard::CfaOrder)));
+
+    unwinder.set_guards(Guards { max_depth: 100, cfa_order: false, ..Guards::default() });
+    assert_eq!(walk(&unwinder, &stack, 16), (100, UnwindError::Guard(Guard::MaxDepth)));
+}
+
+#[test]
+fn return_address_outside_modules() {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut stack = [0u64; 32];
+    stack[16] = addr(&stack, 20);
+    stack[17] = 0x1234;
+    assert_eq!(walk(&unwinder, &stack, 16), (1, UnwindError::Guard(Guard::ReturnAddress)));
+
+    // Without the guard, unknown code is unwound through its frame pointer.
+    unwinder.set_guards(Guards { return_address: false, ..Guards::default() });
+    let mut registers = Registers::default();
+    registers.set(X86_64::RA, Some(0x1234)).unwrap();
+    registers.set(X86_64::RSP, Some(addr(&stack, 0))).unwrap();
+    registers.set(X86_64::RBP, Some(addr(&stack, 20))).unwrap();
+    stack[20] = addr(&stack, 24);
+    stack[21] = in_body();
+    let mut cursor = UnwindCursor::new(registers);
+    let frame = cursor.step(&unwinder).unwrap().unwrap();
+    assert_eq!(frame.kind(), FrameKind::FramePointer);
+    assert_eq!(frame.cfa(), addr(&stack, 22));
+    let frame = cursor.step(&unwinder).unwrap().unwrap();
+    assert_eq!(frame.return_address(), in_body());
+}
+
+#[test]
+fn misaligned_cfa() {
+    let unwinder = DwarfUnwinder::default();
+    let stack = [0u64; 32];
+    let mut registers = Registers::default();
+    registers.set(X86_64::RA, Some(in_body())).unwrap();
+    registers.set(X86_64::RSP, Some(addr(&stack, 0))).unwrap();
+    registers.set(X86_64::RBP, Some(addr(&stack, 16) + 4)).unwrap();
+    let mut cursor = UnwindCursor::new(registers);
+    assert_eq!(cursor.step(&unwinder).err(), Some(UnwindError::Guard(Guard::Alignment)));
+}
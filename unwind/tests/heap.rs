@@ -0,0 +1,95 @@
+extern crate unwind;
+extern crate backtrace;
+extern crate libc;
+
+use std::alloc::System;
+use std::hint::black_box;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use unwind::heap::{HeapProfiler, HeapReport, SampledAlloc};
+
+mod common;
+
+#[global_allocator]
+static ALLOC: SampledAlloc<System> = SampledAlloc::new(System);
+
+// Only one heap profiler may run at a time.
+static PROFILER: Mutex<()> = Mutex::new(());
+
+#[inline(never)]
+fn allocate(count: usize, size: usize) -> Vec<Vec<u8>> {
+    (0..count).map(|_| black_box(vec![1u8; size])).collect()
+}
+
+/// The estimated bytes allocated in `allocate`, judging by the addresses.
+fn bytes_in_allocate(report: &HeapReport) -> f64 {
+    let range = common::fde_range(allocate as *const ());
+    report.allocations().iter()
+        .filter(|allocation| allocation.stack().iter().any(|&ip| range.contains(ip - 1)))
+        .map(|allocation| report.estimate(allocation.size()))
+        .sum()
+}
+
+#[test]
+fn samples_live_allocations() {
+    let _guard = PROFILER.lock().unwrap_or_else(|e| e.into_inner());
+    let profiler = HeapProfiler { sample_interval: 16 * 1024, ..HeapProfiler::default() };
+    let running = profiler.start().unwrap();
+    assert_eq!(profiler.start().err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+
+    let kept = allocate(2000, 1000);
+    let report = running.report();
+    assert_eq!(report.dropped(), 0);
+    // 2MB, give or take the randomness of about 120 samples.
+    let estimate = bytes_in_allocate(&report);
+    assert!(estimate > 1_000_000.0 && estimate < 4_000_000.0, "{} bytes", estimate);
+    // The stacks add up to all sampled allocations, rounded down.
+    let total: f64 = report.allocations().iter().map(|allocation| report.estimate(allocation.size())).sum();
+    let stacks: i64 = report.stacks().iter().map(|stack| stack.1).sum();
+    assert!(stacks as f64 <= total && stacks as f64 + report.allocations().len() as f64 >= total);
+    let pprof = report.pprof(None);
+    assert_eq!(&pprof[..2], &[0x1f, 0x8b]);
+
+    // Frees are seen, including those of reallocated buffers.
+    drop(kept);
+    let mut grown = Vec::new();
+    for i in 0..100_000u32 {
+        grown.push(i);
+    }
+    drop(black_box(grown));
+    let report = running.stop();
+    assert_eq!(bytes_in_allocate(&report), 0.0);
+    assert!(report.allocations().len() < 10, "{} allocations left", report.allocations().len());
+}
+
+#[test]
+fn small_allocations_are_rarely_sampled() {
+    let _guard = PROFILER.lock().unwrap_or_else(|e| e.into_inner());
+    let running = HeapProfiler::default().start().unwrap();
+    let kept = allocate(100, 100);
+    let report = running.stop();
+    // 10KB against a mean of 512KB between samples.
+    assert!(report.allocations().len() <= 1);
+    drop(kept);
+}
+
+#[test]
+fn threads_and_full_table() {
+    let _guard = PROFILER.lock().unwrap_or_else(|e| e.into_inner());
+    let profiler = HeapProfiler { sample_interval: 1, max_live: 100 };
+    let running = profiler.start().unwrap();
+    // Every allocation is sampled, on threads started after the profiler.
+    let workers: Vec<_> = (0..4).map(|_| thread::spawn(|| allocate(100, 64))).collect();
+    let kept: Vec<_> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+    let report = running.stop();
+    // Less a few the threads' own allocations freed again.
+    assert!(report.allocations().len() > 90, "{} allocations", report.allocations().len());
+    assert!(report.dropped() >= 300);
+    assert!(bytes_in_allocate(&report) > 0.0);
+    drop(kept);
+
+    // Another profiler may start now, with an empty table.
+    let report = HeapProfiler::default().start().unwrap().stop();
+    assert!(report.allocations().is_empty());
+}
@@ -0,0 +1,83 @@
+extern crate unwind;
+extern crate gimli;
+extern crate fallible_iterator;
+
+use fallible_iterator::FallibleIterator;
+use gimli::X86_64;
+use unwind::{Unwinder, DwarfUnwinder, UnwindCursor, UnwindError, MemoryMap, AddrRange};
+
+// Far below any mapping, but not so low that the CFA arithmetic wraps.
+const BAD_STACK: u64 = 0x10000;
+
+// A cursor at our caller's frame whose stack and frame pointers are garbage.
+#[inline(never)]
+fn corrupt_cursor(unwinder: &mut DwarfUnwinder) -> UnwindCursor {
+    let mut cursor = None;
+    unwinder.trace(|frames| {
+        frames.next().unwrap().unwrap();
+        let mut corrupt = frames.cursor().clone();
+        corrupt.registers_mut().set(X86_64::RSP, Some(BAD_STACK)).unwrap();
+        corrupt.registers_mut().set(X86_64::RBP, Some(BAD_STACK)).unwrap();
+        cursor = Some(corrupt);
+    });
+    cursor.unwrap()
+}
+
+fn walk_to_error(unwinder: &DwarfUnwinder, mut cursor: UnwindCursor) -> UnwindError {
+    for _ in 0..8 {
+        match cursor.step(unwinder) {
+            Ok(Some(_)) => (),
+            Ok(None) => panic!("walk ended without an error"),
+            Err(e) => return e,
+        }
+    }
+    panic!("walk did not stop");
+}
+
+fn assert_bad_read(error: UnwindError) {
+    match error {
+        UnwindError::InvalidRead(addr) => assert!(addr.wrapping_sub(BAD_STACK) < 0x1000, "read at 0x{:x}", addr),
+        e => panic!("unexpected error {}", e),
+    }
+}
+
+#[test]
+fn corrupt_stack_pointer() {
+    let mut unwinder = DwarfUnwinder::default();
+    let cursor = corrupt_cursor(&mut unwinder);
+    assert_bad_read(walk_to_error(&unwinder, cursor));
+}
+
+#[test]
+fn corrupt_stack_pointer_with_process_vm_readv() {
+    let mut unwinder = DwarfUnwinder::default();
+    unwinder.set_process_vm_readv(true);
+    let cursor = corrupt_cursor(&mut unwinder);
+    assert_bad_read(walk_to_error(&unwinder, cursor));
+}
+
+#[test]
+fn snapshot_covers_code_and_stack() {
+    let unwinder = DwarfUnwinder::default();
+    let map = unwinder.index().memory_map().expect("no /proc/self/maps");
+    let local = 0u64;
+    assert!(map.contains(snapshot_covers_code_and_stack as *const () as u64, 8));
+    assert!(map.contains(&local as *const u64 as u64, 8));
+    assert!(!map.contains(BAD_STACK, 8));
+}
+
+#[test]
+fn map_merges_ranges() {
+    let map = MemoryMap::new(vec![
+        AddrRange { start: 0x3000, end: 0x4000 },
+        AddrRange { start: 0x1000, end: 0x2000 },
+        AddrRange { start: 0x2000, end: 0x2800 },
+    ]);
+    assert!(map.contains(0x1ffc, 8));
+    assert!(map.contains(0x1000, 0x1800));
+    assert!(!map.contains(0x27fc, 8));
+    assert!(!map.contains(0x800, 8));
+    assert!(map.contains(0x3ff8, 8));
+    assert!(!map.contains(0x3ffc, 8));
+    assert!(!map.contains(u64::MAX - 4, 8));
+}
@@ -0,0 +1,254 @@
+#![cfg(feature = "panic_runtime")]
+
+extern crate unwind;
+extern crate fallible_iterator;
+extern crate libc;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::arch::global_asm;
+use std::cell::Cell;
+use std::hint::black_box;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use fallible_iterator::FallibleIterator;
+use unwind::{Unwinder, DwarfUnwinder};
+use unwind::libunwind_shim::*;
+use unwind::panic_runtime::{begin_panic, catch_unwind, rust_eh_personality};
+
+/// Counts the bytes each thread has allocated and not freed.
+struct Counting;
+
+thread_local!(static LIVE_BYTES: Cell<isize> = const { Cell::new(0) });
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = LIVE_BYTES.try_with(|live| live.set(live.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = LIVE_BYTES.try_with(|live| live.set(live.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+struct Bomb<'a>(&'a Cell<u32>);
+
+impl<'a> Drop for Bomb<'a> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[inline(never)]
+fn explode() {
+    begin_panic(Box::new("boom"));
+}
+
+#[inline(never)]
+fn defuse(dropped: &Cell<u32>) {
+    let _b = Bomb(dropped);
+    explode();
+}
+
+#[test]
+fn returns_value() {
+    assert_eq!(catch_unwind(|| 42).ok(), Some(42));
+}
+
+#[test]
+fn catches_payload_and_runs_cleanups() {
+    let dropped = Cell::new(0);
+    let result = catch_unwind(|| {
+        let _b = Bomb(&dropped);
+        defuse(&dropped);
+    });
+
+    let payload = result.unwrap_err();
+    assert_eq!(*payload.downcast::<&str>().unwrap(), "boom");
+    assert_eq!(dropped.get(), 2);
+}
+
+#[test]
+fn nested() {
+    let dropped = Cell::new(0);
+    let outer = catch_unwind(|| {
+        let inner = catch_unwind(|| defuse(&dropped));
+        assert!(inner.is_err());
+        defuse(&dropped);
+    });
+
+    assert!(outer.is_err());
+    assert_eq!(dropped.get(), 2);
+
+    // The chain must be empty again, so this is caught by a fresh frame.
+    assert!(catch_unwind(explode).is_err());
+}
+
+#[test]
+fn catching_does_not_leak() {
+    // The first panic builds the module index.
+    assert!(catch_unwind(explode).is_err());
+    let before = LIVE_BYTES.with(Cell::get);
+    for _ in 0..100 {
+        assert!(catch_unwind(explode).is_err());
+    }
+    assert_eq!(LIVE_BYTES.with(Cell::get), before);
+}
+
+#[inline(never)]
+fn guarded(found: &Cell<bool>) {
+    let dropped = Cell::new(0);
+    // Observed, so that the cleanup stays and `probe` is not a tail call.
+    let _b = Bomb(black_box(&dropped));
+    probe(found);
+}
+
+#[inline(never)]
+fn probe(found: &Cell<bool>) {
+    DwarfUnwinder::default().trace(|frames| {
+        while let Some(frame) = frames.next().unwrap() {
+            if frame.initial_address() != guarded as *const () as u64 {
+                continue;
+            }
+
+            let mut registers = frames.registers().clone();
+            let ip = registers[16].unwrap();
+            let mut ctx = _Unwind_Context {
+                lsda: frame.lsda().unwrap(),
+                ip,
+                initial_address: frame.initial_address(),
+                args_size: frame.args_size(),
+                cfa: frame.cfa(),
+                registers: &mut registers,
+            };
+            let exception = ptr::null_mut();
+
+            unsafe {
+                let search = _Unwind_Action::_UA_SEARCH_PHASE as libc::c_int;
+                assert_eq!(rust_eh_personality(1, search, 0, exception, &mut ctx),
+                           _Unwind_Reason_Code::_URC_CONTINUE_UNWIND);
+
+                let cleanup = _Unwind_Action::_UA_CLEANUP_PHASE as libc::c_int;
+                assert_eq!(rust_eh_personality(1, cleanup, 0, exception, &mut ctx),
+                           _Unwind_Reason_Code::_URC_INSTALL_CONTEXT);
+            }
+
+            // The landing pad lives in `guarded` itself, and gets the exception in RAX.
+            let lpad = registers[16].unwrap();
+            assert!(lpad != ip && lpad > frame.initial_address());
+            assert_eq!(registers[0], Some(0));
+            found.set(true);
+            return;
+        }
+    });
+}
+
+#[test]
+fn personality_finds_cleanup() {
+    let found = Cell::new(false);
+    guarded(&found);
+    assert!(found.get());
+}
+
+// A function whose FDE names this crate's personality, with an LSDA that
+// has a cleanup around the call to `raise`. The landing pad hands the
+// exception to `landed` and returns 1, the normal path returns 0.
+global_asm!(
+    ".pushsection .data.rel.ro.unwind_test_personality, \"aw\"",
+    ".p2align 3",
+    "unwind_test_personality:",
+    ".quad {personality}",
+    ".popsection",
+
+    ".globl unwind_test_with_personality",
+    "unwind_test_with_personality:",
+    ".cfi_startproc",
+    // DW_EH_PE_indirect | DW_EH_PE_pcrel | DW_EH_PE_sdata4
+    ".cfi_personality 0x9b, unwind_test_personality",
+    // DW_EH_PE_pcrel | DW_EH_PE_sdata4
+    ".cfi_lsda 0x1b, .Lunwind_test_lsda",
+    "push rbp",
+    ".cfi_def_cfa_offset 16",
+    ".cfi_offset rbp, -16",
+    "mov rbp, rsp",
+    ".cfi_def_cfa_register rbp",
+    ".Lunwind_test_call:",
+    "call {raise}",
+    ".Lunwind_test_call_end:",
+    "xor eax, eax",
+    "pop rbp",
+    ".cfi_remember_state",
+    ".cfi_def_cfa rsp, 8",
+    "ret",
+    ".cfi_restore_state",
+    ".Lunwind_test_lpad:",
+    "mov rdi, rax",
+    "call {landed}",
+    "mov eax, 1",
+    "pop rbp",
+    ".cfi_def_cfa rsp, 8",
+    "ret",
+    ".cfi_endproc",
+
+    ".pushsection .gcc_except_table, \"a\"",
+    ".Lunwind_test_lsda:",
+    // No landing pad base and no type table.
+    ".byte 0xff",
+    ".byte 0xff",
+    // Call sites as uleb128.
+    ".byte 0x01",
+    ".uleb128 .Lunwind_test_call_sites_end - .Lunwind_test_call_sites",
+    ".Lunwind_test_call_sites:",
+    ".uleb128 .Lunwind_test_call - unwind_test_with_personality",
+    ".uleb128 .Lunwind_test_call_end - .Lunwind_test_call",
+    ".uleb128 .Lunwind_test_lpad - unwind_test_with_personality",
+    // No action: a cleanup.
+    ".uleb128 0",
+    ".Lunwind_test_call_sites_end:",
+    ".popsection",
+
+    personality = sym rust_eh_personality,
+    raise = sym raise,
+    landed = sym landed,
+);
+
+extern "C-unwind" {
+    fn unwind_test_with_personality(exception: *mut _Unwind_Exception) -> u64;
+}
+
+extern "C-unwind" fn raise(exception: *mut _Unwind_Exception) {
+    let code = unsafe { _Unwind_RaiseException(exception) };
+    panic!("nothing handled the exception: {:?}", code);
+}
+
+static LANDED: AtomicPtr<_Unwind_Exception> = AtomicPtr::new(ptr::null_mut());
+static DELETED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn landed(exception: *mut _Unwind_Exception) {
+    LANDED.store(exception, Ordering::SeqCst);
+}
+
+extern "C" fn delete(_code: _Unwind_Reason_Code, exception: *mut _Unwind_Exception) {
+    drop(unsafe { Box::from_raw(exception) });
+    DELETED.store(true, Ordering::SeqCst);
+}
+
+#[test]
+fn raise_through_personality() {
+    let exception = Box::into_raw(Box::new(_Unwind_Exception {
+        exception_class: 0x554e_5744_5445_5354,
+        exception_cleanup: delete,
+        private_1: 0,
+        private_2: 0,
+    }));
+    // The crate's personality finds the cleanup and installs the landing
+    // pad, with the exception in RAX.
+    assert_eq!(unsafe { unwind_test_with_personality(exception) }, 1);
+    assert_eq!(LANDED.load(Ordering::SeqCst), exception);
+    unsafe { _Unwind_DeleteException(exception) };
+    assert!(DELETED.load(Ordering::SeqCst));
+}
@@ -0,0 +1,212 @@
+extern crate unwind;
+extern crate backtrace;
+extern crate libc;
+
+use std::fs;
+use std::io;
+use unwind::{Registers, StackSnapshot};
+use unwind::perf::{self, PerfData, Record};
+
+mod common;
+
+/// Samples this thread the way `perf record --call-graph dwarf` would, and
+/// unwinds it live for comparison.
+fn sample_self() -> (StackSnapshot, Vec<u64>) {
+    // Records are at most 64K, so perf copies less than that.
+    common::sample(32 * 1024, || unsafe { libc::raise(libc::SIGUSR1); })
+}
+
+// The DWARF numbers of the perf registers we record: ax to ip, r8 to r15.
+const REGS_MASK: u64 = 0xff01ff;
+const PERF_REGS: [u16; 24] = [0, 3, 2, 1, 4, 5, 6, 7, 16, 0, 0, 0, 0, 0, 0, 0, 8, 9, 10, 11, 12, 13, 14, 15];
+
+// IP | TID | TIME | PERIOD | REGS_USER | STACK_USER
+const SAMPLE_TYPE: u64 = 1 | 2 | 4 | 1 << 8 | 1 << 12 | 1 << 13;
+
+/// Writes the records of a minimal `perf.data` file.
+#[derive(Default)]
+struct PerfWriter {
+    data: Vec<u8>,
+}
+
+impl PerfWriter {
+    fn record(&mut self, type_: u32, misc: u16, body: &[u8]) {
+        assert_eq!(body.len() % 8, 0);
+        self.data.extend_from_slice(&type_.to_ne_bytes());
+        self.data.extend_from_slice(&misc.to_ne_bytes());
+        self.data.extend_from_slice(&(body.len() as u16 + 8).to_ne_bytes());
+        self.data.extend_from_slice(body);
+    }
+
+    // The `sample_id` fields after every other record: TID and TIME.
+    fn sample_id(body: &mut Vec<u8>, pid: u32, time: u64) {
+        body.extend_from_slice(&pid.to_ne_bytes());
+        body.extend_from_slice(&pid.to_ne_bytes());
+        body.extend_from_slice(&time.to_ne_bytes());
+    }
+
+    fn string(body: &mut Vec<u8>, s: &str) {
+        body.extend_from_slice(s.as_bytes());
+        body.resize((body.len() + 8) & !7, 0);
+    }
+
+    fn comm(&mut self, pid: u32, time: u64, name: &str, exec: bool) {
+        let mut body = Vec::new();
+        body.extend_from_slice(&pid.to_ne_bytes());
+        body.extend_from_slice(&pid.to_ne_bytes());
+        PerfWriter::string(&mut body, name);
+        PerfWriter::sample_id(&mut body, pid, time);
+        self.record(3, if exec { 1 << 13 } else { 0 }, &body);
+    }
+
+    fn fork(&mut self, pid: u32, ppid: u32, time: u64) {
+        let mut body = Vec::new();
+        for id in &[pid, ppid, pid, ppid] {
+            body.extend_from_slice(&id.to_ne_bytes());
+        }
+        body.extend_from_slice(&time.to_ne_bytes());
+        PerfWriter::sample_id(&mut body, pid, time);
+        self.record(7, 0, &body);
+    }
+
+    /// An `MMAP2` for every executable file mapping of this process.
+    fn mmaps(&mut self, pid: u32, time: u64) {
+        for line in fs::read_to_string("/proc/self/maps").unwrap().lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || !fields[1].contains('x') || !fields[5].starts_with('/') {
+                continue;
+            }
+            let mut range = fields[0].split('-').map(|x| u64::from_str_radix(x, 16).unwrap());
+            let (start, end) = (range.next().unwrap(), range.next().unwrap());
+            let mut body = Vec::new();
+            body.extend_from_slice(&pid.to_ne_bytes());
+            body.extend_from_slice(&pid.to_ne_bytes());
+            for x in &[start, end - start, u64::from_str_radix(fields[2], 16).unwrap(), 0, 0, 0] {
+                body.extend_from_slice(&x.to_ne_bytes());
+            }
+            body.extend_from_slice(&5u32.to_ne_bytes()); // PROT_READ | PROT_EXEC
+            body.extend_from_slice(&2u32.to_ne_bytes()); // MAP_PRIVATE
+            PerfWriter::string(&mut body, fields[5]);
+            PerfWriter::sample_id(&mut body, pid, time);
+            self.record(10, 0, &body);
+        }
+    }
+
+    fn sample(&mut self, pid: u32, time: u64, snapshot: &StackSnapshot) {
+        let registers: &Registers = snapshot.registers();
+        let mut body = Vec::new();
+        body.extend_from_slice(&registers[16].unwrap().to_ne_bytes());
+        body.extend_from_slice(&pid.to_ne_bytes());
+        body.extend_from_slice(&pid.to_ne_bytes());
+        body.extend_from_slice(&time.to_ne_bytes());
+        body.extend_from_slice(&1u64.to_ne_bytes());
+        body.extend_from_slice(&2u64.to_ne_bytes()); // PERF_SAMPLE_REGS_ABI_64
+        for bit in 0..24 {
+            if REGS_MASK & (1 << bit) != 0 {
+                body.extend_from_slice(&registers[PERF_REGS[bit]].unwrap_or(0).to_ne_bytes());
+            }
+        }
+        let stack = snapshot.bytes();
+        let size = (stack.len() + 7) & !7;
+        body.extend_from_slice(&(size as u64).to_ne_bytes());
+        body.extend_from_slice(stack);
+        body.resize(body.len() + size - stack.len(), 0);
+        body.extend_from_slice(&(stack.len() as u64).to_ne_bytes());
+        self.record(9, 2, &body);
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut attr = vec![0u8; 128];
+        attr[0..4].copy_from_slice(&1u32.to_ne_bytes()); // PERF_TYPE_SOFTWARE
+        attr[4..8].copy_from_slice(&128u32.to_ne_bytes());
+        attr[24..32].copy_from_slice(&SAMPLE_TYPE.to_ne_bytes());
+        attr[40..48].copy_from_slice(&(1u64 << 18).to_ne_bytes()); // sample_id_all
+        attr[80..88].copy_from_slice(&REGS_MASK.to_ne_bytes());
+        attr[88..92].copy_from_slice(&32768u32.to_ne_bytes());
+        attr.extend_from_slice(&[0; 16]); // the event's ids
+
+        let mut file = Vec::new();
+        file.extend_from_slice(b"PERFILE2");
+        let data_offset = 104 + attr.len() as u64;
+        for x in &[104, attr.len() as u64, 104, attr.len() as u64, data_offset, self.data.len() as u64, 0, 0, 0, 0, 0, 0] {
+            file.extend_from_slice(&x.to_ne_bytes());
+        }
+        file.extend_from_slice(&attr);
+        file.extend_from_slice(&self.data);
+        file
+    }
+}
+
+#[test]
+fn unwind_recorded_sample() {
+    let (snapshot, live) = sample_self();
+    assert!(live.len() > 3);
+    let pid = unsafe { libc::getpid() } as u32;
+
+    // The sample comes first in the file, but after the mappings in time.
+    let mut writer = PerfWriter::default();
+    writer.sample(pid, 30, &snapshot);
+    writer.comm(pid, 10, "perf-test", false);
+    writer.mmaps(pid, 20);
+    let path = std::env::temp_dir().join(format!("unwind-perf-{}.data", pid));
+    fs::write(&path, writer.finish()).unwrap();
+    let data = PerfData::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(matches!(data.records()[0], Record::Comm(ref comm) if comm.name == "perf-test" && !comm.exec));
+    assert!(matches!(*data.records().last().unwrap(), Record::Sample(ref sample) if sample.period == Some(1)));
+
+    let samples = data.unwind();
+    assert_eq!(samples.len(), 1);
+    let sample = &samples[0];
+    assert_eq!(sample.comm, "perf-test");
+    let ips: Vec<u64> = sample.frames.iter().map(|frame| frame.address).collect();
+    // The copy of the stack is too small for the whole walk.
+    assert!(ips.len() > 3, "{:x?}", ips);
+    assert_eq!(&ips[..], &live[..ips.len()]);
+    let exe = std::env::current_exe().unwrap();
+    assert!(sample.frames.iter().any(|frame| frame.module.as_ref().is_some_and(|m| exe.ends_with(m.path()))));
+
+    let mut folded = Vec::new();
+    perf::write_folded(&samples, &mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    assert!(folded.starts_with("perf-test;"), "{}", folded);
+    assert!(folded.ends_with(" 1\n"));
+    assert_eq!(folded.matches(';').count(), ips.len());
+    assert!(folded.contains(&format!(";{}+0x", exe.file_name().unwrap().to_str().unwrap())));
+}
+
+#[test]
+fn forks_and_execs() {
+    let (snapshot, _) = sample_self();
+    let (parent, child) = (100, 101);
+
+    let mut writer = PerfWriter::default();
+    writer.comm(parent, 1, "parent", false);
+    writer.mmaps(parent, 2);
+    writer.fork(child, parent, 3);
+    // The parent replaces itself with a program we know nothing about.
+    writer.comm(parent, 4, "exec", true);
+    writer.sample(child, 5, &snapshot);
+    writer.sample(parent, 6, &snapshot);
+    let samples = PerfData::parse(&writer.finish()).unwrap().unwind();
+
+    assert_eq!(samples[0].comm, "parent");
+    assert!(samples[0].frames.len() > 3);
+    assert!(samples[0].frames.iter().all(|frame| frame.module.is_some()));
+    assert_eq!(samples[1].comm, "exec");
+    assert_eq!(samples[1].frames.len(), 1);
+    assert!(samples[1].frames[0].module.is_none());
+}
+
+#[test]
+fn bad_files() {
+    let error = PerfData::parse(b"PERFILE1").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    let mut file = PerfWriter::default().finish();
+    assert!(PerfData::parse(&file).unwrap().records().is_empty());
+    // The data section points past the end.
+    file[40..48].copy_from_slice(&1024u64.to_ne_bytes());
+    assert_eq!(PerfData::parse(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,151 @@
+extern crate unwind;
+extern crate backtrace;
+extern crate libc;
+extern crate miniz_oxide;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use unwind::{DwarfUnwinder, Module, ModuleIndex};
+use unwind::pprof::Pprof;
+
+mod common;
+
+/// A decoded protobuf message: each field's varints and byte strings.
+#[derive(Default)]
+struct Message<'a> {
+    varints: HashMap<u64, Vec<u64>>,
+    bytes: HashMap<u64, Vec<&'a [u8]>>,
+}
+
+fn varint(data: &mut &[u8]) -> u64 {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = data[0];
+        *data = &data[1..];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            break;
+        }
+    }
+    value
+}
+
+fn decode(mut data: &[u8]) -> Message<'_> {
+    let mut message = Message::default();
+    while !data.is_empty() {
+        let key = varint(&mut data);
+        match key & 7 {
+            0 => message.varints.entry(key >> 3).or_default().push(varint(&mut data)),
+            2 => {
+                let len = varint(&mut data) as usize;
+                message.bytes.entry(key >> 3).or_default().push(&data[..len]);
+                data = &data[len..];
+            }
+            wire => panic!("unexpected wire type {}", wire),
+        }
+    }
+    message
+}
+
+impl<'a> Message<'a> {
+    fn uint(&self, field: u64) -> u64 {
+        self.varints.get(&field).map_or(0, |values| values[0])
+    }
+
+    fn messages(&self, field: u64) -> Vec<Message<'a>> {
+        self.bytes.get(&field).map_or(Vec::new(), |values| values.iter().map(|bytes| decode(bytes)).collect())
+    }
+
+    fn packed(&self, field: u64) -> Vec<u64> {
+        let mut values = Vec::new();
+        for mut bytes in self.bytes.get(&field).cloned().unwrap_or_default() {
+            while !bytes.is_empty() {
+                values.push(varint(&mut bytes));
+            }
+        }
+        values
+    }
+}
+
+fn gunzip(data: &[u8]) -> Vec<u8> {
+    assert_eq!(&data[..3], &[0x1f, 0x8b, 8]);
+    let body = miniz_oxide::inflate::decompress_to_vec(&data[10..data.len() - 8]).unwrap();
+    let size = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap());
+    assert_eq!(body.len() as u32, size);
+    body
+}
+
+#[test]
+fn round_trip() {
+    let mut unwinder = DwarfUnwinder::default();
+    let stacks = vec![(common::sampled(&mut unwinder), 3), (common::sampled_elsewhere(&mut unwinder), 5)];
+    assert!(stacks.iter().all(|stack| stack.0.len() > 3));
+    let modules: Vec<Module> = ModuleIndex::global().modules().cloned().collect();
+
+    let pprof = Pprof {
+        period: Some(("cpu".into(), "nanoseconds".into(), 10_000_000)),
+        duration_nanos: 1_000_000_000,
+        ..Pprof::default()
+    };
+    let data = gunzip(&pprof.encode(&stacks, &modules));
+    let profile = decode(&data);
+    let strings: Vec<&str> = profile.bytes[&6].iter().map(|s| std::str::from_utf8(s).unwrap()).collect();
+    assert_eq!(strings[0], "");
+    let sample_type = &profile.messages(1)[0];
+    assert_eq!(strings[sample_type.uint(1) as usize], "samples");
+    assert_eq!(strings[sample_type.uint(2) as usize], "count");
+    assert_eq!(strings[profile.messages(11)[0].uint(1) as usize], "cpu");
+    assert_eq!(profile.uint(12), 10_000_000);
+    assert_eq!(profile.uint(10), 1_000_000_000);
+
+    let mappings = profile.messages(3);
+    assert_eq!(mappings.len(), modules.len());
+    let locations: HashMap<u64, Message> = profile.messages(4).into_iter().map(|location| (location.uint(1), location)).collect();
+    let samples = profile.messages(2);
+    assert_eq!(samples.len(), 2);
+    for (sample, &(ref ips, value)) in samples.iter().zip(&stacks) {
+        assert_eq!(sample.packed(2), vec![value as u64]);
+        let ids = sample.packed(1);
+        assert_eq!(ids.len(), ips.len());
+        for (i, id) in ids.iter().enumerate() {
+            let location = &locations[id];
+            let address = if i == 0 { ips[0] } else { ips[i] - 1 };
+            assert_eq!(location.uint(3), address);
+            let mapping = &mappings[location.uint(2) as usize - 1];
+            assert!(mapping.uint(2) <= address && address < mapping.uint(3));
+            assert!(location.messages(4).is_empty());
+        }
+    }
+    // The shared callers are one location each.
+    assert!(locations.len() < stacks[0].0.len() + stacks[1].0.len());
+
+    let exe = std::env::current_exe().unwrap();
+    let own = &mappings[modules.iter().position(|m| exe.ends_with(m.path())).unwrap()];
+    assert!(!strings[own.uint(6) as usize].is_empty());
+    assert_eq!(own.uint(7), 0);
+}
+
+#[test]
+fn functions_and_lines() {
+    let mut unwinder = DwarfUnwinder::default();
+    let stacks = vec![(common::sampled(&mut unwinder), 1)];
+    let modules: Vec<Module> = ModuleIndex::global().modules().cloned().collect();
+
+    let pprof = Pprof { symbolizer: Some(&common::Backtrace), ..Pprof::default() };
+    let data = gunzip(&pprof.encode(&stacks, &modules));
+    let profile = decode(&data);
+    let strings: Vec<&str> = profile.bytes[&6].iter().map(|s| std::str::from_utf8(s).unwrap()).collect();
+    let functions: HashMap<u64, Message> = profile.messages(5).into_iter().map(|function| (function.uint(1), function)).collect();
+    let locations = profile.messages(4);
+    let leaf = &locations[profile.messages(2)[0].packed(1)[0] as usize - 1];
+    let lines = leaf.messages(4);
+    assert!(!lines.is_empty());
+    let function = &functions[&lines[0].uint(1)];
+    assert!(strings[function.uint(2) as usize].contains("sampled"), "{}", strings[function.uint(2) as usize]);
+    assert!(strings[function.uint(4) as usize].ends_with("common/mod.rs"));
+    assert!(lines[0].uint(2) > 0);
+
+    let exe = std::env::current_exe().unwrap();
+    let own = &profile.messages(3)[modules.iter().position(|m| exe.ends_with(m.path())).unwrap()];
+    assert_eq!((own.uint(7), own.uint(8), own.uint(9)), (1, 1, 1));
+}
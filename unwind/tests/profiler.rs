@@ -0,0 +1,106 @@
+extern crate unwind;
+extern crate backtrace;
+extern crate libc;
+
+use std::hint::black_box;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use unwind::AddrRange;
+use unwind::profiler::{CallNode, Profiler, Timer};
+
+mod common;
+
+// Only one profiler may run at a time.
+static PROFILER: Mutex<()> = Mutex::new(());
+
+#[inline(never)]
+fn spin(until: Instant) -> u64 {
+    let mut x = 0u64;
+    while Instant::now() < until {
+        for i in 0..1000 {
+            x = black_box(x.wrapping_mul(31).wrapping_add(i));
+        }
+    }
+    x
+}
+
+fn spin_for(duration: Duration) {
+    black_box(spin(Instant::now() + duration));
+}
+
+/// The samples with `spin` on the stack, judging by the addresses.
+fn samples_in_spin(node: &CallNode) -> u64 {
+    samples_in(&common::fde_range(spin as *const ()), node)
+}
+
+fn samples_in(range: &AddrRange, node: &CallNode) -> u64 {
+    // Return addresses may point just past the end of the function.
+    if range.contains(node.address().wrapping_sub(1)) {
+        return node.total();
+    }
+    node.children().iter().map(|child| samples_in(range, child)).sum()
+}
+
+fn check_tree(node: &CallNode) {
+    let below: u64 = node.children().iter().map(|child| child.total()).sum();
+    assert_eq!(node.total(), node.own() + below);
+    assert!(node.children().windows(2).all(|pair| pair[0].total() >= pair[1].total()));
+    node.children().iter().for_each(check_tree);
+}
+
+#[test]
+fn per_thread_timers() {
+    let _guard = PROFILER.lock().unwrap_or_else(|e| e.into_inner());
+    let profiler = Profiler { frequency: 1000, ..Profiler::default() };
+    let running = profiler.start().unwrap();
+    assert_eq!(profiler.start().err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+
+    spin_for(Duration::from_millis(200));
+    thread::sleep(Duration::from_millis(50));
+    let before = samples_in_spin(running.report().root());
+    assert!(before > 0);
+    // Picked up by the background thread after it started.
+    let worker = thread::spawn(|| spin_for(Duration::from_millis(400)));
+    worker.join().unwrap();
+
+    let report = running.stop();
+    check_tree(report.root());
+    assert_eq!(report.root().own(), 0);
+    // The kernel may fire CPU timers less often than asked, but almost
+    // all CPU time went to `spin`, on both threads.
+    let in_spin = samples_in_spin(report.root());
+    assert!(in_spin > 20 && in_spin * 10 > report.samples() * 9, "{} of {} samples in spin", in_spin, report.samples());
+    assert!(in_spin > before + 10, "no samples of the new thread");
+
+    // Another profiler may start now.
+    Profiler::default().start().unwrap().stop();
+}
+
+#[test]
+fn process_timer() {
+    let _guard = PROFILER.lock().unwrap_or_else(|e| e.into_inner());
+    let profiler = Profiler { frequency: 1000, timer: Timer::Process, ..Profiler::default() };
+    let running = profiler.start().unwrap();
+    spin_for(Duration::from_millis(300));
+    let report = running.stop();
+    check_tree(report.root());
+    let in_spin = samples_in_spin(report.root());
+    assert!(in_spin > 10 && in_spin * 10 > report.samples() * 9, "{} of {} samples in spin", in_spin, report.samples());
+
+    // The timer cannot send any other signal.
+    let profiler = Profiler { timer: Timer::Process, signal: libc::SIGUSR2, ..Profiler::default() };
+    assert_eq!(profiler.start().err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+}
+
+#[test]
+fn full_buffer_loses_samples() {
+    let _guard = PROFILER.lock().unwrap_or_else(|e| e.into_inner());
+    let profiler = Profiler { frequency: 10000, buffer_size: 2, ..Profiler::default() };
+    let running = profiler.start().unwrap();
+    spin_for(Duration::from_millis(300));
+    let report = running.stop();
+    assert!(report.lost() > 0);
+    assert!(report.samples() > 0);
+}
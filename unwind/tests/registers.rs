@@ -0,0 +1,78 @@
+extern crate unwind;
+extern crate gimli;
+extern crate libc;
+
+use std::mem;
+use std::ptr;
+use gimli::{Register, X86_64};
+use unwind::{Registers, RegisterError};
+use unwind::registers::{XMM16, RFLAGS, MXCSR, FCW, CS};
+
+#[test]
+fn checked_access() {
+    let mut regs = Registers::default();
+
+    regs.set(Register(RFLAGS), Some(0x246)).unwrap();
+    assert_eq!(regs[RFLAGS], Some(0x246));
+    assert_eq!(regs.get(X86_64::RAX), Ok(None));
+
+    assert_eq!(regs.get(Register(56)), Err(RegisterError::Unknown(56)));
+    assert_eq!(regs.set(Register(200), Some(1)), Err(RegisterError::Unknown(200)));
+
+    let xmm17 = Register(XMM16 + 1);
+    assert!(Registers::is_vector(xmm17));
+    assert_eq!(regs.get(xmm17), Err(RegisterError::WrongWidth(XMM16 + 1)));
+    regs.set_vector(xmm17, Some(1 << 100)).unwrap();
+    assert_eq!(regs.vector(xmm17), Ok(Some(1 << 100)));
+    assert_eq!(regs.vector(X86_64::RSP), Err(RegisterError::WrongWidth(7)));
+}
+
+#[test]
+fn every_register_has_its_own_slot() {
+    let mut regs = Registers::default();
+    let known: Vec<u16> = (0..200).filter(|&reg| regs.get(Register(reg)) != Err(RegisterError::Unknown(reg))).collect();
+    assert_eq!(known.len(), 47 + 40);
+    for &reg in &known {
+        let _ = regs.set(Register(reg), Some(reg as u64));
+        let _ = regs.set_vector(Register(reg), Some(reg as u128));
+    }
+    for &reg in &known {
+        match regs.get(Register(reg)) {
+            Ok(value) => assert_eq!(value, Some(reg as u64)),
+            Err(_) => assert_eq!(regs.vector(Register(reg)), Ok(Some(reg as u128))),
+        }
+    }
+    regs.set_vector(Register(XMM16), None).unwrap();
+    assert_eq!(regs.vector(Register(XMM16)), Ok(None));
+    // Registers are copied for every frame.
+    assert!(mem::size_of::<Registers>() < 1500);
+}
+
+static mut CAPTURED: Option<Registers> = None;
+
+extern "C" fn handler(_sig: libc::c_int, _info: *mut libc::siginfo_t, uc: *mut libc::c_void) {
+    unsafe { CAPTURED = Some(Registers::from_ucontext(uc as *const libc::ucontext_t)) };
+}
+
+#[test]
+fn from_ucontext() {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        assert_eq!(libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()), 0);
+        let local = 0u64;
+        libc::raise(libc::SIGUSR1);
+
+        let regs = (*ptr::addr_of!(CAPTURED)).clone().unwrap();
+        let sp = regs[X86_64::RSP].unwrap();
+        assert!(sp < &local as *const u64 as u64);
+        assert!(regs[X86_64::RA].is_some());
+        assert!(regs[RFLAGS].is_some());
+        assert_eq!(regs[CS], Some(0x33));
+        // Defaults set up by the kernel, unless something changed the FPU mode.
+        assert_eq!(regs[MXCSR].map(|x| x & 0xffc0), Some(0x1f80));
+        assert_eq!(regs[FCW], Some(0x37f));
+        assert!(regs.vector(Register(17)).unwrap().is_some());
+    }
+}
@@ -0,0 +1,121 @@
+extern crate unwind;
+extern crate libc;
+extern crate fallible_iterator;
+
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use fallible_iterator::FallibleIterator;
+use unwind::{Unwinder, DwarfUnwinder, ModuleIndex};
+use unwind::signal_safe::SignalSafeUnwinder;
+
+static UNWINDER: AtomicPtr<SignalSafeUnwinder> = AtomicPtr::new(ptr::null_mut());
+static SAMPLES: AtomicUsize = AtomicUsize::new(0);
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_sigprof(_sig: libc::c_int, _info: *mut libc::siginfo_t, uc: *mut libc::c_void) {
+    let unwinder = UNWINDER.load(Ordering::Acquire);
+    if unwinder.is_null() {
+        return;
+    }
+    let unwinder = unsafe { &*unwinder };
+
+    let mut ips = [0u64; 64];
+    let sample = SAMPLES.fetch_add(1, Ordering::Relaxed);
+    let n = if sample & 1 == 0 {
+        unsafe { unwinder.trace_from_ucontext(uc as *const libc::ucontext_t, &mut ips) }
+    } else {
+        unwinder.trace(&mut ips)
+    };
+    FRAMES.fetch_add(n, Ordering::Relaxed);
+}
+
+fn set_timer(usec: libc::suseconds_t) {
+    let interval = libc::timeval { tv_sec: 0, tv_usec: usec };
+    let timer = libc::itimerval { it_interval: interval, it_value: interval };
+    assert_eq!(unsafe { libc::setitimer(libc::ITIMER_PROF, &timer, ptr::null_mut()) }, 0);
+}
+
+#[test]
+fn unwind_under_malloc_load() {
+    // The stacks of the threads below are mapped after the index was built.
+    let mut unwinder = Box::new(SignalSafeUnwinder::new(ModuleIndex::global(), 16));
+    unwinder.set_process_vm_readv(true);
+    UNWINDER.store(Box::into_raw(unwinder), Ordering::Release);
+
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sigprof as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        assert_eq!(libc::sigaction(libc::SIGPROF, &action, ptr::null_mut()), 0);
+    }
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let threads: Vec<_> = (0..4).map(|t| thread::spawn(move || {
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        let mut i = t as u64;
+        while !STOP.load(Ordering::Relaxed) {
+            i = i.wrapping_mul(6364136223846793005).wrapping_add(1);
+            blocks.push(vec![0; 16 + (i >> 50) as usize]);
+            if blocks.len() > 256 {
+                blocks.swap_remove((i >> 40) as usize % blocks.len());
+            }
+        }
+    })).collect();
+
+    set_timer(500);
+    let start = Instant::now();
+    while SAMPLES.load(Ordering::Relaxed) < 200 && start.elapsed() < Duration::from_secs(20) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    set_timer(0);
+    STOP.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let samples = SAMPLES.load(Ordering::Relaxed);
+    assert!(samples >= 200, "only {} samples", samples);
+    assert!(FRAMES.load(Ordering::Relaxed) >= samples, "traces are mostly empty");
+}
+
+static mut FULL: Vec<(u64, bool)> = Vec::new();
+static mut LEAN: [u64; 64] = [0; 64];
+static mut LEAN_LEN: usize = 0;
+
+extern "C" fn on_sigusr2(_sig: libc::c_int) {
+    unsafe {
+        DwarfUnwinder::default().trace(|frames| {
+            while let Some(frame) = frames.next().unwrap() {
+                (*ptr::addr_of_mut!(FULL)).push((frame.return_address(), frame.is_signal_frame()));
+            }
+        });
+        let unwinder = SignalSafeUnwinder::new(ModuleIndex::global(), 1);
+        LEAN_LEN = unwinder.trace(&mut *ptr::addr_of_mut!(LEAN));
+    }
+}
+
+#[inline(never)]
+fn raise_sigusr2() -> u64 {
+    unsafe {
+        libc::signal(libc::SIGUSR2, on_sigusr2 as *const () as libc::sighandler_t);
+        libc::raise(libc::SIGUSR2);
+    }
+    raise_sigusr2 as *const () as u64
+}
+
+#[test]
+fn through_signal_frame() {
+    let raiser = raise_sigusr2();
+    let full = unsafe { (*ptr::addr_of!(FULL)).clone() };
+    let lean = unsafe { &LEAN[..LEAN_LEN] };
+
+    // Both walks get from the handler through the kernel's trampoline to
+    // the code that raised the signal, and agree on every frame but the
+    // handler's own, where they were called from different places.
+    let trampoline = full.iter().position(|&(_, signal)| signal).unwrap();
+    assert!(full[trampoline + 1..].iter().any(|&(ra, _)| ra > raiser && ra < raiser + 0x200));
+    assert_eq!(full.iter().skip(1).map(|&(ra, _)| ra).collect::<Vec<_>>(), &lean[1..]);
+}
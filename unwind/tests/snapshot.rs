@@ -0,0 +1,66 @@
+extern crate unwind;
+extern crate backtrace;
+extern crate libc;
+extern crate fallible_iterator;
+extern crate gimli;
+
+use std::sync::Arc;
+use fallible_iterator::FallibleIterator;
+use unwind::{DwarfUnwinder, StackFrames, StackSnapshot, UnwindCursor, UnwindError};
+
+mod common;
+
+// Raises the signal from a frame that is gone by the time the snapshot
+// is unwound.
+#[inline(never)]
+fn interrupted() {
+    unsafe { libc::raise(libc::SIGUSR1) };
+}
+
+// Overwrites the stack below the caller.
+#[inline(never)]
+fn scribble() {
+    let junk = [0x55u8; 128 * 1024];
+    std::hint::black_box(&junk);
+}
+
+fn take_snapshot(size: usize) -> (Arc<StackSnapshot>, Vec<u64>) {
+    let (snapshot, live) = common::sample(size, || {
+        interrupted();
+        scribble();
+    });
+    (Arc::new(snapshot), live)
+}
+
+#[test]
+fn deferred_unwind() {
+    let (snapshot, live) = take_snapshot(512 * 1024);
+    assert_eq!(snapshot.base(), snapshot.registers()[gimli::X86_64::RSP].unwrap());
+    assert!(!snapshot.bytes().is_empty());
+    assert!(live.len() > 3);
+
+    let mut unwinder = DwarfUnwinder::default();
+    let mut frames = StackFrames::from_cursor(&mut unwinder, UnwindCursor::from_snapshot(snapshot.clone()));
+    let mut ips = Vec::new();
+    while let Some(frame) = frames.next().unwrap() {
+        ips.push(frame.return_address());
+    }
+    assert_eq!(ips, live);
+
+    // A copy of only the top of the stack ends the walk early, but cleanly.
+    let (small, _) = take_snapshot(256);
+    assert_eq!(small.bytes().len(), 256);
+    let end = small.base() + 256;
+    let mut cursor = UnwindCursor::from_snapshot(small);
+    let error = loop {
+        match cursor.step(&unwinder) {
+            Ok(Some(_)) => (),
+            Ok(None) => panic!("walked past the end of the copy"),
+            Err(e) => break e,
+        }
+    };
+    match error {
+        UnwindError::InvalidRead(addr) => assert!(addr + 8 > end, "read at 0x{:x}", addr),
+        e => panic!("unexpected error {}", e),
+    }
+}
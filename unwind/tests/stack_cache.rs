@@ -0,0 +1,112 @@
+extern crate unwind;
+extern crate fallible_iterator;
+
+use std::hint::black_box;
+use fallible_iterator::FallibleIterator;
+use unwind::{Unwinder, DwarfUnwinder, Guards, UnwindError};
+
+#[inline(never)]
+fn frames(unwinder: &mut DwarfUnwinder) -> Result<Vec<(u64, u64)>, UnwindError> {
+    let mut result = Ok(Vec::new());
+    unwinder.trace(|frames| {
+        result = frames.map(|frame| (frame.return_address(), frame.cfa())).collect();
+    });
+    result
+}
+
+#[inline(never)]
+fn recurse(depth: usize, f: &mut dyn FnMut()) {
+    if depth == 0 {
+        f()
+    } else {
+        recurse(black_box(depth - 1), f);
+        black_box(());
+    }
+}
+
+type Caller = fn(&mut dyn FnMut());
+
+// Two callers with the same frame size, so `recurse` runs at the same CFAs
+// under both. They differ just enough not to be merged.
+#[inline(never)]
+fn caller_a(f: &mut dyn FnMut()) {
+    recurse(black_box(20), f);
+    black_box(1);
+}
+
+#[inline(never)]
+fn caller_b(f: &mut dyn FnMut()) {
+    recurse(black_box(20), f);
+    black_box(2);
+}
+
+// The walks compared below are taken from a single call site, in loops that
+// the optimizer is kept from unrolling, so that they match frame by frame.
+
+#[test]
+fn same_frames_as_without_cache() {
+    let mut cached = DwarfUnwinder::default();
+    cached.set_stack_cache(true);
+    let mut unwinders = [DwarfUnwinder::default(), cached];
+    let mut lengths = Vec::new();
+    for depth in &[30, 30, 10, 50, 50] {
+        recurse(*depth, &mut || {
+            let walks: Vec<_> = black_box(&[0, 1, 1][..]).iter().map(|&i| frames(&mut unwinders[i]).unwrap()).collect();
+            assert_eq!(walks[1], walks[0]);
+            assert_eq!(walks[2], walks[0]);
+            lengths.push(walks[0].len());
+        });
+    }
+    assert_eq!(lengths[0], lengths[1]);
+    assert_eq!(lengths[0], lengths[2] + 20);
+}
+
+#[test]
+fn other_callers_at_the_same_depth() {
+    let mut cached = DwarfUnwinder::default();
+    cached.set_stack_cache(true);
+    let mut unwinders = [DwarfUnwinder::default(), cached];
+    let runs: [(Caller, usize); 3] = [(caller_a, 1), (caller_b, 1), (caller_b, 0)];
+    let mut walks = Vec::new();
+    for &(caller, i) in black_box(&runs[..]) {
+        caller(&mut || walks.push(frames(&mut unwinders[i]).unwrap()));
+    }
+    assert_eq!(walks[1], walks[2]);
+    assert_ne!(walks[0], walks[1]);
+}
+
+#[test]
+fn guards_apply_to_reused_frames() {
+    let mut cached = DwarfUnwinder::default();
+    cached.set_stack_cache(true);
+    recurse(40, &mut || {
+        let mut walks: Vec<Result<Vec<_>, _>> = Vec::new();
+        // The second walk stops five frames short of the first.
+        for &cut in black_box(&[0, 5, 0][..]) {
+            let max_depth = match walks.first() {
+                Some(Ok(all)) if cut > 0 => all.len() - cut,
+                _ => Guards::default().max_depth,
+            };
+            cached.set_guards(Guards { max_depth, ..Guards::default() });
+            walks.push(frames(&mut cached));
+        }
+        assert!(matches!(walks[1], Err(UnwindError::Guard(_))));
+        assert_eq!(walks[2].as_ref().unwrap(), walks[0].as_ref().unwrap());
+    });
+}
+
+#[test]
+fn threads_keep_their_own_walks() {
+    let mut cached = DwarfUnwinder::default();
+    cached.set_stack_cache(true);
+    recurse(10, &mut || { frames(&mut cached).unwrap(); });
+    std::thread::spawn(|| {
+        let mut cached = DwarfUnwinder::default();
+        cached.set_stack_cache(true);
+        let mut unwinders = [DwarfUnwinder::default(), cached];
+        recurse(10, &mut || {
+            let walks: Vec<_> = black_box(&[0, 1][..]).iter().map(|&i| frames(&mut unwinders[i]).unwrap()).collect();
+            assert_eq!(walks[1], walks[0]);
+        });
+    }).join().unwrap();
+}
@@ -0,0 +1,175 @@
+extern crate unwind;
+extern crate libc;
+extern crate fallible_iterator;
+
+use std::arch::global_asm;
+use std::mem;
+use std::os::raw::c_void;
+use std::ptr;
+use std::sync::Mutex;
+use fallible_iterator::FallibleIterator;
+use unwind::{Unwinder, DwarfUnwinder, UnwindCursor, UnwindError, Registers, AddrRange};
+
+// A minimal coroutine: `stacks_coro_run(top, f, arg)` calls `f(arg)` on the
+// stack ending at `top`. Like real coroutine entry points, the first frame
+// on the new stack marks the return address as undefined, so CFI alone
+// ends the walk there.
+global_asm!(
+    ".globl stacks_coro_run",
+    "stacks_coro_run:",
+    ".cfi_startproc",
+    "push rbp",
+    ".cfi_def_cfa_offset 16",
+    ".cfi_offset rbp, -16",
+    "mov rbp, rsp",
+    ".cfi_def_cfa_register rbp",
+    "mov rsp, rdi",
+    "mov rdi, rdx",
+    "call stacks_coro_entry",
+    "mov rsp, rbp",
+    "pop rbp",
+    ".cfi_def_cfa rsp, 8",
+    "ret",
+    ".cfi_endproc",
+    "stacks_coro_entry:",
+    ".cfi_startproc",
+    ".cfi_undefined rip",
+    "sub rsp, 8",
+    ".cfi_adjust_cfa_offset 8",
+    "call rsi",
+    "add rsp, 8",
+    ".cfi_adjust_cfa_offset -8",
+    "ret",
+    ".cfi_endproc",
+);
+
+extern "C" {
+    fn stacks_coro_run(top: u64, f: extern "C" fn(*mut c_void), arg: *mut c_void);
+}
+
+fn walk(unwinder: &DwarfUnwinder, cursor: &mut UnwindCursor) -> (Vec<u64>, Result<(), UnwindError>) {
+    let mut ips = Vec::new();
+    loop {
+        match cursor.step(unwinder) {
+            Ok(Some(frame)) => ips.push(frame.return_address()),
+            Ok(None) => return (ips, Ok(())),
+            Err(e) => return (ips, Err(e)),
+        }
+    }
+}
+
+struct Coro {
+    unwinder: DwarfUnwinder,
+    region: AddrRange,
+    resumer: Registers,
+    plain: (Vec<u64>, Result<(), UnwindError>),
+    switched: (Vec<u64>, Result<(), UnwindError>),
+}
+
+extern "C" fn coro_body(coro: *mut c_void) {
+    let coro = unsafe { &mut *(coro as *mut Coro) };
+    unwind::glue::registers(|registers| {
+        let mut cursor = UnwindCursor::new(registers.clone());
+        cursor.stacks_mut().add(coro.region);
+        coro.plain = walk(&coro.unwinder, &mut cursor);
+
+        let mut cursor = UnwindCursor::new(registers);
+        cursor.stacks_mut().add_switch(coro.region, coro.resumer.clone());
+        coro.switched = walk(&coro.unwinder, &mut cursor);
+    });
+}
+
+// Runs `coro_body` on `stack` and returns the walk from `resume` itself.
+#[inline(never)]
+fn resume(coro: &mut Coro, stack: &mut [u64]) -> Vec<u64> {
+    let mut resumer = None;
+    coro.unwinder.trace(|frames| {
+        frames.next().unwrap().unwrap();
+        resumer = Some(frames.registers().clone());
+    });
+    coro.resumer = resumer.unwrap();
+    let (expected, end) = walk(&coro.unwinder, &mut UnwindCursor::new(coro.resumer.clone()));
+    assert_eq!(end, Ok(()));
+
+    let top = stack.as_ptr() as u64 + mem::size_of_val(stack) as u64;
+    unsafe { stacks_coro_run(top & !15, coro_body, coro as *mut Coro as *mut c_void) };
+    expected
+}
+
+#[test]
+fn coroutine_switch() {
+    let mut stack = vec![0u64; 128 * 1024];
+    let start = stack.as_ptr() as u64;
+    let mut coro = Coro {
+        unwinder: DwarfUnwinder::default(),
+        region: AddrRange { start, end: start + 8 * stack.len() as u64 },
+        resumer: Registers::default(),
+        plain: (Vec::new(), Ok(())),
+        switched: (Vec::new(), Ok(())),
+    };
+    let expected = resume(&mut coro, &mut stack);
+
+    // CFI alone stops at the coroutine's entry point.
+    let (plain, end) = coro.plain.clone();
+    assert_eq!(end, Ok(()));
+    assert!(!plain.is_empty());
+
+    // With the switch point, the walk continues in `resume`.
+    let (switched, end) = coro.switched.clone();
+    assert_eq!(end, Ok(()));
+    assert_eq!(&switched[..plain.len()], &plain[..]);
+    assert_eq!(&switched[plain.len()..], &expected[..]);
+}
+
+// The CFA of each frame and whether it is a signal frame, and how the walk ended.
+type Walk = (Vec<(u64, bool)>, Result<(), UnwindError>);
+
+static ALTSTACK_WALK: Mutex<Option<Walk>> = Mutex::new(None);
+
+extern "C" fn on_sigusr2(_: libc::c_int) {
+    let mut frames_seen = Vec::new();
+    let mut end = Ok(());
+    DwarfUnwinder::default().trace(|frames| {
+        loop {
+            match frames.next() {
+                Ok(Some(frame)) => frames_seen.push((frame.cfa(), frame.is_signal_frame())),
+                Ok(None) => break,
+                Err(e) => {
+                    end = Err(e);
+                    break;
+                }
+            }
+        }
+    });
+    *ALTSTACK_WALK.lock().unwrap() = Some((frames_seen, end));
+}
+
+#[test]
+fn through_sigaltstack() {
+    // Allocated after the module index took its memory snapshot, so only
+    // found by asking the kernel for the thread's alternate stack.
+    DwarfUnwinder::default();
+    let altstack = vec![0u8; 1 << 20];
+    let region = AddrRange { start: altstack.as_ptr() as u64, end: altstack.as_ptr() as u64 + altstack.len() as u64 };
+
+    unsafe {
+        let stack = libc::stack_t { ss_sp: altstack.as_ptr() as *mut _, ss_flags: 0, ss_size: altstack.len() };
+        assert_eq!(libc::sigaltstack(&stack, ptr::null_mut()), 0);
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sigusr2 as *const () as usize;
+        action.sa_flags = libc::SA_ONSTACK;
+        assert_eq!(libc::sigaction(libc::SIGUSR2, &action, ptr::null_mut()), 0);
+        libc::raise(libc::SIGUSR2);
+
+        let disable = libc::stack_t { ss_sp: ptr::null_mut(), ss_flags: libc::SS_DISABLE, ss_size: 0 };
+        assert_eq!(libc::sigaltstack(&disable, ptr::null_mut()), 0);
+    }
+
+    let (frames, end) = ALTSTACK_WALK.lock().unwrap().take().unwrap();
+    assert_eq!(end, Ok(()));
+    let trampoline = frames.iter().position(|&(_, signal)| signal).expect("no signal frame");
+    // The handler ran on the alternate stack, the code it interrupted did not.
+    assert!(frames[..trampoline].iter().all(|&(cfa, _)| region.contains(cfa)));
+    assert!(frames.len() > trampoline + 2);
+    assert!(frames[trampoline + 1..].iter().all(|&(cfa, _)| !region.contains(cfa)));
+}
@@ -0,0 +1,44 @@
+extern crate unwind;
+extern crate fallible_iterator;
+
+use std::sync::Arc;
+use std::thread;
+use fallible_iterator::FallibleIterator;
+use unwind::{Unwinder, DwarfUnwinder, ModuleIndex};
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[inline(never)]
+fn depth(unwinder: &mut DwarfUnwinder) -> usize {
+    let mut depth = 0;
+    unwinder.trace(|frames| depth = frames.count().unwrap());
+    depth
+}
+
+#[inline(never)]
+fn recurse(unwinder: &mut DwarfUnwinder, n: usize) -> usize {
+    if n == 0 { depth(unwinder) } else { recurse(unwinder, n - 1) + 1 }
+}
+
+#[test]
+fn shared_index() {
+    assert_send_sync::<ModuleIndex>();
+    assert!(Arc::ptr_eq(&ModuleIndex::global(), DwarfUnwinder::default().index()));
+    assert!(ModuleIndex::global().modules().any(|m| !m.path().is_empty()));
+
+    let index = Arc::new(ModuleIndex::new());
+    let threads: Vec<_> = (0..8).map(|i| {
+        let index = index.clone();
+        thread::spawn(move || {
+            let mut unwinder = DwarfUnwinder::new(index);
+            let deep = recurse(&mut unwinder, i);
+            assert!(deep > i);
+            for _ in 0..100 {
+                assert_eq!(recurse(&mut unwinder, i), deep);
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}